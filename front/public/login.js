@@ -0,0 +1,3 @@
+if (window.location.search.includes("failed")) {
+    document.getElementById("failed").hidden = false;
+}
@@ -1,20 +1,45 @@
+mod fever;
+mod greader;
+mod publish;
+mod v1;
+
+use crate::auth::{self, User};
+use crate::conf::{image_dir, FETCH_LOG_SIZE};
 use crate::db;
-use chrono::prelude::*;
-use chrono::DateTime;
-use serde::Deserialize;
+use crate::feed::{self, FeedCandidate};
+use crate::opml;
+use crate::sanitize::EMBED_HOSTS;
+use crate::search;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
 use std::error::Error;
-use std::fs;
 use std::net::Ipv4Addr;
+use warp::http::StatusCode;
 use warp::Filter;
 
 #[derive(Debug, Deserialize)]
-struct RssQuery {
-    query_type: String,
+struct SearchQuery {
+    q: String,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+    #[serde(default)]
+    offset: usize,
+}
+
+fn default_search_limit() -> usize {
+    20
 }
 
 #[derive(Debug, Deserialize)]
-struct PageQuery {
-    path: String,
+struct SubscribeQuery {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscribeRequest {
+    /// a feed, or a website to find the feed of
+    url: String,
+    folder: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,97 +50,163 @@ struct MarkRemove {
     link: String,
 }
 
-fn ensure_path(path: &String) -> Result<String, &'static str> {
-    let cleaned_path = path_clean::clean(path);
-    if !(cleaned_path.starts_with("pages/")) {
-        return Err("Invalid path");
+fn rss_mark(user: &User, _query: &Mark) -> Result<(), Box<dyn Error>> {
+    db::mark_pages_read(user.id, 15)?;
+    Ok(())
+}
+
+fn rss_remove(user: &User, query: &MarkRemove) -> Result<(), Box<dyn Error>> {
+    let link = &query.link;
+    eprintln!("remove page {}", link);
+    db::remove_pages_from_link(user.id, link)?;
+    Ok(())
+}
+
+/// Reply to an error of the server, the details go to the server log and not to the client
+pub(crate) fn internal_error(e: impl std::fmt::Display) -> Box<dyn warp::Reply> {
+    eprintln!("internal error: {}", e);
+    v1::error(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+}
+
+fn opml_export(user: User) -> Box<dyn warp::Reply> {
+    match opml::export(user.id) {
+        Ok(xml) => Box::new(warp::reply::with_header(
+            xml,
+            "Content-Type",
+            "text/x-opml; charset=utf-8",
+        )),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn opml_import(user: &User, body: &[u8]) -> Box<dyn warp::Reply> {
+    let res = std::str::from_utf8(body)
+        .map_err(|e| e.into())
+        .and_then(|xml| opml::import(user.id, xml));
+    match res {
+        Ok(count) => Box::new(count.to_string()),
+        Err(e) if e.is::<rusqlite::Error>() => internal_error(e),
+        // the document isn't UTF-8 or OPML
+        Err(e) => Box::new(warp::reply::with_status(
+            e.to_string(),
+            StatusCode::BAD_REQUEST,
+        )),
+    }
+}
+
+fn search_query(user: &User, query: &SearchQuery) -> Box<dyn warp::Reply> {
+    match search::search(user.id, &query.q, query.limit, query.offset) {
+        Ok(hits) => Box::new(warp::reply::json(&hits)),
+        Err(e) => internal_error(e),
     }
-    Ok(cleaned_path)
 }
 
-fn page_query(query: &PageQuery) -> Result<warp::reply::Json, &'static str> {
-    let page = db::query_page_link(&query.path);
-    if page.is_none() {
-        return Ok(warp::reply::json(&(String::from("NoPage"), String::new())));
+async fn discover(url: &str) -> Result<Vec<FeedCandidate>, Box<dyn warp::Reply>> {
+    match feed::discover_feeds(url, true).await {
+        Ok(candidates) => Ok(candidates),
+        Err(e) => Err(v1::error(
+            StatusCode::BAD_GATEWAY,
+            &format!("failed to fetch {}: {}", url, e),
+        )),
     }
-    let path = ensure_path(&format!(
-        "./pages/{}.html",
-        page.as_ref().unwrap().title.clone()
-    ))?;
-    let data = fs::read_to_string(&path).unwrap();
-    let p = page.unwrap();
-    let time = p.publish_datetime.clone();
-    let (title, source) = {
-        if !p.readed {
-            db::update_page_read(&p.link).map_err(|_op| "update error")?;
-        }
-        (p.title.clone(), p.source.clone())
+}
+
+// the feeds found for a website, to pick one from
+async fn subscribe_candidates(query: SubscribeQuery) -> Result<Box<dyn warp::Reply>, Infallible> {
+    Ok(match discover(&query.url).await {
+        Ok(candidates) => Box::new(warp::reply::json(&candidates)),
+        Err(reply) => reply,
+    })
+}
+
+// subscribe a feed found for the given url. Unless the url is the one of a feed found, the
+// candidates come back with a 300 to pick one and subscribe its url, even when there's one.
+async fn subscribe(user: User, req: SubscribeRequest) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let candidates = match discover(&req.url).await {
+        Ok(candidates) => candidates,
+        Err(reply) => return Ok(reply),
     };
+    if candidates.is_empty() {
+        return Ok(v1::error(StatusCode::NOT_FOUND, "no feed found"));
+    }
+    let Some(chosen) = candidates.iter().find(|c| c.url == req.url) else {
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&candidates),
+            StatusCode::MULTIPLE_CHOICES,
+        )));
+    };
+    let res = match &req.folder {
+        Some(folder) => db::import_feed(user.id, &chosen.url, &chosen.title, "", folder),
+        None => db::add_feed(user.id, &chosen.url).map(|_| ()),
+    };
+    Ok(match res {
+        Ok(()) => Box::new(warp::reply::json(chosen)),
+        Err(e) => internal_error(e),
+    })
+}
 
-    return Ok(warp::reply::json(&(
-        title,
-        data,
-        query.path.clone(),
-        time,
-        source,
-    )));
+/// How the fetches of a feed went, newest first in `log`
+#[derive(Debug, Serialize)]
+struct FeedStatus {
+    id: i64,
+    url: String,
+    title: String,
+    #[serde(flatten)]
+    fetch: db::FetchState,
+    log: Vec<db::FetchLog>,
 }
 
-fn rss_query(query: &RssQuery) -> Result<String, Box<dyn Error>> {
-    let limits = if query.query_type == "unread" {
-        vec![("readed", "0")]
-    } else {
-        vec![]
+fn feed_status(user: &User, id: i64) -> Box<dyn warp::Reply> {
+    let feeds = match db::query_feeds(user.id) {
+        Ok(feeds) => feeds,
+        Err(e) => return internal_error(e),
     };
-    let mut pages = db::query_pages(&limits);
-    pages.sort_by(|a, b| {
-        b.publish_datetime
-            .parse::<DateTime<Local>>()
-            .unwrap()
-            .partial_cmp(&a.publish_datetime.parse::<DateTime<Local>>().unwrap())
-            .unwrap()
-    });
-
-    let page_limit = if query.query_type == "unread" {
-        30
-    } else {
-        100
+    let Some(feed) = feeds.into_iter().find(|f| f.id == id) else {
+        return v1::error(StatusCode::NOT_FOUND, "feed not found");
     };
-    let max_len = usize::min(page_limit as usize, pages.len());
-    let res: Vec<String> = pages[..max_len]
-        .iter()
-        .map(|page| {
-            let class = if page.readed { "visited" } else { "" };
-            let max = 65;
-            let title = if page.title.chars().count() > max {
-                page.title.chars().take(max).collect::<String>() + "..."
-            } else {
-                page.title.clone()
-            };
-            format!(
-                "<li><a class=\"{}\" id=\"{}\", href=\"#\">{}</a></li>",
-                class, page.link, title
-            )
-        })
-        .collect();
-    Ok(res.join(""))
-}
-
-fn rss_mark(_query: &Mark) -> Result<(), Box<dyn Error>> {
-    db::mark_pages_read(15)?;
-    Ok(())
+    match db::query_fetch_log(id, FETCH_LOG_SIZE) {
+        Ok(log) => Box::new(warp::reply::json(&FeedStatus {
+            id,
+            url: feed.url,
+            title: feed.title,
+            fetch: feed.fetch,
+            log,
+        })),
+        Err(e) => internal_error(e),
+    }
 }
 
-fn rss_remove(query: &MarkRemove) -> Result<(), Box<dyn Error>> {
-    let link = &query.link;
-    eprintln!("remove page {}", link);
-    db::remove_pages_from_link(link)?;
-    Ok(())
+// requests to the api without a user get a 401, other rejections go on as they are
+async fn unauthorized(err: warp::Rejection) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if err.find::<auth::Unauthorized>().is_some() {
+        Ok(v1::error(StatusCode::UNAUTHORIZED, "login required"))
+    } else {
+        Err(err)
+    }
 }
 
-pub async fn run_server(port: u16) {
-    pretty_env_logger::init();
+// scripts and styles only come from the server and the CDNs of the front page,
+// article bodies can't run anything even if the sanitizer misses something
+fn content_security_policy() -> String {
+    let cdns =
+        "https://cdn.jsdelivr.net https://stackpath.bootstrapcdn.com https://cdnjs.cloudflare.com";
+    let frames = EMBED_HOSTS
+        .iter()
+        .map(|host| format!("https://{}", host))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "default-src 'self'; script-src 'self' {0}; style-src 'self' {0}; font-src 'self' {0}; \
+         img-src 'self' data: https:; media-src 'self' https:; frame-src {1}; \
+         object-src 'none'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'",
+        cdns, frames
+    )
+}
 
+/// Every route of the web server, reached by clients at `public_url`
+pub fn routes(
+    public_url: &str,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     //let pages = warp::path("static").and(warp::fs::dir("./static/"));
     let routes = warp::path!("read").and(warp::fs::file("./front/public/index.html"));
     let front = warp::path("front").and(warp::fs::dir("./front/public/"));
@@ -128,58 +219,94 @@ pub async fn run_server(port: u16) {
 
     let page_images = warp::path("pages")
         .and(warp::path("images"))
-        .and(warp::fs::dir("./pages/images"));
-    let routes = routes.or(images).or(page_images);
-
-    let page = warp::path!("api" / "page")
-        .and(warp::get())
-        .and(warp::query::<PageQuery>())
-        .map(|query: PageQuery| {
-            let res = page_query(&query);
-            res.unwrap()
-        });
-    let routes = routes.or(page);
+        .and(warp::fs::dir(image_dir()));
+    let routes = routes.or(images).or(page_images).or(auth::routes());
 
-    let rss = warp::path!("api" / "rss")
-        .and(warp::get())
-        .and(warp::query::<RssQuery>())
-        .map(|query: RssQuery| {
-            let res = rss_query(&query);
-            if res.is_ok() {
-                format!("{}", res.unwrap())
-            } else {
-                format!("no-page")
-            }
-        });
+    // the api routes below answer for the user of the request, see `auth::user`
+    let routes = routes.or(v1::routes());
+    let routes = routes.or(fever::routes());
+    let routes = routes.or(greader::routes());
+    let routes = routes.or(publish::routes(public_url));
 
     let rss_mark = warp::path!("api" / "rss_mark")
         .and(warp::post())
+        .and(auth::user())
         .and(warp::query::<Mark>())
-        .map(|query: Mark| {
-            let res = rss_mark(&query);
+        .map(|user: User, query: Mark| {
+            let res = rss_mark(&user, &query);
             if res.is_ok() {
-                format!("ok")
+                "ok".to_string()
             } else {
-                format!("no-page")
+                "no-page".to_string()
             }
         });
-    let routes = routes.or(rss).or(rss_mark);
+    let routes = routes.or(rss_mark);
 
     let rss_remove = warp::path!("api" / "rss_remove")
         .and(warp::post())
+        .and(auth::user())
         .and(warp::body::json())
-        .map(|query: MarkRemove| {
-            let res = rss_remove(&query);
+        .map(|user: User, query: MarkRemove| {
+            let res = rss_remove(&user, &query);
             if res.is_ok() {
-                format!("ok")
+                "ok".to_string()
             } else {
-                format!("")
+                String::new()
             }
         });
     let routes = routes.or(rss_remove);
 
-    let log = warp::log("api");
-    let routes = routes.with(log);
+    let opml_get = warp::path!("api" / "opml")
+        .and(warp::get())
+        .and(auth::user())
+        .map(opml_export);
+    let opml_post = warp::path!("api" / "opml")
+        .and(warp::post())
+        .and(auth::user())
+        .and(warp::body::content_length_limit(4 * 1024 * 1024))
+        .and(warp::body::bytes())
+        .map(|user: User, body: bytes::Bytes| opml_import(&user, &body));
+    let routes = routes.or(opml_get).or(opml_post);
+
+    let search = warp::path!("api" / "search")
+        .and(warp::get())
+        .and(auth::user())
+        .and(warp::query::<SearchQuery>())
+        .map(|user: User, query: SearchQuery| search_query(&user, &query));
+    let candidates = warp::path!("api" / "subscribe")
+        .and(warp::get())
+        .and(auth::user())
+        .and(warp::query::<SubscribeQuery>())
+        .and_then(|_: User, query: SubscribeQuery| subscribe_candidates(query));
+    let subscribe = warp::path!("api" / "subscribe")
+        .and(warp::post())
+        .and(auth::user())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and_then(subscribe);
+    let routes = routes.or(candidates).or(subscribe);
+
+    let status = warp::path!("api" / "feeds" / i64 / "status")
+        .and(warp::get())
+        .and(auth::user())
+        .map(|id: i64, user: User| feed_status(&user, id));
+    let routes = routes.or(search).or(status).recover(unauthorized);
+
+    let csp = warp::reply::with::header("content-security-policy", content_security_policy());
+    routes.with(csp)
+}
+
+/// Serve on `port`, `public_url` is where clients reach the server, like
+/// `https://rss.example.com` behind a proxy, `http://localhost:<port>` when not set
+pub async fn run_server(port: u16, public_url: Option<String>) {
+    pretty_env_logger::init();
+
+    if auth::query_users().is_ok_and(|users| users.is_empty()) {
+        eprintln!("no users yet, create one with `rss-rs user add <name>` to use the api");
+    }
+
+    let public_url = public_url.unwrap_or_else(|| format!("http://localhost:{}", port));
+    let routes = routes(&public_url).with(warp::log("api"));
     println!("listen to : {} ...", port);
 
     warp::serve(routes).run((Ipv4Addr::UNSPECIFIED, port)).await
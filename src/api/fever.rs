@@ -0,0 +1,291 @@
+use super::internal_error;
+use crate::auth::{self, User};
+use crate::db::{self, Order, PageFilter};
+use chrono::prelude::*;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use warp::{Filter, Rejection, Reply};
+
+static API_VERSION: i64 = 3;
+// items returned by one request, as in the reference implementation
+static ITEMS_LIMIT: usize = 50;
+// favicons are not stored, every feed gets this blank gif
+static FAVICON_ID: i64 = 1;
+static FAVICON_DATA: &str =
+    "image/gif;base64,R0lGODlhAQABAIAAAObm5gAAACH5BAEAAAAALAAAAAABAAEAAAICRAEAOw==";
+
+// query string and form body together, the Fever flags like `?api&items` have empty values
+type Params = HashMap<String, String>;
+
+#[derive(Debug, Serialize)]
+struct Group {
+    id: i64,
+    title: String,
+}
+
+#[derive(Debug, Serialize)]
+struct FeedsGroup {
+    group_id: i64,
+    /// comma separated feed ids
+    feed_ids: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Feed {
+    id: i64,
+    favicon_id: i64,
+    title: String,
+    url: String,
+    site_url: String,
+    is_spark: u8,
+    last_updated_on_time: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct Item {
+    id: i64,
+    feed_id: i64,
+    title: String,
+    author: String,
+    html: String,
+    url: String,
+    is_saved: u8,
+    is_read: u8,
+    created_on_time: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct Favicon {
+    id: i64,
+    data: &'static str,
+}
+
+fn param<T: std::str::FromStr>(params: &Params, name: &str) -> Option<T> {
+    params.get(name).and_then(|v| v.trim().parse().ok())
+}
+
+fn ids(list: &str) -> Vec<i64> {
+    list.split(',')
+        .filter_map(|v| v.trim().parse().ok())
+        .collect()
+}
+
+fn join_ids(ids: impl Iterator<Item = i64>) -> String {
+    ids.map(|id| id.to_string()).collect::<Vec<_>>().join(",")
+}
+
+// Fever has flat groups, one for each folder the user files feeds in, numbered by name
+fn groups(feeds: &[db::Feed]) -> Vec<Group> {
+    let mut folders: Vec<&str> = feeds
+        .iter()
+        .map(|f| f.folder.as_str())
+        .filter(|f| !f.is_empty())
+        .collect();
+    folders.sort_unstable();
+    folders.dedup();
+    folders
+        .into_iter()
+        .zip(1..)
+        .map(|(title, id)| Group {
+            id,
+            title: title.to_string(),
+        })
+        .collect()
+}
+
+fn feeds_groups(feeds: &[db::Feed], groups: &[Group]) -> Vec<FeedsGroup> {
+    groups
+        .iter()
+        .map(|g| FeedsGroup {
+            group_id: g.id,
+            feed_ids: join_ids(feeds.iter().filter(|f| f.folder == g.title).map(|f| f.id)),
+        })
+        .collect()
+}
+
+fn fever_feeds(user: &User, feeds: &[db::Feed]) -> rusqlite::Result<Vec<Feed>> {
+    feeds
+        .iter()
+        .map(|f| {
+            let newest = db::query_pages(user.id, &PageFilter::new().feed_id(f.id).limit(1))?;
+            Ok(Feed {
+                id: f.id,
+                favicon_id: FAVICON_ID,
+                title: f.title.clone(),
+                url: f.url.clone(),
+                site_url: f.site_link.clone(),
+                is_spark: 0,
+                last_updated_on_time: newest.first().map_or(0, |p| p.published_at),
+            })
+        })
+        .collect()
+}
+
+fn items(user: &User, feeds: &[db::Feed], params: &Params) -> rusqlite::Result<Vec<Item>> {
+    let filter = PageFilter::new().limit(ITEMS_LIMIT);
+    let filter = if let Some(with_ids) = params.get("with_ids") {
+        let ids = ids(with_ids);
+        filter
+            .ids(&ids[..ids.len().min(ITEMS_LIMIT)])
+            .order(Order::IdAscending)
+    } else if let Some(max_id) = param(params, "max_id") {
+        filter.max_id(max_id).order(Order::IdDescending)
+    } else {
+        filter
+            .since_id(param(params, "since_id").unwrap_or(0))
+            .order(Order::IdAscending)
+    };
+    let feed_ids: HashMap<&str, i64> = feeds.iter().map(|f| (f.url.as_str(), f.id)).collect();
+    let pages = db::query_pages(user.id, &filter)?;
+    let ids: Vec<i64> = pages.iter().map(|p| p.id).collect();
+    let mut contents = db::query_page_contents(&ids)?;
+    let items = pages
+        .into_iter()
+        .map(|page| Item {
+            id: page.id,
+            feed_id: feed_ids.get(page.source.as_str()).copied().unwrap_or(0),
+            author: String::new(),
+            html: contents.remove(&page.id).unwrap_or_default(),
+            is_saved: page.starred as u8,
+            is_read: page.readed as u8,
+            created_on_time: page.published_at,
+            title: page.title,
+            url: page.link,
+        })
+        .collect();
+    Ok(items)
+}
+
+fn saved_ids(user: &User) -> rusqlite::Result<impl Iterator<Item = i64>> {
+    let saved = PageFilter::new().starred(true).order(Order::IdAscending);
+    Ok(db::query_pages(user.id, &saved)?.into_iter().map(|p| p.id))
+}
+
+// `mark=item` takes `as=read|unread|saved|unsaved`, feeds and groups are marked read up to `before`
+fn mark(user: &User, feeds: &[db::Feed], params: &Params) -> rusqlite::Result<()> {
+    let (Some(kind), Some(state), Some(id)) = (
+        params.get("mark"),
+        params.get("as"),
+        param::<i64>(params, "id"),
+    ) else {
+        return Ok(());
+    };
+    if kind == "item" {
+        match state.as_str() {
+            "read" => db::set_page_read(user.id, id, true)?,
+            "unread" => db::set_page_read(user.id, id, false)?,
+            "saved" => db::set_page_starred(user.id, id, true)?,
+            "unsaved" => db::set_page_starred(user.id, id, false)?,
+            _ => 0,
+        };
+        return Ok(());
+    }
+    let readed = match state.as_str() {
+        "read" => true,
+        "unread" => false,
+        _ => return Ok(()),
+    };
+    let before = param(params, "before")
+        .and_then(|t| Utc.timestamp_opt(t, 0).single())
+        .unwrap_or_else(Utc::now);
+    let filter = PageFilter::new().until(before);
+    let filter = match kind.as_str() {
+        "feed" => filter.feed_id(id),
+        // group 0 holds every feed
+        "group" if id == 0 => filter,
+        "group" => match groups(feeds).into_iter().find(|g| g.id == id) {
+            Some(group) => filter.folder(&group.title),
+            None => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+    db::set_pages_read(user.id, &filter, readed)?;
+    Ok(())
+}
+
+fn respond(user: &User, params: &Params) -> rusqlite::Result<Map<String, Value>> {
+    let feeds = db::query_feeds(user.id)?;
+    let mut res = Map::new();
+    if params.contains_key("mark") {
+        mark(user, &feeds, params)?;
+    }
+    if params.contains_key("groups") || params.contains_key("feeds") {
+        let groups = groups(&feeds);
+        res.insert("feeds_groups".into(), json(feeds_groups(&feeds, &groups)));
+        if params.contains_key("groups") {
+            res.insert("groups".into(), json(groups));
+        }
+        if params.contains_key("feeds") {
+            res.insert("feeds".into(), json(fever_feeds(user, &feeds)?));
+        }
+    }
+    if params.contains_key("favicons") {
+        let favicons = [Favicon {
+            id: FAVICON_ID,
+            data: FAVICON_DATA,
+        }];
+        res.insert("favicons".into(), json(favicons));
+    }
+    if params.contains_key("items") {
+        let total = db::count_pages(user.id, &PageFilter::new())?;
+        res.insert("total_items".into(), json(total));
+        res.insert("items".into(), json(items(user, &feeds, params)?));
+    }
+    if params.contains_key("links") {
+        res.insert("links".into(), json(Vec::<Value>::new()));
+    }
+    if params.contains_key("unread_item_ids") {
+        let unread = PageFilter::new().readed(false).order(Order::IdAscending);
+        let unread = db::query_pages(user.id, &unread)?;
+        let ids = join_ids(unread.into_iter().map(|p| p.id));
+        res.insert("unread_item_ids".into(), json(ids));
+    }
+    if params.contains_key("saved_item_ids") {
+        res.insert("saved_item_ids".into(), json(join_ids(saved_ids(user)?)));
+    }
+    Ok(res)
+}
+
+fn json<T: Serialize>(value: T) -> Value {
+    serde_json::to_value(value).unwrap()
+}
+
+fn fever(query: Params, form: Params) -> Box<dyn Reply> {
+    let mut params = query;
+    params.extend(form);
+    let mut res = Map::new();
+    res.insert("api_version".into(), json(API_VERSION));
+    let user = match params.get("api_key").map(|key| auth::fever_user(key)) {
+        Some(Ok(user)) => user,
+        Some(Err(e)) => return internal_error(e),
+        None => None,
+    };
+    let Some(user) = user else {
+        res.insert("auth".into(), json(0));
+        return Box::new(warp::reply::json(&res));
+    };
+    res.insert("auth".into(), json(1));
+    res.insert(
+        "last_refreshed_on_time".into(),
+        json(Utc::now().timestamp()),
+    );
+    match respond(&user, &params) {
+        Ok(more) => res.extend(more),
+        Err(e) => return internal_error(e),
+    }
+    Box::new(warp::reply::json(&res))
+}
+
+/// The Fever API under `/fever/`, authenticated by the `api_key` of the request
+pub(crate) fn routes() -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    let form = warp::post()
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::form::<Params>())
+        .or(warp::get().map(Params::new))
+        .unify();
+    warp::path!("fever")
+        .and(warp::query::<Params>())
+        .and(form)
+        .map(fever)
+}
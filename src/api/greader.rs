@@ -0,0 +1,918 @@
+use super::internal_error;
+use crate::auth::{self, User};
+use crate::db::{self, Order, PageFilter};
+use chrono::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+static READING_LIST: &str = "user/-/state/com.google/reading-list";
+static READ: &str = "user/-/state/com.google/read";
+static STARRED: &str = "user/-/state/com.google/starred";
+static KEPT_UNREAD: &str = "user/-/state/com.google/kept-unread";
+static LABEL: &str = "user/-/label/";
+static ITEM_PREFIX: &str = "tag:google.com,2005:reader/item/";
+static DEFAULT_COUNT: usize = 20;
+static MAX_IDS: usize = 10000;
+static MAX_CONTENTS: usize = 1000;
+
+// query string and form body together, keys like `i` and `a` are repeated
+type Params = Vec<(String, String)>;
+
+#[derive(Debug, Serialize)]
+struct Category {
+    id: String,
+    label: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Subscription {
+    id: String,
+    title: String,
+    categories: Vec<Category>,
+    url: String,
+    html_url: String,
+    icon_url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Tag {
+    id: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    kind: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ItemRef {
+    id: String,
+    timestamp_usec: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Link {
+    href: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    direction: &'static str,
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Origin {
+    stream_id: String,
+    title: String,
+    html_url: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Item {
+    id: String,
+    crawl_time_msec: String,
+    timestamp_usec: String,
+    published: i64,
+    updated: i64,
+    title: String,
+    author: String,
+    categories: Vec<String>,
+    alternate: Vec<Link>,
+    canonical: Vec<Link>,
+    summary: Summary,
+    origin: Origin,
+}
+
+#[derive(Debug, Serialize)]
+struct Stream {
+    direction: &'static str,
+    id: String,
+    updated: i64,
+    items: Vec<Item>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    continuation: Option<String>,
+}
+
+fn get<'a>(params: &'a Params, name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn get_all<'a>(params: &'a Params, name: &'a str) -> impl Iterator<Item = &'a str> {
+    params
+        .iter()
+        .filter(move |(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn text(status: StatusCode, body: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(body.to_string(), status))
+}
+
+// ids of other users are accepted in place of `-`, they always mean the user of the request
+fn normalize(stream: &str) -> String {
+    match stream.strip_prefix("user/").and_then(|s| s.split_once('/')) {
+        Some((_, rest)) => format!("user/-/{}", rest),
+        None => stream.to_string(),
+    }
+}
+
+// names of the tags of the user, a label is a tag when there is one of its name, a folder otherwise
+fn tag_names(user: &User) -> rusqlite::Result<Vec<String>> {
+    Ok(db::query_tags(user.id)?
+        .into_iter()
+        .map(|t| t.name)
+        .collect())
+}
+
+fn stream_filter(stream: &str, tags: &[String]) -> Option<PageFilter> {
+    let stream = normalize(stream);
+    let filter = PageFilter::new();
+    if stream == READING_LIST {
+        Some(filter)
+    } else if stream == READ {
+        Some(filter.readed(true))
+    } else if stream == STARRED {
+        Some(filter.starred(true))
+    } else if let Some(label) = stream.strip_prefix(LABEL) {
+        Some(if tags.iter().any(|t| t == label) {
+            filter.tag(label)
+        } else {
+            filter.folder(label)
+        })
+    } else {
+        // feeds are `feed/<id>`, original Google Reader clients send `feed/<url>`
+        let feed = stream.strip_prefix("feed/")?;
+        Some(match feed.parse() {
+            Ok(id) => filter.feed_id(id),
+            Err(_) => filter.source(feed),
+        })
+    }
+}
+
+// the filter for a stream request: `xt`/`it` exclude or include a state,
+// `ot`/`nt` bound the publish time, `r=o` is oldest first and `c` the offset
+fn stream_query(
+    stream: &str,
+    tags: &[String],
+    params: &Params,
+    max: usize,
+) -> Option<(PageFilter, usize, usize)> {
+    let mut filter = stream_filter(stream, tags)?;
+    for target in get_all(params, "xt").map(normalize) {
+        if target == READ {
+            filter = filter.readed(false);
+        } else if target == STARRED {
+            filter = filter.starred(false);
+        }
+    }
+    for target in get_all(params, "it").map(normalize) {
+        if target == READ {
+            filter = filter.readed(true);
+        } else if target == STARRED {
+            filter = filter.starred(true);
+        }
+    }
+    let time = |name| {
+        get(params, name)
+            .and_then(|t| t.parse().ok())
+            .and_then(|t| Utc.timestamp_opt(t, 0).single())
+    };
+    if let Some(since) = time("ot") {
+        filter = filter.since(since);
+    }
+    if let Some(until) = time("nt") {
+        filter = filter.until(until);
+    }
+    if get(params, "r") == Some("o") {
+        filter = filter.order(Order::OldestFirst);
+    }
+    let count = get(params, "n")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_COUNT)
+        .clamp(1, max);
+    let offset = get(params, "c").and_then(|c| c.parse().ok()).unwrap_or(0);
+    // fetch one more to know whether there is a continuation
+    Some((filter.limit(count + 1).offset(offset), count, offset))
+}
+
+// items are referred to by their decimal id, or in the long hexadecimal form
+fn item_id(id: &str) -> Option<i64> {
+    match id.strip_prefix(ITEM_PREFIX) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().map(|id| id as i64),
+        None => id.parse().ok(),
+    }
+}
+
+fn feed_stream(feed: &db::Feed) -> String {
+    format!("feed/{}", feed.id)
+}
+
+fn label(folder: &str) -> String {
+    format!("{}{}", LABEL, folder)
+}
+
+fn client_login(params: &Params) -> Box<dyn Reply> {
+    let (Some(name), Some(password)) = (get(params, "Email"), get(params, "Passwd")) else {
+        return text(StatusCode::UNAUTHORIZED, "Error=BadAuthentication\n");
+    };
+    match auth::login(name, password) {
+        Ok(Some(token)) => text(
+            StatusCode::OK,
+            &format!("SID={0}\nLSID={0}\nAuth={0}\n", token),
+        ),
+        Ok(None) => text(StatusCode::UNAUTHORIZED, "Error=BadAuthentication\n"),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn user_info(user: &User) -> Box<dyn Reply> {
+    Box::new(warp::reply::json(&serde_json::json!({
+        "userId": user.id.to_string(),
+        "userName": user.name,
+        "userProfileId": user.id.to_string(),
+        "userEmail": "",
+    })))
+}
+
+fn subscription_list(user: &User) -> Box<dyn Reply> {
+    let feeds = match db::query_feeds(user.id) {
+        Ok(feeds) => feeds,
+        Err(e) => return internal_error(e),
+    };
+    let subscriptions: Vec<Subscription> = feeds
+        .iter()
+        .map(|f| Subscription {
+            id: feed_stream(f),
+            title: f.title.clone(),
+            categories: if f.folder.is_empty() {
+                vec![]
+            } else {
+                vec![Category {
+                    id: label(&f.folder),
+                    label: f.folder.clone(),
+                }]
+            },
+            url: f.url.clone(),
+            html_url: f.site_link.clone(),
+            icon_url: String::new(),
+        })
+        .collect();
+    Box::new(warp::reply::json(
+        &serde_json::json!({ "subscriptions": subscriptions }),
+    ))
+}
+
+fn tag_list(user: &User) -> Box<dyn Reply> {
+    let feeds = match db::query_feeds(user.id) {
+        Ok(feeds) => feeds,
+        Err(e) => return internal_error(e),
+    };
+    let mut folders: Vec<&str> = feeds
+        .iter()
+        .map(|f| f.folder.as_str())
+        .filter(|f| !f.is_empty())
+        .collect();
+    folders.sort_unstable();
+    folders.dedup();
+    let mut tags = vec![Tag {
+        id: STARRED.to_string(),
+        kind: None,
+    }];
+    tags.extend(folders.into_iter().map(|f| Tag {
+        id: label(f),
+        kind: Some("folder"),
+    }));
+    let names = match tag_names(user) {
+        Ok(names) => names,
+        Err(e) => return internal_error(e),
+    };
+    tags.extend(names.iter().map(|t| Tag {
+        id: label(t),
+        kind: Some("tag"),
+    }));
+    Box::new(warp::reply::json(&serde_json::json!({ "tags": tags })))
+}
+
+fn item_ids(user: &User, params: &Params) -> Box<dyn Reply> {
+    let stream = get(params, "s").unwrap_or(READING_LIST);
+    let tags = match tag_names(user) {
+        Ok(tags) => tags,
+        Err(e) => return internal_error(e),
+    };
+    let Some((filter, count, offset)) = stream_query(stream, &tags, params, MAX_IDS) else {
+        return text(StatusCode::BAD_REQUEST, "unknown stream");
+    };
+    let mut pages = match db::query_pages(user.id, &filter) {
+        Ok(pages) => pages,
+        Err(e) => return internal_error(e),
+    };
+    let continuation = next_offset(&mut pages, count, offset);
+    let refs: Vec<ItemRef> = pages
+        .iter()
+        .map(|p| ItemRef {
+            id: p.id.to_string(),
+            timestamp_usec: (p.published_at * 1_000_000).to_string(),
+        })
+        .collect();
+    let mut body = serde_json::json!({ "itemRefs": refs });
+    if let Some(continuation) = continuation {
+        body["continuation"] = continuation.into();
+    }
+    Box::new(warp::reply::json(&body))
+}
+
+fn next_offset(pages: &mut Vec<db::Page>, count: usize, offset: usize) -> Option<String> {
+    if pages.len() > count {
+        pages.truncate(count);
+        Some((offset + count).to_string())
+    } else {
+        None
+    }
+}
+
+fn items(user: &User, pages: Vec<db::Page>) -> rusqlite::Result<Vec<Item>> {
+    let feeds = db::query_feeds(user.id)?;
+    let feeds: HashMap<&str, &db::Feed> = feeds.iter().map(|f| (f.url.as_str(), f)).collect();
+    let ids: Vec<i64> = pages.iter().map(|p| p.id).collect();
+    let mut tags = db::query_page_tags(user.id, &ids)?;
+    let mut contents = db::query_page_contents(&ids)?;
+    let items = pages
+        .into_iter()
+        .map(|page| {
+            let feed = feeds.get(page.source.as_str());
+            let mut categories = vec![READING_LIST.to_string()];
+            if let Some(folder) = feed.map(|f| &f.folder).filter(|f| !f.is_empty()) {
+                categories.push(label(folder));
+            }
+            if page.readed {
+                categories.push(READ.to_string());
+            }
+            if page.starred {
+                categories.push(STARRED.to_string());
+            }
+            for tag in tags.remove(&page.id).unwrap_or_default() {
+                categories.push(label(&tag));
+            }
+            Item {
+                id: format!("{}{:016x}", ITEM_PREFIX, page.id),
+                crawl_time_msec: (page.fetched_at * 1000).to_string(),
+                timestamp_usec: (page.published_at * 1_000_000).to_string(),
+                published: page.published_at,
+                updated: page.published_at,
+                author: String::new(),
+                categories,
+                alternate: vec![Link {
+                    href: page.link.clone(),
+                }],
+                canonical: vec![Link {
+                    href: page.link.clone(),
+                }],
+                summary: Summary {
+                    direction: "ltr",
+                    content: contents.remove(&page.id).unwrap_or_default(),
+                },
+                origin: Origin {
+                    stream_id: feed.map(|f| feed_stream(f)).unwrap_or_default(),
+                    title: feed.map(|f| f.title.clone()).unwrap_or_default(),
+                    html_url: page.website.clone(),
+                },
+                title: page.title,
+            }
+        })
+        .collect();
+    Ok(items)
+}
+
+fn stream_reply(
+    user: &User,
+    stream: &str,
+    pages: Vec<db::Page>,
+    continuation: Option<String>,
+) -> Box<dyn Reply> {
+    match items(user, pages) {
+        Ok(items) => Box::new(warp::reply::json(&Stream {
+            direction: "ltr",
+            id: stream.to_string(),
+            updated: Utc::now().timestamp(),
+            items,
+            continuation,
+        })),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn stream_contents(user: &User, stream: &str, params: &Params) -> Box<dyn Reply> {
+    let stream = if stream.is_empty() {
+        get(params, "s").unwrap_or(READING_LIST)
+    } else {
+        stream
+    };
+    let tags = match tag_names(user) {
+        Ok(tags) => tags,
+        Err(e) => return internal_error(e),
+    };
+    let Some((filter, count, offset)) = stream_query(stream, &tags, params, MAX_CONTENTS) else {
+        return text(StatusCode::BAD_REQUEST, "unknown stream");
+    };
+    let mut pages = match db::query_pages(user.id, &filter) {
+        Ok(pages) => pages,
+        Err(e) => return internal_error(e),
+    };
+    let continuation = next_offset(&mut pages, count, offset);
+    stream_reply(user, stream, pages, continuation)
+}
+
+fn items_contents(user: &User, params: &Params) -> Box<dyn Reply> {
+    let ids: Vec<i64> = get_all(params, "i").filter_map(item_id).collect();
+    if ids.len() > MAX_CONTENTS {
+        return text(StatusCode::BAD_REQUEST, "too many items");
+    }
+    match db::query_pages(user.id, &PageFilter::new().ids(&ids)) {
+        Ok(pages) => stream_reply(user, READING_LIST, pages, None),
+        Err(e) => internal_error(e),
+    }
+}
+
+// `a` adds and `r` removes the read and starred states and the labels of the `i` items,
+// labels put on items are tags
+fn edit_tag(user: &User, params: &Params) -> Box<dyn Reply> {
+    let ids: Vec<i64> = get_all(params, "i").filter_map(item_id).collect();
+    let add: Vec<String> = get_all(params, "a").map(normalize).collect();
+    let remove: Vec<String> = get_all(params, "r").map(normalize).collect();
+    for id in ids {
+        let res = add
+            .iter()
+            .map(|tag| (tag, true))
+            .chain(remove.iter().map(|tag| (tag, false)))
+            .try_for_each(|(tag, set)| {
+                if *tag == READ {
+                    db::set_page_read(user.id, id, set)?;
+                } else if *tag == STARRED {
+                    db::set_page_starred(user.id, id, set)?;
+                } else if *tag == KEPT_UNREAD && set {
+                    db::set_page_read(user.id, id, false)?;
+                } else if let Some(name) = tag.strip_prefix(LABEL).and_then(db::tag_name) {
+                    if set {
+                        db::add_page_tag(user.id, id, &name)?;
+                    } else {
+                        db::remove_page_tag(user.id, id, &name)?;
+                    }
+                }
+                Ok::<_, rusqlite::Error>(())
+            });
+        if let Err(e) = res {
+            return internal_error(e);
+        }
+    }
+    text(StatusCode::OK, "OK")
+}
+
+// marks the stream read up to `ts`, in microseconds since the unix epoch
+fn mark_all_as_read(user: &User, params: &Params) -> Box<dyn Reply> {
+    let tags = match tag_names(user) {
+        Ok(tags) => tags,
+        Err(e) => return internal_error(e),
+    };
+    let Some(filter) = get(params, "s").and_then(|s| stream_filter(s, &tags)) else {
+        return text(StatusCode::BAD_REQUEST, "unknown stream");
+    };
+    let until = get(params, "ts")
+        .and_then(|ts| ts.parse::<i64>().ok())
+        .and_then(|ts| Utc.timestamp_opt(ts / 1_000_000, 0).single())
+        .unwrap_or_else(Utc::now);
+    match db::set_pages_read(user.id, &filter.until(until), true) {
+        Ok(_) => text(StatusCode::OK, "OK"),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// The user of a request with an `Authorization: GoogleLogin auth=<token>` header,
+/// the token is a session from ClientLogin or an API token
+fn user() -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(
+        |authorization: Option<String>| async move {
+            let token = authorization
+                .as_deref()
+                .and_then(|h| h.strip_prefix("GoogleLogin auth="))
+                .map(str::trim);
+            match token.map(auth::authenticate) {
+                Some(Ok(Some(user))) => Ok(user),
+                _ => Err(warp::reject::custom(auth::Unauthorized)),
+            }
+        },
+    )
+}
+
+fn params() -> impl Filter<Extract = (Params,), Error = Rejection> + Clone {
+    let form = warp::post()
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::form::<Params>())
+        .or(warp::get().map(Params::new))
+        .unify();
+    warp::query::<Params>()
+        .and(form)
+        .map(|mut query: Params, form: Params| {
+            query.extend(form);
+            query
+        })
+}
+
+async fn recover(err: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    if err.find::<auth::Unauthorized>().is_some() {
+        Ok(text(StatusCode::UNAUTHORIZED, "Unauthorized"))
+    } else if err.find::<warp::reject::InvalidQuery>().is_some()
+        || err.find::<warp::body::BodyDeserializeError>().is_some()
+    {
+        Ok(text(StatusCode::BAD_REQUEST, "Bad Request"))
+    } else {
+        Err(err)
+    }
+}
+
+/// The Google Reader API, `/accounts/ClientLogin` and the routes under `/reader/api/0`
+pub(crate) fn routes() -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    let login = warp::path!("accounts" / "ClientLogin")
+        .and(params())
+        .map(|params: Params| client_login(&params));
+
+    let token = warp::path!("token")
+        .and(warp::get())
+        .and(user())
+        .and(warp::header::<String>("authorization"))
+        // write tokens are not checked, requests already carry the auth token
+        .map(|_: User, authorization: String| {
+            let token = authorization.trim_start_matches("GoogleLogin auth=");
+            text(StatusCode::OK, token)
+        });
+    let user_info = warp::path!("user-info")
+        .and(warp::get())
+        .and(user())
+        .map(|user: User| user_info(&user));
+    let subscriptions = warp::path!("subscription" / "list")
+        .and(warp::get())
+        .and(user())
+        .map(|user: User| subscription_list(&user));
+    let tags = warp::path!("tag" / "list")
+        .and(warp::get())
+        .and(user())
+        .map(|user: User| tag_list(&user));
+    let ids = warp::path!("stream" / "items" / "ids")
+        .and(user())
+        .and(params())
+        .map(|user: User, params: Params| item_ids(&user, &params));
+    let contents = warp::path!("stream" / "items" / "contents")
+        .and(user())
+        .and(params())
+        .map(|user: User, params: Params| items_contents(&user, &params));
+    let stream = warp::path!("stream" / "contents" / ..)
+        .and(warp::path::tail())
+        .and(user())
+        .and(params())
+        .map(|tail: warp::path::Tail, user: User, params: Params| {
+            let stream = percent_encoding::percent_decode_str(tail.as_str()).decode_utf8_lossy();
+            stream_contents(&user, &stream, &params)
+        });
+    let edit = warp::path!("edit-tag")
+        .and(warp::post())
+        .and(user())
+        .and(params())
+        .map(|user: User, params: Params| edit_tag(&user, &params));
+    let mark_all = warp::path!("mark-all-as-read")
+        .and(warp::post())
+        .and(user())
+        .and(params())
+        .map(|user: User, params: Params| mark_all_as_read(&user, &params));
+
+    let reader = token
+        .or(user_info)
+        .unify()
+        .or(subscriptions)
+        .unify()
+        .or(tags)
+        .unify()
+        .or(ids)
+        .unify()
+        .or(contents)
+        .unify()
+        .or(stream)
+        .unify()
+        .or(edit)
+        .unify()
+        .or(mark_all)
+        .unify();
+    let reader = warp::path!("reader" / "api" / "0" / ..).and(reader);
+    login.or(reader).unify().recover(recover).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::*;
+    use crate::testing::{self, TestDir};
+    use serde_json::Value;
+
+    // alice files feed A under "news" and B nowhere, she has read the first page
+    fn setup() -> rusqlite::Result<(TestDir, String)> {
+        let dir = TestDir::new()?;
+        let alice = auth::add_user("alice", "secret").unwrap();
+        import_feed(alice, "http://a.com/feed", "A", "http://a.com", "news")?;
+        import_feed(alice, "http://b.com/feed", "B", "http://b.com", "")?;
+        for (i, (source, title)) in [("a", "a0"), ("a", "a1"), ("b", "b0")].iter().enumerate() {
+            let link = format!("http://{}.com/{}", source, &title[1..]);
+            dump_new_page(&Page {
+                title: title.to_string(),
+                website: format!("http://{}.com", source),
+                published_at: 1600000000 + 100 * i as i64,
+                fetched_at: 1600000500,
+                ..testing::page(&link, &format!("http://{}.com/feed", source))
+            })?;
+            save_page_content(&link, &format!("<p>{}</p>", title))?;
+        }
+        set_page_read(alice, 1, true)?;
+        Ok((dir, auth::login("alice", "secret").unwrap().unwrap()))
+    }
+
+    async fn request(method: &str, path: &str, token: &str, body: &str) -> (StatusCode, String) {
+        let res = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", format!("GoogleLogin auth={}", token))
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body)
+            .reply(&routes())
+            .await;
+        let body = String::from_utf8(res.body().to_vec()).unwrap();
+        (res.status(), body)
+    }
+
+    async fn get_json(path: &str, token: &str) -> Value {
+        let (status, body) = request("GET", path, token, "").await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    fn ids(body: &Value) -> Vec<&str> {
+        body["itemRefs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["id"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_ids() {
+        assert_eq!(
+            item_id("tag:google.com,2005:reader/item/000000000000001f"),
+            Some(31)
+        );
+        assert_eq!(item_id("31"), Some(31));
+        assert_eq!(item_id("x"), None);
+        assert_eq!(normalize("user/1005/state/com.google/read"), READ);
+        let tags = ["rfc".to_string()];
+        assert_eq!(
+            stream_filter("feed/2", &tags),
+            Some(PageFilter::new().feed_id(2))
+        );
+        assert_eq!(
+            stream_filter("feed/http://a.com/feed", &tags),
+            Some(PageFilter::new().source("http://a.com/feed"))
+        );
+        assert_eq!(
+            stream_filter("user/-/label/news", &tags),
+            Some(PageFilter::new().folder("news"))
+        );
+        assert_eq!(
+            stream_filter("user/-/label/rfc", &tags),
+            Some(PageFilter::new().tag("rfc"))
+        );
+        assert_eq!(stream_filter("splice/1", &tags), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_login() -> rusqlite::Result<()> {
+        let _dir = setup()?;
+        let (status, body) = request(
+            "POST",
+            "/accounts/ClientLogin",
+            "",
+            "Email=alice&Passwd=secret",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let token = body.lines().last().unwrap().strip_prefix("Auth=").unwrap();
+        let info = get_json("/reader/api/0/user-info", token).await;
+        assert_eq!(info["userName"], "alice");
+        let (_, write_token) = request("GET", "/reader/api/0/token", token, "").await;
+        assert_eq!(write_token, token);
+
+        let (status, _) =
+            request("POST", "/accounts/ClientLogin", "", "Email=alice&Passwd=x").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = request("GET", "/reader/api/0/user-info", "nothing", "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        // other paths are left to the rest of the routes
+        let res = warp::test::request()
+            .path("/api/v1/entries")
+            .filter(&routes())
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions() -> rusqlite::Result<()> {
+        let (_dir, token) = setup()?;
+        let body = get_json("/reader/api/0/subscription/list?output=json", &token).await;
+        let subscriptions = body["subscriptions"].as_array().unwrap();
+        assert_eq!(subscriptions.len(), 2);
+        assert_eq!(subscriptions[0]["id"], "feed/1");
+        assert_eq!(subscriptions[0]["htmlUrl"], "http://a.com");
+        assert_eq!(subscriptions[0]["categories"][0]["id"], "user/-/label/news");
+        assert!(subscriptions[1]["categories"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+
+        let body = get_json("/reader/api/0/tag/list?output=json", &token).await;
+        assert_eq!(body["tags"][0]["id"], STARRED);
+        assert_eq!(body["tags"][1]["id"], "user/-/label/news");
+        assert_eq!(body["tags"][1]["type"], "folder");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streams() -> rusqlite::Result<()> {
+        let (_dir, token) = setup()?;
+        let path = "/reader/api/0/stream/items/ids?s=user/-/state/com.google/reading-list";
+        let body = get_json(path, &token).await;
+        assert_eq!(ids(&body), ["3", "2", "1"]);
+        assert!(body.get("continuation").is_none());
+
+        let body = get_json(&format!("{}&xt={}&n=1", path, READ), &token).await;
+        assert_eq!(ids(&body), ["3"]);
+        assert_eq!(body["continuation"], "1");
+        let body = get_json(&format!("{}&xt={}&n=1&c=1", path, READ), &token).await;
+        assert_eq!(ids(&body), ["2"]);
+        assert!(body.get("continuation").is_none());
+        let body = get_json(&format!("{}&r=o&ot=1600000050", path), &token).await;
+        assert_eq!(ids(&body), ["2", "3"]);
+
+        let body = get_json("/reader/api/0/stream/contents/feed%2F1?n=1", &token).await;
+        assert_eq!(body["id"], "feed/1");
+        let item = &body["items"][0];
+        assert_eq!(
+            item["id"],
+            "tag:google.com,2005:reader/item/0000000000000002"
+        );
+        assert_eq!(item["title"], "a1");
+        assert_eq!(item["summary"]["content"], "<p>a1</p>");
+        assert_eq!(item["canonical"][0]["href"], "http://a.com/1");
+        assert_eq!(item["origin"]["streamId"], "feed/1");
+        assert_eq!(item["timestampUsec"], "1600000100000000");
+        assert_eq!(item["crawlTimeMsec"], "1600000500000");
+        assert_eq!(item["categories"][1], "user/-/label/news");
+        assert_eq!(body["continuation"], "1");
+
+        let body = get_json(
+            "/reader/api/0/stream/contents/user%2F-%2Flabel%2Fnews",
+            &token,
+        )
+        .await;
+        assert_eq!(body["items"].as_array().unwrap().len(), 2);
+        assert!(body["items"][1]["categories"]
+            .as_array()
+            .unwrap()
+            .contains(&READ.into()));
+
+        let (status, body) = request(
+            "POST",
+            "/reader/api/0/stream/items/contents",
+            &token,
+            "i=tag:google.com,2005:reader/item/0000000000000003&i=1",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["items"].as_array().unwrap().len(), 2);
+
+        let (status, _) = request(
+            "GET",
+            "/reader/api/0/stream/contents/splice%2F1",
+            &token,
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_edit_tag() -> rusqlite::Result<()> {
+        let (_dir, token) = setup()?;
+        let unread = format!(
+            "/reader/api/0/stream/items/ids?s={}&xt={}",
+            READING_LIST, READ
+        );
+        let starred = format!("/reader/api/0/stream/items/ids?s={}", STARRED);
+
+        let (status, body) = request(
+            "POST",
+            "/reader/api/0/edit-tag",
+            &token,
+            &format!("i=2&i=3&a={}&T=x", READ),
+        )
+        .await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "OK"));
+        assert!(ids(&get_json(&unread, &token).await).is_empty());
+
+        let body = format!(
+            "i=tag:google.com,2005:reader/item/0000000000000002&r={}&a={}",
+            READ, STARRED
+        );
+        request("POST", "/reader/api/0/edit-tag", &token, &body).await;
+        assert_eq!(ids(&get_json(&unread, &token).await), ["2"]);
+        assert_eq!(ids(&get_json(&starred, &token).await), ["2"]);
+        let body = get_json(
+            "/reader/api/0/stream/contents?s=user/-/state/com.google/starred",
+            &token,
+        )
+        .await;
+        assert!(body["items"][0]["categories"]
+            .as_array()
+            .unwrap()
+            .contains(&Value::from(STARRED)));
+
+        request(
+            "POST",
+            "/reader/api/0/edit-tag",
+            &token,
+            &format!("i=2&r={}", STARRED),
+        )
+        .await;
+        assert!(ids(&get_json(&starred, &token).await).is_empty());
+
+        // labels put on items are tags
+        let tagged = "/reader/api/0/stream/items/ids?s=user/-/label/rfc";
+        request(
+            "POST",
+            "/reader/api/0/edit-tag",
+            &token,
+            "i=3&a=user/-/label/rfc",
+        )
+        .await;
+        assert_eq!(ids(&get_json(tagged, &token).await), ["3"]);
+        let body = get_json("/reader/api/0/stream/contents?s=user/-/label/rfc", &token).await;
+        assert!(body["items"][0]["categories"]
+            .as_array()
+            .unwrap()
+            .contains(&Value::from("user/-/label/rfc")));
+        let body = get_json("/reader/api/0/tag/list?output=json", &token).await;
+        assert_eq!(body["tags"][2]["type"], "tag");
+        request(
+            "POST",
+            "/reader/api/0/edit-tag",
+            &token,
+            "i=3&r=user/-/label/rfc",
+        )
+        .await;
+        assert!(ids(&get_json(tagged, &token).await).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mark_all_as_read() -> rusqlite::Result<()> {
+        let (_dir, token) = setup()?;
+        let unread = format!(
+            "/reader/api/0/stream/items/ids?s={}&xt={}",
+            READING_LIST, READ
+        );
+        // only what was published before `ts`
+        let (status, _) = request(
+            "POST",
+            "/reader/api/0/mark-all-as-read",
+            &token,
+            "s=feed/1&ts=1600000150000000",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&get_json(&unread, &token).await), ["3"]);
+
+        request(
+            "POST",
+            "/reader/api/0/mark-all-as-read",
+            &token,
+            &format!("s={}", READING_LIST),
+        )
+        .await;
+        assert!(ids(&get_json(&unread, &token).await).is_empty());
+        Ok(())
+    }
+}
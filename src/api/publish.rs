@@ -0,0 +1,516 @@
+use super::internal_error;
+use crate::auth::{self, User};
+use crate::conf::IMAGE_URL_PREFIX;
+use crate::db;
+use crate::sanitize;
+use chrono::prelude::*;
+use quick_xml::escape::escape;
+use serde::Serialize;
+use url::Url;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+// newest pages in a published feed
+static FEED_ITEMS: usize = 50;
+static GENERATOR: &str = "rss-rs";
+
+/// The pages a published feed is made of
+#[derive(Clone, Debug, PartialEq)]
+enum Selection {
+    All,
+    Starred,
+    Folder(String),
+    Tag(String),
+    Feed(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Atom,
+    Rss,
+    Json,
+}
+
+impl Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Atom => "application/atom+xml; charset=utf-8",
+            Format::Rss => "application/rss+xml; charset=utf-8",
+            Format::Json => "application/feed+json; charset=utf-8",
+        }
+    }
+}
+
+// `all.atom`, `starred.rss`, `folder/Rust/Compiler.json`, `tag/rfc.atom` or `feed/3.atom`
+fn parse_selector(path: &str) -> Option<(Selection, Format)> {
+    let (selector, ext) = path.rsplit_once('.')?;
+    let format = match ext {
+        "atom" => Format::Atom,
+        "rss" | "xml" => Format::Rss,
+        "json" => Format::Json,
+        _ => return None,
+    };
+    let selection = match selector.split_once('/') {
+        None if selector == "all" => Selection::All,
+        None if selector == "starred" => Selection::Starred,
+        Some(("folder", folder)) => Selection::Folder(db::folder_path(folder)),
+        Some(("tag", tag)) => Selection::Tag(db::tag_name(tag)?),
+        Some(("feed", id)) => Selection::Feed(id.parse().ok()?),
+        _ => return None,
+    };
+    Some((selection, format))
+}
+
+/// A published feed, before it is written in one of the formats
+#[derive(Debug)]
+struct Channel {
+    title: String,
+    /// url of the feed itself, without the token
+    self_url: String,
+    home_url: String,
+    updated: DateTime<Utc>,
+    items: Vec<Item>,
+}
+
+#[derive(Debug)]
+struct Item {
+    title: String,
+    link: String,
+    published: DateTime<Utc>,
+    fetched: DateTime<Utc>,
+    tags: Vec<String>,
+    /// body with absolute urls
+    content: String,
+}
+
+fn channel(
+    user: &User,
+    selection: &Selection,
+    base: &str,
+    path: &str,
+) -> rusqlite::Result<Option<Channel>> {
+    let filter = db::PageFilter::new().limit(FEED_ITEMS);
+    let (title, filter) = match selection {
+        Selection::All => ("all".to_string(), filter),
+        Selection::Starred => ("starred".to_string(), filter.starred(true)),
+        Selection::Folder(folder) => (format!("folder {}", folder), filter.folder(folder)),
+        Selection::Tag(tag) => (format!("tag {}", tag), filter.tag(tag)),
+        Selection::Feed(id) => {
+            let feeds = db::query_feeds(user.id)?;
+            let Some(feed) = feeds.into_iter().find(|f| f.id == *id) else {
+                return Ok(None);
+            };
+            let title = if feed.title.is_empty() {
+                feed.url
+            } else {
+                feed.title
+            };
+            (title, filter.feed_id(*id))
+        }
+    };
+    let pages = db::query_pages(user.id, &filter)?;
+    let ids: Vec<i64> = pages.iter().map(|p| p.id).collect();
+    let mut tags = db::query_page_tags(user.id, &ids)?;
+    let mut contents = db::query_page_contents(&ids)?;
+    let mut items = vec![];
+    for page in pages {
+        let body = contents.remove(&page.id).unwrap_or_default();
+        items.push(Item {
+            content: absolute_urls(&body, base, &page.link),
+            tags: tags.remove(&page.id).unwrap_or_default(),
+            published: page.published(),
+            fetched: Utc
+                .timestamp_opt(page.fetched_at, 0)
+                .single()
+                .unwrap_or_default(),
+            title: page.title,
+            link: page.link,
+        });
+    }
+    let updated = items
+        .iter()
+        .map(|item| item.published.max(item.fetched))
+        .max()
+        .unwrap_or_default();
+    Ok(Some(Channel {
+        title: format!("rss-rs: {}", title),
+        self_url: format!("{}/feeds/{}", base, path),
+        home_url: format!("{}/read", base),
+        updated,
+        items,
+    }))
+}
+
+// the absolute form of a url in a body, downloaded images are served by us and the rest
+// is relative to the page
+fn absolute_url(value: &str, base: &str, page: Option<&Url>) -> Option<String> {
+    if value.is_empty() || value.starts_with('#') || Url::parse(value).is_ok() {
+        return None;
+    }
+    if value.starts_with(IMAGE_URL_PREFIX) {
+        return Some(format!("{}{}", base, value));
+    }
+    page?.join(value).ok().map(String::from)
+}
+
+// the absolute form of every url in a `srcset`, None when they all are
+fn absolute_srcset(value: &str, base: &str, page: Option<&Url>) -> Option<String> {
+    let mut changed = false;
+    let candidates: Vec<String> = value
+        .split(',')
+        .map(|candidate| {
+            let candidate = candidate.trim();
+            let (url, size) = candidate.split_once(' ').unwrap_or((candidate, ""));
+            match absolute_url(url, base, page) {
+                Some(url) => {
+                    changed = true;
+                    format!("{} {}", url, size).trim_end().to_string()
+                }
+                None => candidate.to_string(),
+            }
+        })
+        .collect();
+    changed.then(|| candidates.join(", "))
+}
+
+// rewrite the relative urls of the `src`, `href`, `poster` and `srcset` attributes
+fn absolute_urls(body: &str, base: &str, link: &str) -> String {
+    let base = base.to_string();
+    let page = Url::parse(link).ok();
+    sanitize::rewrite_attributes(body, move |name, value| match name {
+        "src" | "href" | "poster" => absolute_url(value, &base, page.as_ref()),
+        "srcset" => absolute_srcset(value, &base, page.as_ref()),
+        _ => None,
+    })
+}
+
+fn atom(channel: &Channel) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str(&format!("  <title>{}</title>\n", escape(&channel.title)));
+    out.push_str(&format!("  <id>{}</id>\n", escape(&channel.self_url)));
+    out.push_str(&format!(
+        "  <link rel=\"self\" type=\"application/atom+xml\" href=\"{}\"/>\n",
+        escape(&channel.self_url)
+    ));
+    out.push_str(&format!(
+        "  <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n",
+        escape(&channel.home_url)
+    ));
+    out.push_str(&format!(
+        "  <updated>{}</updated>\n",
+        channel.updated.to_rfc3339_opts(SecondsFormat::Secs, true)
+    ));
+    out.push_str(&format!("  <author><name>{}</name></author>\n", GENERATOR));
+    out.push_str(&format!("  <generator>{}</generator>\n", GENERATOR));
+    for item in &channel.items {
+        out.push_str("  <entry>\n");
+        out.push_str(&format!("    <title>{}</title>\n", escape(&item.title)));
+        out.push_str(&format!("    <id>{}</id>\n", escape(&item.link)));
+        out.push_str(&format!(
+            "    <link rel=\"alternate\" href=\"{}\"/>\n",
+            escape(&item.link)
+        ));
+        out.push_str(&format!(
+            "    <published>{}</published>\n",
+            item.published.to_rfc3339_opts(SecondsFormat::Secs, true)
+        ));
+        out.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            item.published
+                .max(item.fetched)
+                .to_rfc3339_opts(SecondsFormat::Secs, true)
+        ));
+        for tag in &item.tags {
+            out.push_str(&format!("    <category term=\"{}\"/>\n", escape(tag)));
+        }
+        out.push_str(&format!(
+            "    <content type=\"html\">{}</content>\n",
+            escape(&item.content)
+        ));
+        out.push_str("  </entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+fn rss(channel: &Channel) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str("  <channel>\n");
+    out.push_str(&format!("    <title>{}</title>\n", escape(&channel.title)));
+    out.push_str(&format!("    <link>{}</link>\n", escape(&channel.home_url)));
+    out.push_str(&format!(
+        "    <description>{}</description>\n",
+        escape(&channel.title)
+    ));
+    out.push_str(&format!(
+        "    <atom:link rel=\"self\" type=\"application/rss+xml\" href=\"{}\"/>\n",
+        escape(&channel.self_url)
+    ));
+    out.push_str(&format!(
+        "    <lastBuildDate>{}</lastBuildDate>\n",
+        channel.updated.to_rfc2822()
+    ));
+    out.push_str(&format!("    <generator>{}</generator>\n", GENERATOR));
+    for item in &channel.items {
+        out.push_str("    <item>\n");
+        out.push_str(&format!("      <title>{}</title>\n", escape(&item.title)));
+        out.push_str(&format!("      <link>{}</link>\n", escape(&item.link)));
+        out.push_str(&format!(
+            "      <guid isPermaLink=\"true\">{}</guid>\n",
+            escape(&item.link)
+        ));
+        out.push_str(&format!(
+            "      <pubDate>{}</pubDate>\n",
+            item.published.to_rfc2822()
+        ));
+        for tag in &item.tags {
+            out.push_str(&format!("      <category>{}</category>\n", escape(tag)));
+        }
+        out.push_str(&format!(
+            "      <description>{}</description>\n",
+            escape(&item.content)
+        ));
+        out.push_str("    </item>\n");
+    }
+    out.push_str("  </channel>\n</rss>\n");
+    out
+}
+
+#[derive(Debug, Serialize)]
+struct JsonFeed<'a> {
+    version: &'static str,
+    title: &'a str,
+    home_page_url: &'a str,
+    feed_url: &'a str,
+    items: Vec<JsonItem<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonItem<'a> {
+    id: &'a str,
+    url: &'a str,
+    title: &'a str,
+    content_html: &'a str,
+    date_published: String,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tags: &'a [String],
+}
+
+fn json_feed(channel: &Channel) -> String {
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: &channel.title,
+        home_page_url: &channel.home_url,
+        feed_url: &channel.self_url,
+        items: channel
+            .items
+            .iter()
+            .map(|item| JsonItem {
+                id: &item.link,
+                url: &item.link,
+                title: &item.title,
+                content_html: &item.content,
+                date_published: item.published.to_rfc3339_opts(SecondsFormat::Secs, true),
+                tags: &item.tags,
+            })
+            .collect(),
+    };
+    serde_json::to_string(&feed).unwrap_or_default()
+}
+
+// `path` is still percent-encoded, as it goes in the self link
+fn publish(user: &User, path: &str, base: &str) -> Box<dyn Reply> {
+    let selector = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+    let Some((selection, format)) = parse_selector(&selector) else {
+        return Box::new(warp::reply::with_status(
+            "unknown feed",
+            StatusCode::NOT_FOUND,
+        ));
+    };
+    let channel = match channel(user, &selection, base, path) {
+        Ok(Some(channel)) => channel,
+        Ok(None) => {
+            return Box::new(warp::reply::with_status(
+                "unknown feed",
+                StatusCode::NOT_FOUND,
+            ))
+        }
+        Err(e) => return internal_error(e),
+    };
+    let body = match format {
+        Format::Atom => atom(&channel),
+        Format::Rss => rss(&channel),
+        Format::Json => json_feed(&channel),
+    };
+    Box::new(warp::reply::with_header(
+        body,
+        "Content-Type",
+        format.content_type(),
+    ))
+}
+
+/// The collected pages published as feeds at `/feeds/<selection>.<atom|rss|json>`, feed
+/// readers can pass the api token in the `token` query parameter. Links to the server start
+/// with `public_url`, never with what the request claims the host is.
+pub(crate) fn routes(
+    public_url: &str,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    let base = public_url.trim_end_matches('/').to_string();
+    warp::path("feeds")
+        .and(warp::path::tail())
+        .and(warp::get())
+        .and(auth::user_or_query_token())
+        .map(move |tail: warp::path::Tail, user: User| publish(&user, tail.as_str(), &base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::*;
+    use crate::testing::{self, TestDir};
+
+    #[test]
+    fn test_parse_selector() {
+        assert_eq!(
+            parse_selector("all.atom"),
+            Some((Selection::All, Format::Atom))
+        );
+        assert_eq!(
+            parse_selector("starred.rss"),
+            Some((Selection::Starred, Format::Rss))
+        );
+        assert_eq!(
+            parse_selector("folder/Rust//Compiler.json"),
+            Some((Selection::Folder("Rust/Compiler".to_string()), Format::Json))
+        );
+        assert_eq!(
+            parse_selector("tag/v1.2.atom"),
+            Some((Selection::Tag("v1.2".to_string()), Format::Atom))
+        );
+        assert_eq!(
+            parse_selector("feed/3.atom"),
+            Some((Selection::Feed(3), Format::Atom))
+        );
+        assert_eq!(parse_selector("all"), None);
+        assert_eq!(parse_selector("all.html"), None);
+        assert_eq!(parse_selector("feed/x.atom"), None);
+        assert_eq!(parse_selector("tag/.atom"), None);
+    }
+
+    #[test]
+    fn test_absolute_urls() {
+        // a stored body, sanitized on fetch
+        let body = r##"<p><img src="/pages/images/ab.png" srcset="/pages/images/ab.png 2x, https://cdn.com/c.png 3x"><a href="../post?a=1&amp;b=2" rel="noopener noreferrer">next</a><a href="https://other.com/" rel="noopener noreferrer">x</a><a href="#note" rel="noopener noreferrer">1</a></p>"##;
+        assert_eq!(
+            absolute_urls(body, "https://rss.example", "https://blog.com/2023/post"),
+            r##"<p><img src="https://rss.example/pages/images/ab.png" srcset="https://rss.example/pages/images/ab.png 2x, https://cdn.com/c.png 3x"><a href="https://blog.com/post?a=1&amp;b=2" rel="noopener noreferrer">next</a><a href="https://other.com/" rel="noopener noreferrer">x</a><a href="#note" rel="noopener noreferrer">1</a></p>"##
+        );
+        // only the attributes themselves change, whatever their escaping
+        let body =
+            r#"<img data-src="/a.png" src="/a.png" alt='src="/a.png"'><a href="/it's">it's</a>"#;
+        assert_eq!(
+            absolute_urls(body, "https://rss.example", "https://blog.com/post"),
+            r#"<img src="https://blog.com/a.png" alt="src=&quot;/a.png&quot;"><a href="https://blog.com/it's" rel="noopener noreferrer">it's</a>"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish() -> Result<(), Box<dyn std::error::Error>> {
+        let _dir = TestDir::new()?;
+        let alice = auth::add_user("alice", "secret")?;
+        import_feed(alice, "http://a.com/feed", "A", "http://a.com", "news")?;
+        import_feed(alice, "http://b.com/feed", "B", "http://b.com", "")?;
+        for (i, source) in ["a", "a", "b"].iter().enumerate() {
+            let link = format!("http://{}.com/{}", source, i);
+            dump_new_page(&Page {
+                title: format!("title <{}>", i),
+                website: format!("http://{}.com", source),
+                published_at: 1600000000 + 100 * i as i64,
+                fetched_at: 1600000500,
+                ..testing::page(&link, &format!("http://{}.com/feed", source))
+            })?;
+            save_page_content(
+                &link,
+                &format!(r#"<p>body {}<img src="/pages/images/{}.png"></p>"#, i, i),
+            )?;
+        }
+        add_page_tag(alice, 1, "rfc")?;
+        set_page_starred(alice, 3, true)?;
+        let token = auth::create_api_token("alice", "feeds")?;
+        let routes = routes("https://rss.example/");
+
+        // links to the server don't follow what the request claims the host is
+        let get = |path: &str| {
+            warp::test::request()
+                .path(&format!("{}?token={}", path, token))
+                .header("host", "evil.example")
+                .header("x-forwarded-proto", "http")
+                .reply(&routes)
+        };
+        let res = get("/feeds/all.atom").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()["content-type"],
+            "application/atom+xml; charset=utf-8"
+        );
+        let feed = feed_rs::parser::parse(res.body().as_ref())?;
+        assert_eq!(feed.title.unwrap().content, "rss-rs: all");
+        assert_eq!(feed.links[0].href, "https://rss.example/feeds/all.atom");
+        assert_eq!(feed.entries.len(), 3);
+        let entry = &feed.entries[2];
+        assert_eq!(entry.title.as_ref().unwrap().content, "title <0>");
+        assert_eq!(entry.links[0].href, "http://a.com/0");
+        assert_eq!(entry.categories[0].term, "rfc");
+        assert!(entry
+            .content
+            .as_ref()
+            .and_then(|c| c.body.as_ref())
+            .unwrap()
+            .contains(r#"src="https://rss.example/pages/images/0.png""#));
+
+        let res = get("/feeds/folder/news.rss").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let feed = feed_rs::parser::parse(res.body().as_ref())?;
+        let links: Vec<&str> = feed
+            .entries
+            .iter()
+            .map(|e| e.links[0].href.as_str())
+            .collect();
+        assert_eq!(links, ["http://a.com/1", "http://a.com/0"]);
+        assert_eq!(feed.entries[1].categories[0].term, "rfc");
+        assert!(feed.entries[1]
+            .summary
+            .as_ref()
+            .unwrap()
+            .content
+            .contains("https://rss.example/pages/images/0.png"));
+
+        let res = get("/feeds/starred.json").await;
+        let json: serde_json::Value = serde_json::from_slice(res.body())?;
+        assert_eq!(json["items"].as_array().unwrap().len(), 1);
+        assert_eq!(json["items"][0]["url"], "http://b.com/2");
+        let res = get("/feeds/tag/rfc.atom").await;
+        let feed = feed_rs::parser::parse(res.body().as_ref())?;
+        assert_eq!(feed.entries.len(), 1);
+        let res = get("/feeds/feed/2.atom").await;
+        let feed = feed_rs::parser::parse(res.body().as_ref())?;
+        assert_eq!(feed.title.unwrap().content, "rss-rs: B");
+
+        assert_eq!(
+            get("/feeds/feed/9.atom").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get("/feeds/everything.atom").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let res = warp::test::request()
+            .path("/feeds/all.atom")
+            .reply(&routes.recover(crate::api::unauthorized))
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
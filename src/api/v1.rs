@@ -0,0 +1,741 @@
+use super::internal_error;
+use crate::auth::{self, User};
+use crate::db;
+use crate::feed;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+static MAX_LIMIT: usize = 500;
+
+/// An entry of a feed, `content` is only filled when a single entry is requested
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Entry {
+    pub id: i64,
+    pub feed_url: String,
+    pub title: String,
+    pub link: String,
+    pub website: String,
+    /// seconds since the unix epoch
+    pub published_at: i64,
+    pub fetched_at: i64,
+    pub read: bool,
+    pub starred: bool,
+    /// tags the user put on the entry
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+impl From<db::Page> for Entry {
+    fn from(page: db::Page) -> Self {
+        Entry {
+            id: page.id,
+            feed_url: page.source,
+            title: page.title,
+            link: page.link,
+            website: page.website,
+            published_at: page.published_at,
+            fetched_at: page.fetched_at,
+            read: page.readed,
+            starred: page.starred,
+            tags: vec![],
+            content: None,
+        }
+    }
+}
+
+/// One page of entries, `next_offset` is set when there are more
+#[derive(Debug, Serialize)]
+pub struct EntryList {
+    pub entries: Vec<Entry>,
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiError {
+    code: u16,
+    message: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+#[derive(Debug, Deserialize)]
+struct EntriesQuery {
+    read: Option<bool>,
+    starred: Option<bool>,
+    feed: Option<i64>,
+    /// folder path, empty for the feeds outside any folder
+    folder: Option<String>,
+    tag: Option<String>,
+    /// seconds since the unix epoch
+    since: Option<i64>,
+    until: Option<i64>,
+    #[serde(default)]
+    order: Order,
+    #[serde(default = "default_limit")]
+    limit: usize,
+    #[serde(default)]
+    offset: usize,
+}
+
+fn default_limit() -> usize {
+    50
+}
+
+#[derive(Debug, Deserialize)]
+struct TagQuery {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FolderUpdate {
+    folder: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EntryUpdate {
+    read: Option<bool>,
+    starred: Option<bool>,
+}
+
+pub(super) fn error(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    let body = ApiError {
+        code: status.as_u16(),
+        message: message.to_string(),
+    };
+    Box::new(warp::reply::with_status(warp::reply::json(&body), status))
+}
+
+fn timestamp(secs: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(secs, 0).single()
+}
+
+fn list_entries(user: &User, query: &EntriesQuery) -> Box<dyn Reply> {
+    if query.limit == 0 || query.limit > MAX_LIMIT {
+        return error(
+            StatusCode::BAD_REQUEST,
+            &format!("limit must be between 1 and {}", MAX_LIMIT),
+        );
+    }
+    // fetch one more to know whether there is a next page
+    let mut filter = db::PageFilter::new()
+        .limit(query.limit + 1)
+        .offset(query.offset)
+        .order(match query.order {
+            Order::Newest => db::Order::NewestFirst,
+            Order::Oldest => db::Order::OldestFirst,
+        });
+    if let Some(read) = query.read {
+        filter = filter.readed(read);
+    }
+    if let Some(starred) = query.starred {
+        filter = filter.starred(starred);
+    }
+    if let Some(feed) = query.feed {
+        filter = filter.feed_id(feed);
+    }
+    if let Some(folder) = &query.folder {
+        filter = filter.folder(&db::folder_path(folder));
+    }
+    if let Some(tag) = &query.tag {
+        filter = filter.tag(tag.trim());
+    }
+    if let Some(since) = query.since {
+        let Some(since) = timestamp(since) else {
+            return error(StatusCode::BAD_REQUEST, "invalid since");
+        };
+        filter = filter.since(since);
+    }
+    if let Some(until) = query.until {
+        let Some(until) = timestamp(until) else {
+            return error(StatusCode::BAD_REQUEST, "invalid until");
+        };
+        filter = filter.until(until);
+    }
+    let mut entries: Vec<Entry> = match db::query_pages(user.id, &filter) {
+        Ok(pages) => pages.into_iter().map(Entry::from).collect(),
+        Err(e) => return internal_error(e),
+    };
+    let next_offset = if entries.len() > query.limit {
+        entries.truncate(query.limit);
+        Some(query.offset + query.limit)
+    } else {
+        None
+    };
+    let ids: Vec<i64> = entries.iter().map(|e| e.id).collect();
+    let mut tags = match db::query_page_tags(user.id, &ids) {
+        Ok(tags) => tags,
+        Err(e) => return internal_error(e),
+    };
+    for entry in &mut entries {
+        entry.tags = tags.remove(&entry.id).unwrap_or_default();
+    }
+    Box::new(warp::reply::json(&EntryList {
+        entries,
+        next_offset,
+    }))
+}
+
+fn get_entry(user: &User, id: i64) -> Box<dyn Reply> {
+    match db::query_page(user.id, id) {
+        Ok(Some(page)) => entry_with_content(user, page),
+        Ok(None) => error(StatusCode::NOT_FOUND, "entry not found"),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn entry_with_content(user: &User, page: db::Page) -> Box<dyn Reply> {
+    let content = match db::query_page_content(page.id) {
+        Ok(content) => content.unwrap_or_default(),
+        Err(e) => return internal_error(e),
+    };
+    let tags = match db::query_page_tags(user.id, &[page.id]) {
+        Ok(mut tags) => tags.remove(&page.id).unwrap_or_default(),
+        Err(e) => return internal_error(e),
+    };
+    let entry = Entry {
+        tags,
+        content: Some(content),
+        ..Entry::from(page)
+    };
+    Box::new(warp::reply::json(&entry))
+}
+
+fn update_entry(user: &User, id: i64, update: &EntryUpdate) -> Box<dyn Reply> {
+    let mut page = match db::query_page(user.id, id) {
+        Ok(Some(page)) => page,
+        Ok(None) => return error(StatusCode::NOT_FOUND, "entry not found"),
+        Err(e) => return internal_error(e),
+    };
+    if let Some(read) = update.read {
+        if let Err(e) = db::set_page_read(user.id, id, read) {
+            return internal_error(e);
+        }
+    }
+    if let Some(starred) = update.starred {
+        if let Err(e) = db::set_page_starred(user.id, id, starred) {
+            return internal_error(e);
+        }
+    }
+    // an unstarred entry of an unsubscribed feed is no longer listed, answer with it anyway
+    page.readed = update.read.unwrap_or(page.readed);
+    page.starred = update.starred.unwrap_or(page.starred);
+    entry_with_content(user, page)
+}
+
+fn tag_entry(user: &User, id: i64, name: &str, add: bool) -> Box<dyn Reply> {
+    let Some(name) = db::tag_name(name) else {
+        return error(StatusCode::BAD_REQUEST, "invalid tag name");
+    };
+    match db::query_page(user.id, id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return error(StatusCode::NOT_FOUND, "entry not found"),
+        Err(e) => return internal_error(e),
+    }
+    let res = if add {
+        db::add_page_tag(user.id, id, &name)
+    } else {
+        db::remove_page_tag(user.id, id, &name)
+    };
+    match res {
+        Ok(_) => get_entry(user, id),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn list_tags(user: User) -> Box<dyn Reply> {
+    match db::query_tags(user.id) {
+        Ok(tags) => Box::new(warp::reply::json(&tags)),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn star_entry(user: &User, id: i64, starred: bool) -> Box<dyn Reply> {
+    update_entry(
+        user,
+        id,
+        &EntryUpdate {
+            read: None,
+            starred: Some(starred),
+        },
+    )
+}
+
+fn list_feeds(user: User) -> Box<dyn Reply> {
+    match db::query_feeds(user.id) {
+        Ok(feeds) => Box::new(warp::reply::json(&feeds)),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn list_folders(user: User) -> Box<dyn Reply> {
+    match db::query_folders(user.id) {
+        Ok(folders) => Box::new(warp::reply::json(&folders)),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn update_folder(user: &User, feed_id: i64, update: &FolderUpdate) -> Box<dyn Reply> {
+    match db::set_feed_folder(user.id, feed_id, &update.folder) {
+        Ok(0) => error(StatusCode::NOT_FOUND, "feed not found"),
+        Ok(_) => match db::query_feeds(user.id) {
+            Ok(feeds) => Box::new(warp::reply::json(
+                &feeds.into_iter().find(|f| f.id == feed_id),
+            )),
+            Err(e) => internal_error(e),
+        },
+        Err(e) => internal_error(e),
+    }
+}
+
+fn get_rules(user: &User, feed_id: i64) -> Box<dyn Reply> {
+    match db::query_feeds(user.id) {
+        Ok(feeds) => match feeds.into_iter().find(|f| f.id == feed_id) {
+            Some(feed) => Box::new(warp::reply::json(&feed.rules)),
+            None => error(StatusCode::NOT_FOUND, "feed not found"),
+        },
+        Err(e) => internal_error(e),
+    }
+}
+
+fn update_rules(user: &User, feed_id: i64, rules: &db::ExtractRules) -> Box<dyn Reply> {
+    // pages are stored once for all users, the rules apply to everyone
+    if !user.admin {
+        return error(
+            StatusCode::FORBIDDEN,
+            "only admins can change the rules of a feed",
+        );
+    }
+    if let Err(e) = feed::check_rules(rules) {
+        return error(StatusCode::BAD_REQUEST, &e);
+    }
+    match db::set_feed_rules(user.id, feed_id, rules) {
+        Ok(0) => error(StatusCode::NOT_FOUND, "feed not found"),
+        Ok(_) => get_rules(user, feed_id),
+        Err(e) => internal_error(e),
+    }
+}
+
+// rejections of requests under /api/v1 are answered with an ApiError too
+async fn recover(err: Rejection) -> Result<Box<dyn Reply>, Infallible> {
+    let reply = if err.find::<auth::Unauthorized>().is_some() {
+        error(StatusCode::UNAUTHORIZED, "login required")
+    } else if err.is_not_found() {
+        error(StatusCode::NOT_FOUND, "not found")
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        error(StatusCode::BAD_REQUEST, &e.to_string())
+    } else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+        error(StatusCode::BAD_REQUEST, &e.to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+    } else {
+        internal_error(format!("{:?}", err))
+    };
+    Ok(reply)
+}
+
+/// Routes of the JSON API under `/api/v1`
+pub(crate) fn routes() -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    let entries = warp::path!("entries")
+        .and(warp::get())
+        .and(auth::user())
+        .and(warp::query::<EntriesQuery>())
+        .map(|user: User, query: EntriesQuery| list_entries(&user, &query));
+    let entry = warp::path!("entries" / i64)
+        .and(warp::get())
+        .and(auth::user())
+        .map(|id: i64, user: User| get_entry(&user, id));
+    let entry_update = warp::path!("entries" / i64)
+        .and(warp::patch())
+        .and(auth::user())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .map(|id: i64, user: User, update: EntryUpdate| update_entry(&user, id, &update));
+    let star = warp::path!("entries" / i64 / "star")
+        .and(warp::put())
+        .and(auth::user())
+        .map(|id: i64, user: User| star_entry(&user, id, true));
+    let unstar = warp::path!("entries" / i64 / "star")
+        .and(warp::delete())
+        .and(auth::user())
+        .map(|id: i64, user: User| star_entry(&user, id, false));
+    let tag = warp::path!("entries" / i64 / "tags")
+        .and(warp::put())
+        .and(auth::user())
+        .and(warp::query::<TagQuery>())
+        .map(|id: i64, user: User, tag: TagQuery| tag_entry(&user, id, &tag.name, true));
+    let untag = warp::path!("entries" / i64 / "tags")
+        .and(warp::delete())
+        .and(auth::user())
+        .and(warp::query::<TagQuery>())
+        .map(|id: i64, user: User, tag: TagQuery| tag_entry(&user, id, &tag.name, false));
+    let tags = warp::path!("tags")
+        .and(warp::get())
+        .and(auth::user())
+        .map(list_tags);
+    let feeds = warp::path!("feeds")
+        .and(warp::get())
+        .and(auth::user())
+        .map(list_feeds);
+    let folder_update = warp::path!("feeds" / i64 / "folder")
+        .and(warp::put())
+        .and(auth::user())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .map(|id: i64, user: User, update: FolderUpdate| update_folder(&user, id, &update));
+    let folders = warp::path!("folders")
+        .and(warp::get())
+        .and(auth::user())
+        .map(list_folders);
+    let rules = warp::path!("feeds" / i64 / "rules")
+        .and(warp::get())
+        .and(auth::user())
+        .map(|id: i64, user: User| get_rules(&user, id));
+    let rules_update = warp::path!("feeds" / i64 / "rules")
+        .and(warp::put())
+        .and(auth::user())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .map(|id: i64, user: User, rules: db::ExtractRules| update_rules(&user, id, &rules));
+
+    let api = entries
+        .or(entry)
+        .unify()
+        .or(entry_update)
+        .unify()
+        .or(star)
+        .unify()
+        .or(unstar)
+        .unify()
+        .or(tag)
+        .unify()
+        .or(untag)
+        .unify()
+        .or(tags)
+        .unify()
+        .or(feeds)
+        .unify()
+        .or(folder_update)
+        .unify()
+        .or(folders)
+        .unify()
+        .or(rules)
+        .unify()
+        .or(rules_update)
+        .unify()
+        .recover(recover)
+        .unify();
+    warp::path!("api" / "v1" / ..).and(api)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::*;
+    use crate::testing::{self, TestDir};
+    use serde_json::Value;
+
+    // alice subscribes the feed with three entries, return her user id with the test dir
+    fn setup() -> rusqlite::Result<(TestDir, i64)> {
+        let dir = TestDir::new()?;
+        let alice = auth::add_user("alice", "secret").unwrap();
+        auth::add_user("bob", "secret").unwrap();
+        add_feed(alice, "source")?;
+        for i in 0..3 {
+            let link = format!("link{}", i);
+            dump_new_page(&Page {
+                title: format!("title{}", i),
+                published_at: i,
+                ..testing::page(&link, "source")
+            })?;
+            save_page_content(&link, &format!("<p>body{}</p>", i))?;
+        }
+        let first = query_page_link(alice, "link0")?.unwrap();
+        set_page_read(alice, first.id, true)?;
+        Ok((dir, alice))
+    }
+
+    fn bearer(name: &str) -> String {
+        format!("Bearer {}", auth::create_api_token(name, "test").unwrap())
+    }
+
+    async fn get_as(name: &str, path: &str) -> (StatusCode, Value) {
+        let res = warp::test::request()
+            .path(path)
+            .header("authorization", bearer(name))
+            .reply(&routes())
+            .await;
+        (res.status(), serde_json::from_slice(res.body()).unwrap())
+    }
+
+    async fn get(path: &str) -> (StatusCode, Value) {
+        get_as("alice", path).await
+    }
+
+    #[tokio::test]
+    async fn test_entries() -> rusqlite::Result<()> {
+        let _dir = setup()?;
+        let (status, body) = get("/api/v1/entries?limit=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["entries"].as_array().unwrap().len(), 2);
+        assert_eq!(body["entries"][0]["title"], "title2");
+        assert!(body["entries"][0].get("content").is_none());
+        assert_eq!(body["next_offset"], 2);
+
+        let (_, body) = get("/api/v1/entries?limit=2&offset=2").await;
+        assert_eq!(body["entries"][0]["title"], "title0");
+        assert!(body["next_offset"].is_null());
+
+        let (_, body) = get("/api/v1/entries?read=false&order=oldest").await;
+        assert_eq!(body["entries"][0]["title"], "title1");
+        assert_eq!(body["entries"].as_array().unwrap().len(), 2);
+
+        let (_, body) = get("/api/v1/entries?since=1&until=2").await;
+        assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_entry() -> rusqlite::Result<()> {
+        let (_dir, alice) = setup()?;
+        let id = query_page_link(alice, "link1")?.unwrap().id;
+        let (status, body) = get(&format!("/api/v1/entries/{}", id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["content"], "<p>body1</p>");
+        assert_eq!(body["feed_url"], "source");
+        assert_eq!(body["read"], false);
+
+        let res = warp::test::request()
+            .method("PATCH")
+            .path(&format!("/api/v1/entries/{}", id))
+            .header("authorization", bearer("alice"))
+            .json(&serde_json::json!({"read": true}))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(query_page(alice, id)?.unwrap().readed);
+
+        let (_, body) = get("/api/v1/feeds").await;
+        assert_eq!(body[0]["url"], "source");
+
+        // bob doesn't subscribe the feed
+        let (status, _) = get_as("bob", &format!("/api/v1/entries/{}", id)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, body) = get_as("bob", "/api/v1/entries").await;
+        assert!(body["entries"].as_array().unwrap().is_empty());
+        let (_, body) = get_as("bob", "/api/v1/feeds").await;
+        assert!(body.as_array().unwrap().is_empty());
+        Ok(())
+    }
+
+    async fn send(method: &str, path: &str) -> StatusCode {
+        let res = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", bearer("alice"))
+            .reply(&routes())
+            .await;
+        res.status()
+    }
+
+    #[tokio::test]
+    async fn test_starred() -> rusqlite::Result<()> {
+        let (_dir, alice) = setup()?;
+        let id = query_page_link(alice, "link1")?.unwrap().id;
+        let star = format!("/api/v1/entries/{}/star", id);
+        assert_eq!(send("PUT", &star).await, StatusCode::OK);
+        let (_, body) = get("/api/v1/entries?starred=true").await;
+        assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(body["entries"][0]["starred"], true);
+        assert_eq!(body["entries"][0]["read"], false);
+
+        // starred entries stay after the feed is unsubscribed, the others are gone
+        remove_feed(alice, "source")?;
+        let (_, body) = get("/api/v1/entries").await;
+        assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(body["entries"][0]["title"], "title1");
+        let (_, body) = get(&format!("/api/v1/entries/{}", id)).await;
+        assert_eq!(body["content"], "<p>body1</p>");
+
+        assert_eq!(send("DELETE", &star).await, StatusCode::OK);
+        cleanup_pages()?;
+        assert_eq!(send("PUT", &star).await, StatusCode::NOT_FOUND);
+        let (_, body) = get("/api/v1/entries").await;
+        assert!(body["entries"].as_array().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_folders() -> rusqlite::Result<()> {
+        let (_dir, alice) = setup()?;
+        add_feed(alice, "other")?;
+        let (_, body) = get("/api/v1/feeds").await;
+        let id = body[0]["id"].as_i64().unwrap();
+        assert_eq!(body[0]["unread"], 2);
+        assert_eq!(body[1]["unread"], 0);
+        let (_, body) = get("/api/v1/entries?folder=").await;
+        assert_eq!(body["entries"].as_array().unwrap().len(), 3);
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/v1/feeds/{}/folder", id))
+            .header("authorization", bearer("alice"))
+            .json(&serde_json::json!({"folder": "Rust/Compiler"}))
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let (_, body) = get("/api/v1/folders").await;
+        assert_eq!(body[0]["name"], "");
+        assert_eq!(body[0]["feeds"], 1);
+        assert_eq!(body[1]["name"], "Rust/Compiler");
+        assert_eq!(body[1]["unread"], 2);
+
+        let (_, body) = get("/api/v1/entries?folder=Rust/Compiler&read=false").await;
+        assert_eq!(body["entries"].as_array().unwrap().len(), 2);
+        let (_, body) = get("/api/v1/entries?folder=").await;
+        assert!(body["entries"].as_array().unwrap().is_empty());
+
+        let (_, body) = get_as("bob", "/api/v1/folders").await;
+        assert!(body.as_array().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tags() -> rusqlite::Result<()> {
+        let (_dir, alice) = setup()?;
+        let id = query_page_link(alice, "link1")?.unwrap().id;
+        let tags = format!("/api/v1/entries/{}/tags", id);
+        assert_eq!(
+            send("PUT", &format!("{}?name=to-review", tags)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send("PUT", &format!("{}?name=rfc%201", tags)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send("PUT", &format!("{}?name=%20", tags)).await,
+            StatusCode::BAD_REQUEST
+        );
+        let (_, body) = get(&format!("/api/v1/entries/{}", id)).await;
+        assert_eq!(body["tags"], serde_json::json!(["rfc 1", "to-review"]));
+        let (_, body) = get("/api/v1/entries?tag=rfc%201").await;
+        assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(body["entries"][0]["tags"][1], "to-review");
+        let (_, body) = get("/api/v1/tags").await;
+        assert_eq!(
+            body,
+            serde_json::json!([{"name": "rfc 1", "pages": 1}, {"name": "to-review", "pages": 1}])
+        );
+
+        assert_eq!(
+            send("DELETE", &format!("{}?name=rfc%201", tags)).await,
+            StatusCode::OK
+        );
+        let (_, body) = get("/api/v1/tags").await;
+        assert_eq!(body[0]["name"], "to-review");
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        let (status, _) = get_as("bob", "/api/v1/tags").await;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    async fn put_rules(name: &str, feed_id: i64, rules: Value) -> StatusCode {
+        let res = warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/v1/feeds/{}/rules", feed_id))
+            .header("authorization", bearer(name))
+            .json(&rules)
+            .reply(&routes())
+            .await;
+        res.status()
+    }
+
+    #[tokio::test]
+    async fn test_feed_rules() -> rusqlite::Result<()> {
+        let (_dir, alice) = setup()?;
+        let (_, body) = get("/api/v1/feeds").await;
+        let id = body[0]["id"].as_i64().unwrap();
+        assert_eq!(body[0]["rules"]["full_page"], false);
+        assert!(body[0]["rules"]["strip"].is_null());
+
+        let rules = serde_json::json!({"content_selector": "div.post", "strip": [".share"]});
+        assert_eq!(put_rules("alice", id, rules).await, StatusCode::OK);
+        let (_, body) = get(&format!("/api/v1/feeds/{}/rules", id)).await;
+        assert_eq!(body["content_selector"], "div.post");
+        assert_eq!(body["strip"][0], ".share");
+        assert_eq!(body["full_page"], false);
+        assert_eq!(
+            query_feed_rules("source").strip,
+            Some(vec![".share".into()])
+        );
+
+        let rules = serde_json::json!({"content_selector": "div["});
+        assert_eq!(put_rules("alice", id, rules).await, StatusCode::BAD_REQUEST);
+        // bob subscribes the feed too, only alice is an admin
+        let bob = auth::find_user("bob").unwrap().unwrap();
+        add_feed(bob.id, "source")?;
+        let rules = serde_json::json!({"full_page": true});
+        assert_eq!(
+            put_rules("bob", id, rules.clone()).await,
+            StatusCode::FORBIDDEN
+        );
+        assert!(!query_feed_rules("source").full_page);
+        assert_eq!(set_feed_rules(bob.id, id, &Default::default())?, 0);
+        assert_eq!(
+            query_feed_rules("source").content_selector.as_deref(),
+            Some("div.post")
+        );
+        // alice can only change feeds she subscribes
+        remove_feed(alice, "source")?;
+        assert_eq!(put_rules("alice", id, rules).await, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_errors() -> rusqlite::Result<()> {
+        let _dir = setup()?;
+        let (status, body) = get("/api/v1/entries/12345").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], 404);
+
+        let (status, body) = get("/api/v1/entries?limit=0").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().contains("limit"));
+
+        let (status, _) = get("/api/v1/entries?read=maybe").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = get("/api/v1/nothing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let res = warp::test::request()
+            .path("/api/v1/entries")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // what went wrong inside stays on the server
+        connect()?.execute_batch("DROP TABLE page_tags")?;
+        let (status, body) = get("/api/v1/entries").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["message"], "internal error");
+
+        // other paths are left to the rest of the routes
+        let res = warp::test::request()
+            .path("/api/rss")
+            .filter(&routes())
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+}
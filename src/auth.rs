@@ -0,0 +1,551 @@
+use crate::conf::*;
+use crate::db::connect;
+use chrono::prelude::*;
+use rand::Rng;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use sha2::Digest;
+use std::error::Error;
+use warp::http::header;
+use warp::{Filter, Rejection, Reply};
+
+/// An account allowed to use the web server
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    /// may change what is shared by all users
+    pub admin: bool,
+}
+
+/// Rejection for requests without a valid session or API token
+#[derive(Debug)]
+pub(crate) struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+fn hash_password(password: &str) -> Result<String, argon2::Error> {
+    let salt = rand::thread_rng().gen::<[u8; 16]>();
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+}
+
+// sessions and API tokens are stored hashed, so a copy of the db doesn't leak them
+fn hash_token(token: &str) -> String {
+    sha2::Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|v| format!("{:02x}", v))
+        .collect()
+}
+
+// key of the Fever API, clients derive it from the user name and the Fever password.
+// md5 is quick to crack, so the Fever password is a random one and never the login password.
+fn fever_key(name: &str, password: &str) -> String {
+    md5::Md5::digest(format!("{}:{}", name, password).as_bytes())
+        .iter()
+        .map(|v| format!("{:02x}", v))
+        .collect()
+}
+
+fn new_token() -> String {
+    uuid::Uuid::new_v4().to_simple().to_string()
+}
+
+fn user_id(name: &str) -> Result<i64, Box<dyn Error>> {
+    let conn = connect()?;
+    conn.query_row("SELECT id FROM users WHERE name = ?", [name], |row| {
+        row.get(0)
+    })
+    .optional()?
+    .ok_or_else(|| format!("no user named {}", name).into())
+}
+
+/// The first user, owning what was stored before there were users.
+/// Without any user an `admin` without password is created, it can't log in until one is set.
+pub(crate) fn default_user(conn: &Connection) -> rusqlite::Result<i64> {
+    let id = conn
+        .query_row("SELECT id FROM users ORDER BY id LIMIT 1", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    conn.execute(
+        "INSERT INTO users (name, password_hash, created_at) values ('admin', '', ?)",
+        [Utc::now().timestamp()],
+    )?;
+    eprintln!("created user admin, set its password with `rss-rs user passwd admin`");
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn first_user() -> rusqlite::Result<User> {
+    let id = default_user(&connect()?)?;
+    Ok(query_users()?.into_iter().find(|u| u.id == id).unwrap())
+}
+
+pub fn find_user(name: &str) -> rusqlite::Result<Option<User>> {
+    Ok(query_users()?.into_iter().find(|u| u.name == name))
+}
+
+pub fn add_user(name: &str, password: &str) -> Result<i64, Box<dyn Error>> {
+    if name.is_empty() || password.is_empty() {
+        return Err("name and password can't be empty".into());
+    }
+    let conn = connect()?;
+    conn.execute(
+        "INSERT INTO users (name, password_hash, created_at) values (?1, ?2, ?3)",
+        params![name, hash_password(password)?, Utc::now().timestamp()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Change the password of a user and end all sessions of it
+pub fn set_password(name: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    if password.is_empty() {
+        return Err("password can't be empty".into());
+    }
+    let id = user_id(name)?;
+    let conn = connect()?;
+    conn.execute(
+        "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+        params![hash_password(password)?, id],
+    )?;
+    conn.execute("DELETE FROM sessions WHERE user_id = ?", [id])?;
+    Ok(())
+}
+
+// `None` turns the Fever API off for the user
+pub(crate) fn set_fever_password(name: &str, password: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let id = user_id(name)?;
+    let key = password.map(|password| fever_key(name, password));
+    connect()?.execute(
+        "UPDATE users SET fever_key = ?1 WHERE id = ?2",
+        params![key, id],
+    )?;
+    Ok(())
+}
+
+/// Let a user sync with Fever API clients, returning a new random password to enter in the
+/// client along with the user name. The previous Fever password stops working.
+pub fn enable_fever(name: &str) -> Result<String, Box<dyn Error>> {
+    let password = new_token();
+    set_fever_password(name, Some(&password))?;
+    Ok(password)
+}
+
+/// Turn the Fever API off for a user
+pub fn disable_fever(name: &str) -> Result<(), Box<dyn Error>> {
+    set_fever_password(name, None)
+}
+
+/// Let a user change what is shared by all users, or take it back. There is always an admin left.
+pub fn set_admin(name: &str, admin: bool) -> Result<(), Box<dyn Error>> {
+    let id = user_id(name)?;
+    let conn = connect()?;
+    let others: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM users WHERE admin AND id != ?",
+        [id],
+        |row| row.get(0),
+    )?;
+    if !admin && others == 0 {
+        return Err(format!("{} is the last admin", name).into());
+    }
+    conn.execute(
+        "UPDATE users SET admin = ?1 WHERE id = ?2",
+        params![admin, id],
+    )?;
+    Ok(())
+}
+
+/// Remove a user with its sessions and API tokens
+pub fn remove_user(name: &str) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute("DELETE FROM users WHERE name = ?", [name])
+}
+
+pub fn query_users() -> rusqlite::Result<Vec<User>> {
+    let conn = connect()?;
+    let mut statement = conn.prepare("SELECT id, name, admin FROM users ORDER BY id")?;
+    let users = statement
+        .query_map([], |row| {
+            Ok(User {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                admin: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(users)
+}
+
+/// Create an API token for scripts, the token is only shown here
+pub fn create_api_token(name: &str, label: &str) -> Result<String, Box<dyn Error>> {
+    let id = user_id(name)?;
+    let token = new_token();
+    let conn = connect()?;
+    conn.execute(
+        "INSERT INTO api_tokens (token_hash, user_id, name, created_at) values (?1, ?2, ?3, ?4)",
+        params![hash_token(&token), id, label, Utc::now().timestamp()],
+    )?;
+    Ok(token)
+}
+
+/// Check a password and start a session, return the session token
+pub fn login(name: &str, password: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let conn = connect()?;
+    let user = conn
+        .query_row(
+            "SELECT id, password_hash FROM users WHERE name = ?",
+            [name],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()?;
+    let Some((id, hash)) = user else {
+        return Ok(None);
+    };
+    // users created without a password have an empty hash
+    if !argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false) {
+        return Ok(None);
+    }
+    let token = new_token();
+    let now = Utc::now().timestamp();
+    conn.execute("DELETE FROM sessions WHERE expires_at <= ?", [now])?;
+    conn.execute(
+        "INSERT INTO sessions (token_hash, user_id, expires_at) values (?1, ?2, ?3)",
+        params![hash_token(&token), id, now + SESSION_DAYS * 24 * 3600],
+    )?;
+    Ok(Some(token))
+}
+
+pub fn logout(token: &str) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute(
+        "DELETE FROM sessions WHERE token_hash = ?",
+        [hash_token(token)],
+    )
+}
+
+/// The user owning a session or API token
+pub fn authenticate(token: &str) -> rusqlite::Result<Option<User>> {
+    let conn = connect()?;
+    conn.query_row(
+        "SELECT u.id, u.name, u.admin FROM users u
+         WHERE u.id IN (SELECT user_id FROM sessions WHERE token_hash = ?1 AND expires_at > ?2)
+            OR u.id IN (SELECT user_id FROM api_tokens WHERE token_hash = ?1)",
+        params![hash_token(token), Utc::now().timestamp()],
+        |row| {
+            Ok(User {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                admin: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// The user of a Fever API key
+pub(crate) fn fever_user(api_key: &str) -> rusqlite::Result<Option<User>> {
+    let conn = connect()?;
+    conn.query_row(
+        "SELECT id, name, admin FROM users WHERE fever_key = ?",
+        [api_key.to_lowercase()],
+        |row| {
+            Ok(User {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                admin: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+fn request_token(cookie: Option<String>, authorization: Option<String>) -> Option<String> {
+    authorization
+        .as_deref()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string())
+        .or(cookie)
+}
+
+async fn token_user(token: Option<String>) -> Result<User, Rejection> {
+    let Some(token) = token else {
+        return Err(warp::reject::custom(Unauthorized));
+    };
+    match authenticate(&token) {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(warp::reject::custom(Unauthorized)),
+        Err(e) => {
+            eprintln!("authenticate failed: {}", e);
+            Err(warp::reject::custom(Unauthorized))
+        }
+    }
+}
+
+/// The user of a request, from the session cookie or an `Authorization: Bearer` API token
+pub(crate) fn user() -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::cookie::optional::<String>(SESSION_COOKIE)
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(|cookie: Option<String>, authorization: Option<String>| {
+            token_user(request_token(cookie, authorization))
+        })
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Like `user`, the API token may also be in the `token` query parameter,
+/// for feed readers that can't send headers
+pub(crate) fn user_or_query_token() -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::query::<TokenQuery>()
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            |query: TokenQuery, cookie: Option<String>, authorization: Option<String>| {
+                token_user(query.token.or_else(|| request_token(cookie, authorization)))
+            },
+        )
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    name: String,
+    password: String,
+}
+
+fn session_cookie(token: &str, max_age: i64) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION_COOKIE, token, max_age
+    )
+}
+
+fn login_reply(form: &LoginForm) -> Box<dyn Reply> {
+    match login(&form.name, &form.password) {
+        Ok(Some(token)) => Box::new(warp::reply::with_header(
+            warp::redirect::see_other(warp::http::Uri::from_static("/read")),
+            header::SET_COOKIE,
+            session_cookie(&token, SESSION_DAYS * 24 * 3600),
+        )),
+        Ok(None) => Box::new(warp::redirect::see_other(warp::http::Uri::from_static(
+            "/login?failed",
+        ))),
+        Err(e) => crate::api::internal_error(e),
+    }
+}
+
+fn logout_reply(cookie: Option<String>) -> Box<dyn Reply> {
+    if let Some(token) = cookie {
+        if let Err(e) = logout(&token) {
+            eprintln!("logout failed: {}", e);
+        }
+    }
+    Box::new(warp::reply::with_header(
+        warp::redirect::see_other(warp::http::Uri::from_static("/login")),
+        header::SET_COOKIE,
+        session_cookie("", 0),
+    ))
+}
+
+/// The login page and the routes to start and end a session, these need no user
+pub(crate) fn routes() -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    let page = warp::path!("login")
+        .and(warp::get())
+        .and(warp::fs::file("./front/public/login.html"))
+        .map(|file: warp::fs::File| Box::new(file) as Box<dyn Reply>);
+    let login = warp::path!("api" / "login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::form())
+        .map(|form: LoginForm| login_reply(&form));
+    let logout = warp::path!("api" / "logout")
+        .and(warp::post())
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .map(logout_reply);
+    page.or(login).unify().or(logout).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use warp::http::StatusCode;
+
+    #[test]
+    fn test_login() -> Result<(), Box<dyn Error>> {
+        let _dir = TestDir::new()?;
+        let id = add_user("alice", "secret")?;
+        assert!(add_user("alice", "other").is_err());
+        assert!(login("alice", "wrong")?.is_none());
+        assert!(login("bob", "secret")?.is_none());
+
+        let token = login("alice", "secret")?.unwrap();
+        let user = authenticate(&token)?.unwrap();
+        assert_eq!(user.id, id);
+        assert_eq!(user.name, "alice");
+        assert!(authenticate("nothing")?.is_none());
+
+        logout(&token)?;
+        assert!(authenticate(&token)?.is_none());
+
+        // a new password ends existing sessions
+        let token = login("alice", "secret")?.unwrap();
+        set_password("alice", "changed")?;
+        assert!(authenticate(&token)?.is_none());
+        assert!(login("alice", "secret")?.is_none());
+        assert!(login("alice", "changed")?.is_some());
+        assert!(set_password("bob", "x").is_err());
+
+        // passwords and tokens are not stored in clear
+        let conn = connect()?;
+        let hash: String =
+            conn.query_row("SELECT password_hash FROM users", [], |row| row.get(0))?;
+        assert!(hash.starts_with("$argon2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fever_password() -> Result<(), Box<dyn Error>> {
+        let _dir = TestDir::new()?;
+        let id = add_user("alice", "secret")?;
+        // off until turned on, and never the login password
+        assert!(fever_user(&fever_key("alice", "secret"))?.is_none());
+        let password = enable_fever("alice")?;
+        assert_ne!(password, "secret");
+        assert_eq!(fever_user(&fever_key("alice", &password))?.unwrap().id, id);
+        set_password("alice", "changed")?;
+        assert!(fever_user(&fever_key("alice", "changed"))?.is_none());
+        assert!(fever_user(&fever_key("alice", &password))?.is_some());
+
+        let again = enable_fever("alice")?;
+        assert!(fever_user(&fever_key("alice", &password))?.is_none());
+        disable_fever("alice")?;
+        assert!(fever_user(&fever_key("alice", &again))?.is_none());
+        assert!(enable_fever("bob").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_admins() -> Result<(), Box<dyn Error>> {
+        let _dir = TestDir::new()?;
+        // the first user of an empty db is an admin
+        add_user("alice", "secret")?;
+        add_user("bob", "secret")?;
+        let admins = |users: Vec<User>| -> Vec<String> {
+            users
+                .into_iter()
+                .filter(|u| u.admin)
+                .map(|u| u.name)
+                .collect()
+        };
+        assert_eq!(admins(query_users()?), vec!["alice"]);
+        assert!(set_admin("alice", false).is_err());
+        set_admin("bob", true)?;
+        set_admin("alice", false)?;
+        assert_eq!(admins(query_users()?), vec!["bob"]);
+        let token = login("bob", "secret")?.unwrap();
+        assert!(authenticate(&token)?.unwrap().admin);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_session() -> Result<(), Box<dyn Error>> {
+        let _dir = TestDir::new()?;
+        add_user("alice", "secret")?;
+        let token = login("alice", "secret")?.unwrap();
+        connect()?.execute("UPDATE sessions SET expires_at = 0", [])?;
+        assert!(authenticate(&token)?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filter() -> Result<(), Box<dyn Error>> {
+        let _dir = TestDir::new()?;
+        add_user("alice", "secret")?;
+        let token = create_api_token("alice", "script")?;
+        let session = login("alice", "secret")?.unwrap();
+        let filter = user();
+
+        let res = warp::test::request().filter(&filter).await;
+        assert!(res.unwrap_err().find::<Unauthorized>().is_some());
+        let res = warp::test::request()
+            .header("authorization", "Bearer wrong")
+            .filter(&filter)
+            .await;
+        assert!(res.is_err());
+        let res = warp::test::request()
+            .header("authorization", format!("Bearer {}", token))
+            .filter(&filter)
+            .await;
+        assert_eq!(res.unwrap().name, "alice");
+        let res = warp::test::request()
+            .header("cookie", format!("{}={}", SESSION_COOKIE, session))
+            .filter(&filter)
+            .await;
+        assert_eq!(res.unwrap().name, "alice");
+
+        let res = warp::test::request()
+            .path(&format!("/feeds/all.atom?token={}", token))
+            .filter(&user_or_query_token())
+            .await;
+        assert_eq!(res.unwrap().name, "alice");
+        let res = warp::test::request()
+            .path("/feeds/all.atom?token=wrong")
+            .filter(&user_or_query_token())
+            .await;
+        assert!(res.is_err());
+
+        remove_user("alice")?;
+        assert!(authenticate(&token)?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_login_routes() -> Result<(), Box<dyn Error>> {
+        let dir = TestDir::new()?;
+        add_user("alice", "secret")?;
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/login")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("name=alice&password=secret")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let cookie = res.headers()[header::SET_COOKIE].to_str()?;
+        assert!(cookie.contains("HttpOnly"));
+        let token = cookie
+            .trim_start_matches(&format!("{}=", SESSION_COOKIE))
+            .split(';')
+            .next()
+            .unwrap();
+        assert!(authenticate(token)?.is_some());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/login")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("name=alice&password=wrong")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.headers()[header::LOCATION], "/login?failed");
+        assert!(res.headers().get(header::SET_COOKIE).is_none());
+
+        // db errors stay in the server log
+        drop(dir);
+        let _dir = TestDir::empty();
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/login")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body("name=alice&password=secret")
+            .reply(&routes())
+            .await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!String::from_utf8_lossy(res.body()).contains("users"));
+        Ok(())
+    }
+}
@@ -1,8 +1,70 @@
+// every test gets a directory of its own, see `testing::TestDir`
 #[cfg(test)]
-pub(crate) static PAGES_DB: &'static str = "/tmp/pages.db";
+pub(crate) fn pages_db() -> String {
+    crate::testing::path("pages.db")
+}
 
 #[cfg(not(test))]
-pub(crate) static PAGES_DB: &'static str = "./db/pages.db";
+pub(crate) fn pages_db() -> String {
+    "./db/pages.db".to_string()
+}
 
-pub(crate) static IMAGE_DIR: &'static str = "./pages/images";
-pub(crate) static ALL_FEEDS: &'static str = "./db/feeds.md";
+#[cfg(test)]
+pub(crate) fn pages_dir() -> String {
+    crate::testing::path("pages")
+}
+
+#[cfg(not(test))]
+pub(crate) fn pages_dir() -> String {
+    "./pages".to_string()
+}
+
+#[cfg(test)]
+pub(crate) fn image_dir() -> String {
+    crate::testing::path("images")
+}
+
+#[cfg(not(test))]
+pub(crate) fn image_dir() -> String {
+    "./pages/images".to_string()
+}
+
+// downloaded images are linked from the bodies under this path
+pub(crate) static IMAGE_URL_PREFIX: &str = "/pages/images/";
+
+#[cfg(test)]
+pub(crate) fn feeds_file() -> String {
+    crate::testing::path("feeds.md")
+}
+
+#[cfg(not(test))]
+pub(crate) fn feeds_file() -> String {
+    "./db/feeds.md".to_string()
+}
+
+pub(crate) static FETCH_JOBS: usize = 8;
+pub(crate) static FETCH_HOST_JOBS: usize = 2;
+pub(crate) static FETCH_TIMEOUT_SECS: u64 = 120;
+// one page of an entry, so that a stalled page doesn't use up the time of its whole feed
+pub(crate) static FETCH_PAGE_TIMEOUT_SECS: u64 = 30;
+pub(crate) static FETCH_INTERVAL_MINUTES: u64 = 20;
+pub(crate) static DISCOVER_TIMEOUT_SECS: u64 = 10;
+pub(crate) static DISCOVER_MAX_REDIRECTS: usize = 5;
+pub(crate) static DISCOVER_MAX_BYTES: usize = 5 * 1024 * 1024;
+// the scheduler looks for due feeds at least this often, in seconds
+pub(crate) static SCHEDULER_TICK_SECS: i64 = 60;
+// pages and feeds nobody subscribes anymore are cleaned up this often, in seconds
+pub(crate) static CLEANUP_INTERVAL_SECS: i64 = 3600;
+// failures in a row after which a feed is flagged as broken
+pub(crate) static BROKEN_FAILURES: i64 = 5;
+// fetches kept in the log of every feed
+pub(crate) static FETCH_LOG_SIZE: usize = 50;
+// the purge job runs this often when a retention is set
+pub(crate) static PURGE_INTERVAL_HOURS: u64 = 24;
+// links of purged pages are remembered this long, feeds rarely list older items
+pub(crate) static PURGED_LINKS_DAYS: i64 = 180;
+// images younger than this may belong to a page being fetched, the purge leaves them
+pub(crate) static RECENT_IMAGE_SECS: u64 = 3600;
+
+pub(crate) static SESSION_COOKIE: &str = "rss_session";
+pub(crate) static SESSION_DAYS: i64 = 30;
@@ -1,156 +1,1568 @@
+use crate::auth::default_user;
 use crate::conf::*;
-use rusqlite::{params, Connection};
+use crate::migrations::migrate;
+use chrono::prelude::*;
+use rusqlite::types::ToSql;
+use rusqlite::{params, params_from_iter, Connection, TransactionBehavior};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
-/// An item within a feed
+/// An item within a feed, `readed` and `starred` are the states for the user it was queried for
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Page {
+    /// row id, 0 for a page not stored yet
+    pub id: i64,
     pub title: String,
-    pub publish_datetime: String,
+    /// publish time in seconds since the unix epoch
+    pub published_at: i64,
+    /// time the page was stored, in seconds since the unix epoch
+    pub fetched_at: i64,
     pub link: String,
     pub source: String,
     pub website: String,
     pub readed: bool,
+    pub starred: bool,
 }
 
-pub(crate) fn init_db(db_name: Option<&str>) -> rusqlite::Result<()> {
-    let name = db_name.unwrap_or(PAGES_DB);
-    if !Path::new(name).exists() {
-        let conn = Connection::open(name)?;
-        conn.execute_batch(
-            r#"
-            BEGIN;
-            CREATE TABLE pages (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                title String NOT NULL,
-                link String NOT NULL,
-                website String,
-                publish_datetime String,
-                readed Boolean,
-                source String NOT NULL);
-            CREATE UNIQUE INDEX idx_pages_link ON pages (link);
-            COMMIT;
-            "#,
-        )?;
+impl Page {
+    pub fn published(&self) -> DateTime<Utc> {
+        Utc.timestamp_opt(self.published_at, 0)
+            .single()
+            .unwrap_or_default()
+    }
+}
+
+/// Ordering of pages by publish time, or by id for clients syncing incrementally
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    #[default]
+    NewestFirst,
+    OldestFirst,
+    IdAscending,
+    IdDescending,
+}
+
+/// Filters for `query_pages`, every value is passed to SQLite as a bound parameter
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PageFilter {
+    pub id: Option<i64>,
+    pub ids: Option<Vec<i64>>,
+    /// pages with an id greater than this one
+    pub since_id: Option<i64>,
+    /// pages with an id lower than this one
+    pub max_id: Option<i64>,
+    pub readed: Option<bool>,
+    pub starred: Option<bool>,
+    pub link: Option<String>,
+    /// url of the feed the page comes from
+    pub source: Option<String>,
+    pub feed_id: Option<i64>,
+    /// folder of the subscription
+    pub folder: Option<String>,
+    /// name of a tag the user put on the page
+    pub tag: Option<String>,
+    /// pages published at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// pages published before this time
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub order: Order,
+}
+
+impl PageFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn id(mut self, id: i64) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn ids(mut self, ids: &[i64]) -> Self {
+        self.ids = Some(ids.to_vec());
+        self
+    }
+
+    pub fn since_id(mut self, id: i64) -> Self {
+        self.since_id = Some(id);
+        self
+    }
+
+    pub fn max_id(mut self, id: i64) -> Self {
+        self.max_id = Some(id);
+        self
+    }
+
+    pub fn readed(mut self, readed: bool) -> Self {
+        self.readed = Some(readed);
+        self
+    }
+
+    pub fn starred(mut self, starred: bool) -> Self {
+        self.starred = Some(starred);
+        self
+    }
+
+    pub fn link(mut self, link: &str) -> Self {
+        self.link = Some(link.to_string());
+        self
+    }
+
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    pub fn feed_id(mut self, feed_id: i64) -> Self {
+        self.feed_id = Some(feed_id);
+        self
+    }
+
+    pub fn folder(mut self, folder: &str) -> Self {
+        self.folder = Some(folder.to_string());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Some(tag.to_string());
+        self
+    }
+
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    pub fn until(mut self, until: DateTime<Utc>) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    // the WHERE/ORDER/LIMIT part of the query, with the parameters it binds
+    fn to_sql(&self) -> (String, Vec<Box<dyn ToSql>>) {
+        let mut conds: Vec<String> = vec![];
+        let mut params: Vec<Box<dyn ToSql>> = vec![];
+        if let Some(id) = self.id {
+            conds.push("p.id = ?".into());
+            params.push(Box::new(id));
+        }
+        if let Some(ids) = &self.ids {
+            let marks = vec!["?"; ids.len()].join(", ");
+            conds.push(format!("p.id IN ({})", marks));
+            params.extend(ids.iter().map(|&id| Box::new(id) as Box<dyn ToSql>));
+        }
+        if let Some(id) = self.since_id {
+            conds.push("p.id > ?".into());
+            params.push(Box::new(id));
+        }
+        if let Some(id) = self.max_id {
+            conds.push("p.id < ?".into());
+            params.push(Box::new(id));
+        }
+        if let Some(readed) = self.readed {
+            conds.push("COALESCE(ps.readed, 0) = ?".into());
+            params.push(Box::new(readed));
+        }
+        if let Some(starred) = self.starred {
+            conds.push("COALESCE(ps.starred, 0) = ?".into());
+            params.push(Box::new(starred));
+        }
+        if let Some(link) = &self.link {
+            conds.push("p.link = ?".into());
+            params.push(Box::new(link.clone()));
+        }
+        if let Some(source) = &self.source {
+            conds.push("f.url = ?".into());
+            params.push(Box::new(source.clone()));
+        }
+        if let Some(feed_id) = self.feed_id {
+            conds.push("p.feed_id = ?".into());
+            params.push(Box::new(feed_id));
+        }
+        if let Some(folder) = &self.folder {
+            // subscriptions outside any folder have none
+            conds.push("s.user_id IS NOT NULL AND COALESCE(s.folder, '') = ?".into());
+            params.push(Box::new(folder.clone()));
+        }
+        if let Some(tag) = &self.tag {
+            conds.push(
+                "p.id IN (SELECT pt.page_id FROM page_tags pt JOIN tags t ON t.id = pt.tag_id
+                 WHERE t.user_id = u.id AND t.name = ?)"
+                    .into(),
+            );
+            params.push(Box::new(tag.clone()));
+        }
+        if let Some(since) = self.since {
+            conds.push("p.published_at >= ?".into());
+            params.push(Box::new(since.timestamp()));
+        }
+        if let Some(until) = self.until {
+            conds.push("p.published_at < ?".into());
+            params.push(Box::new(until.timestamp()));
+        }
+        let mut sql = if conds.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conds.join(" AND "))
+        };
+        sql.push_str(match self.order {
+            Order::NewestFirst => " ORDER BY p.published_at DESC, p.id DESC",
+            Order::OldestFirst => " ORDER BY p.published_at ASC, p.id ASC",
+            Order::IdAscending => " ORDER BY p.id ASC",
+            Order::IdDescending => " ORDER BY p.id DESC",
+        });
+        if self.limit.is_some() || self.offset > 0 {
+            // a negative limit means no limit in SQLite
+            sql.push_str(" LIMIT ? OFFSET ?");
+            params.push(Box::new(self.limit.map_or(-1, |l| l as i64)));
+            params.push(Box::new(self.offset as i64));
+        }
+        (sql, params)
+    }
+}
+
+/// A feed subscribed by a user, `added_at`, `enabled` and `folder` belong to the subscription
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Feed {
+    pub id: i64,
+    pub url: String,
+    pub title: String,
+    pub site_link: String,
+    /// time the user subscribed the feed, in seconds since the unix epoch
+    pub added_at: i64,
+    pub last_status: String,
+    pub enabled: bool,
+    /// folder path, nested folders are separated by `/`
+    pub folder: String,
+    /// pages of the feed the user hasn't read
+    pub unread: i64,
+    /// shared by every user subscribing the feed
+    pub rules: ExtractRules,
+    pub fetch: FetchState,
+}
+
+/// When a feed is fetched and how the last fetches went, shared by every subscriber
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FetchState {
+    /// minutes between two fetches set by a user, `None` to follow the feed
+    pub interval: Option<i64>,
+    /// minutes the feed asks to be cached for, from `<ttl>` or `sy:updatePeriod`
+    pub ttl: Option<i64>,
+    /// times are in seconds since the unix epoch
+    pub next_fetch_at: i64,
+    /// failed fetches in a row, reset by a success
+    pub failures: i64,
+    /// set after too many failures in a row, the feed may be dead or moved
+    pub broken: bool,
+    pub last_success_at: Option<i64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<i64>,
+}
+
+/// How the content of the entries of a feed is taken from their pages
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtractRules {
+    /// css selector of the content in a fetched page, guessed from the paragraphs when not set
+    pub content_selector: Option<String>,
+    /// css selectors of elements removed from the content, `None` for the built-in list
+    pub strip: Option<Vec<String>>,
+    /// always fetch the linked page instead of trusting the content in the feed
+    pub full_page: bool,
+}
+
+/// One fetch of a feed
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FetchLog {
+    /// seconds since the unix epoch
+    pub fetched_at: i64,
+    /// `None` when no response came back
+    pub http_status: Option<u16>,
+    pub duration_ms: i64,
+    /// entries in the feed, and the ones stored for the first time
+    pub items_found: i64,
+    pub items_new: i64,
+    pub error: Option<String>,
+}
+
+/// Validators from the last successful response of a feed, used for conditional GET
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FeedCache {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+pub(crate) fn connect() -> rusqlite::Result<Connection> {
+    let conn = Connection::open(pages_db())?;
+    // pages are removed together with their feed
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    Ok(conn)
+}
+
+/// urls listed in the feeds file, `None` when there is no such file
+pub(crate) fn feeds_file_urls() -> Option<Vec<String>> {
+    let rss_buf = fs::read_to_string(feeds_file()).ok()?;
+    let urls = rss_buf
+        .split('\n')
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect();
+    Some(urls)
+}
+
+// subscriptions used to live in a text file, import it once for the first user and keep a backup
+fn import_feeds_file(conn: &Connection) -> rusqlite::Result<()> {
+    let feeds_file = feeds_file();
+    let Some(urls) = feeds_file_urls() else {
+        return Ok(());
+    };
+    let owner = default_user(conn)?;
+    for feed in urls {
+        subscribe(conn, owner, &feed)?;
+    }
+    let backup = format!("{}.imported", feeds_file);
+    if let Err(e) = fs::rename(&feeds_file, &backup) {
+        eprintln!("failed to move {} to {}: {}", feeds_file, backup, e);
+    }
+    eprintln!("feeds imported from: {:?}", feeds_file);
+    Ok(())
+}
+
+pub fn init_db(db_name: Option<&str>) -> rusqlite::Result<()> {
+    let name = db_name.map_or_else(pages_db, |name| name.to_string());
+    if !Path::new(&name).exists() {
         eprintln!("db created: {:?}", name);
     }
+    let mut conn = Connection::open(&name)?;
+    migrate(&mut conn)?;
+    import_feeds_file(&conn)?;
     Ok(())
 }
 
+fn row_to_feed(row: &rusqlite::Row) -> rusqlite::Result<Feed> {
+    Ok(Feed {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        title: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+        site_link: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        added_at: row.get(4)?,
+        last_status: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+        enabled: row.get(6)?,
+        folder: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+        rules: row_to_rules(row, 8)?,
+        fetch: row_to_fetch_state(row, 11)?,
+        unread: row.get(18)?,
+    })
+}
+
+fn row_to_fetch_state(row: &rusqlite::Row, first: usize) -> rusqlite::Result<FetchState> {
+    let failures = row.get(first + 3)?;
+    Ok(FetchState {
+        interval: row.get(first)?,
+        ttl: row.get(first + 1)?,
+        next_fetch_at: row.get(first + 2)?,
+        failures,
+        broken: failures >= BROKEN_FAILURES,
+        last_success_at: row.get(first + 4)?,
+        last_error: row.get(first + 5)?,
+        last_error_at: row.get(first + 6)?,
+    })
+}
+
+// the strip list is stored as a JSON array
+fn row_to_rules(row: &rusqlite::Row, first: usize) -> rusqlite::Result<ExtractRules> {
+    let strip = row
+        .get::<_, Option<String>>(first + 1)?
+        .and_then(|s| serde_json::from_str(&s).ok());
+    Ok(ExtractRules {
+        content_selector: row.get(first)?,
+        strip,
+        full_page: row.get(first + 2)?,
+    })
+}
+
+/// feeds subscribed by the user
+pub fn query_feeds(user_id: i64) -> rusqlite::Result<Vec<Feed>> {
+    let conn = connect()?;
+    let mut statement = conn.prepare(
+        "SELECT f.id, f.url, f.title, f.site_link, s.added_at, f.last_status, s.enabled, s.folder,
+                f.content_selector, f.strip_selectors, f.full_page,
+                f.fetch_interval, f.ttl, f.next_fetch_at, f.failures,
+                f.last_success_at, f.last_error, f.last_error_at,
+                (SELECT count(*) FROM pages p
+                 LEFT JOIN page_states ps ON ps.page_id = p.id AND ps.user_id = s.user_id
+                 WHERE p.feed_id = f.id AND NOT COALESCE(ps.readed, 0))
+         FROM subscriptions s JOIN feeds f ON f.id = s.feed_id
+         WHERE s.user_id = ? ORDER BY f.id",
+    )?;
+    let feeds = statement
+        .query_map([user_id], row_to_feed)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(feeds)
+}
+
+/// A folder of the user with the number of feeds filed in it and of their unread pages,
+/// feeds in subfolders are counted in the subfolder only
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Folder {
+    /// folder path, empty for the feeds outside any folder
+    pub name: String,
+    pub feeds: usize,
+    pub unread: i64,
+}
+
+/// folders of the user sorted by name
+pub fn query_folders(user_id: i64) -> rusqlite::Result<Vec<Folder>> {
+    let mut folders: Vec<Folder> = vec![];
+    let mut feeds = query_feeds(user_id)?;
+    feeds.sort_by(|a, b| a.folder.cmp(&b.folder));
+    for feed in feeds {
+        match folders.last_mut() {
+            Some(folder) if folder.name == feed.folder => {
+                folder.feeds += 1;
+                folder.unread += feed.unread;
+            }
+            _ => folders.push(Folder {
+                name: feed.folder,
+                feeds: 1,
+                unread: feed.unread,
+            }),
+        }
+    }
+    Ok(folders)
+}
+
+/// clean up a folder path typed by a user, `/Rust//Compiler ` is `Rust/Compiler`
+pub fn folder_path(folder: &str) -> String {
+    folder
+        .split('/')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// file a feed of the user in a folder, an empty one takes it out of any folder
+pub fn set_feed_folder(user_id: i64, feed_id: i64, folder: &str) -> rusqlite::Result<usize> {
+    connect()?.execute(
+        "UPDATE subscriptions SET folder = ?1 WHERE user_id = ?2 AND feed_id = ?3",
+        params![folder_path(folder), user_id, feed_id],
+    )
+}
+
+/// urls of all feeds enabled by at least one user, every feed is fetched once for all of them
 pub(crate) fn all_feeds() -> Vec<String> {
-    let rss_buf = fs::read_to_string(ALL_FEEDS).unwrap();
-    let feeds = rss_buf
-        .split("\n")
-        .map(|l| l.trim())
-        .filter(|&l| l.len() > 0)
-        .map(|l| l.to_string())
-        .collect::<Vec<_>>();
-    feeds
+    let query = || -> rusqlite::Result<Vec<String>> {
+        let conn = connect()?;
+        let mut statement = conn.prepare(
+            "SELECT url FROM feeds
+             WHERE id IN (SELECT feed_id FROM subscriptions WHERE enabled) ORDER BY id",
+        )?;
+        let urls = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(urls)
+    };
+    query().unwrap_or_default()
+}
+
+// in one transaction, `cleanup_pages` deletes a feed seen without subscription
+fn subscribe(conn: &Connection, user_id: i64, url: &str) -> rusqlite::Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let added = insert_subscription(&tx, user_id, url)?;
+    tx.commit()?;
+    Ok(added)
 }
 
+fn insert_subscription(conn: &Connection, user_id: i64, url: &str) -> rusqlite::Result<usize> {
+    conn.execute("INSERT OR IGNORE INTO feeds (url) values (?)", [url])?;
+    conn.execute(
+        "INSERT OR IGNORE INTO subscriptions (user_id, feed_id, added_at)
+         SELECT ?1, id, ?2 FROM feeds WHERE url = ?3",
+        params![user_id, Utc::now().timestamp(), url],
+    )
+}
+
+pub fn add_feed(user_id: i64, url: &str) -> rusqlite::Result<usize> {
+    subscribe(&connect()?, user_id, url)
+}
+
+/// subscribe a feed with a known title and folder, an existing subscription is moved into the folder
+pub fn import_feed(
+    user_id: i64,
+    url: &str,
+    title: &str,
+    site_link: &str,
+    folder: &str,
+) -> rusqlite::Result<()> {
+    let conn = connect()?;
+    let tx = conn.unchecked_transaction()?;
+    import_feed_in(&tx, user_id, url, title, site_link, folder)?;
+    tx.commit()
+}
+
+/// `import_feed` within the transaction of the caller
+pub(crate) fn import_feed_in(
+    conn: &Connection,
+    user_id: i64,
+    url: &str,
+    title: &str,
+    site_link: &str,
+    folder: &str,
+) -> rusqlite::Result<()> {
+    insert_subscription(conn, user_id, url)?;
+    // the feed is shared, only fill in what is missing
+    conn.execute(
+        "UPDATE feeds SET title = COALESCE(NULLIF(title, ''), ?1),
+                          site_link = COALESCE(NULLIF(site_link, ''), ?2)
+         WHERE url = ?3",
+        params![title, site_link, url],
+    )?;
+    conn.execute(
+        "UPDATE subscriptions SET folder = ?1
+         WHERE user_id = ?2 AND feed_id = (SELECT id FROM feeds WHERE url = ?3)",
+        params![folder, user_id, url],
+    )?;
+    Ok(())
+}
+
+/// unsubscribe a feed, the feed and its pages are deleted when nobody else subscribes it,
+/// except the pages someone starred
+pub fn remove_feed(user_id: i64, url: &str) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute(
+        "DELETE FROM page_states WHERE user_id = ?1 AND NOT starred
+         AND page_id IN (SELECT p.id FROM pages p JOIN feeds f ON f.id = p.feed_id WHERE f.url = ?2)",
+        params![user_id, url],
+    )?;
+    // tags go with the pages the user no longer sees
+    conn.execute(
+        "DELETE FROM page_tags WHERE tag_id IN (SELECT id FROM tags WHERE user_id = ?1)
+         AND page_id IN (SELECT p.id FROM pages p JOIN feeds f ON f.id = p.feed_id WHERE f.url = ?2)
+         AND page_id NOT IN (SELECT page_id FROM page_states WHERE user_id = ?1 AND starred)",
+        params![user_id, url],
+    )?;
+    let removed = conn.execute(
+        "DELETE FROM subscriptions
+         WHERE user_id = ?1 AND feed_id = (SELECT id FROM feeds WHERE url = ?2)",
+        params![user_id, url],
+    )?;
+    cleanup_pages()?;
+    Ok(removed)
+}
+
+pub fn set_feed_enabled(user_id: i64, url: &str, enabled: bool) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute(
+        "UPDATE subscriptions SET enabled = ?1
+         WHERE user_id = ?2 AND feed_id = (SELECT id FROM feeds WHERE url = ?3)",
+        params![enabled, user_id, url],
+    )
+}
+
+pub(crate) fn update_feed_info(url: &str, title: &str, site_link: &str) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    // keep the imported title when the feed itself has none
+    conn.execute(
+        "UPDATE feeds SET title = CASE WHEN ?1 = '' THEN title ELSE ?1 END, site_link = ?2
+         WHERE url = ?3",
+        params![title, site_link, url],
+    )
+}
+
+pub(crate) fn update_feed_ttl(url: &str, ttl: Option<i64>) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute(
+        "UPDATE feeds SET ttl = ?1 WHERE url = ?2",
+        params![ttl, url],
+    )
+}
+
+/// remember the outcome of a fetch in the log of the feed, only the newest entries are kept
+pub(crate) fn record_fetch(url: &str, log: &FetchLog, next_fetch_at: i64) -> rusqlite::Result<()> {
+    let mut conn = connect()?;
+    let tx = conn.transaction()?;
+    match &log.error {
+        None => tx.execute(
+            "UPDATE feeds SET last_status = 'ok', failures = 0, last_success_at = ?1,
+                              next_fetch_at = ?2
+             WHERE url = ?3",
+            params![log.fetched_at, next_fetch_at, url],
+        )?,
+        Some(error) => tx.execute(
+            "UPDATE feeds SET last_status = ?1, failures = failures + 1, last_error = ?1,
+                              last_error_at = ?2, next_fetch_at = ?3
+             WHERE url = ?4",
+            params![error, log.fetched_at, next_fetch_at, url],
+        )?,
+    };
+    tx.execute(
+        "INSERT INTO fetch_log
+             (feed_id, fetched_at, http_status, duration_ms, items_found, items_new, error)
+         SELECT id, ?1, ?2, ?3, ?4, ?5, ?6 FROM feeds WHERE url = ?7",
+        params![
+            log.fetched_at,
+            log.http_status,
+            log.duration_ms,
+            log.items_found,
+            log.items_new,
+            log.error,
+            url
+        ],
+    )?;
+    tx.execute(
+        "DELETE FROM fetch_log WHERE feed_id = (SELECT id FROM feeds WHERE url = ?1)
+         AND id NOT IN (SELECT l.id FROM fetch_log l JOIN feeds f ON f.id = l.feed_id
+                        WHERE f.url = ?1 ORDER BY l.id DESC LIMIT ?2)",
+        params![url, FETCH_LOG_SIZE as i64],
+    )?;
+    tx.commit()
+}
+
+/// the newest fetches of a feed, newest first
+pub fn query_fetch_log(feed_id: i64, limit: usize) -> rusqlite::Result<Vec<FetchLog>> {
+    let conn = connect()?;
+    let mut statement = conn.prepare(
+        "SELECT fetched_at, http_status, duration_ms, items_found, items_new, error
+         FROM fetch_log WHERE feed_id = ? ORDER BY id DESC LIMIT ?",
+    )?;
+    let logs = statement
+        .query_map(params![feed_id, limit as i64], |row| {
+            Ok(FetchLog {
+                fetched_at: row.get(0)?,
+                http_status: row.get(1)?,
+                duration_ms: row.get(2)?,
+                items_found: row.get(3)?,
+                items_new: row.get(4)?,
+                error: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(logs)
+}
+
+/// the fetch state of a feed, the defaults for an unknown feed
+pub(crate) fn query_fetch_state(url: &str) -> FetchState {
+    let query = || -> rusqlite::Result<FetchState> {
+        connect()?.query_row(
+            "SELECT fetch_interval, ttl, next_fetch_at, failures,
+                    last_success_at, last_error, last_error_at
+             FROM feeds WHERE url = ?",
+            [url],
+            |row| row_to_fetch_state(row, 0),
+        )
+    };
+    query().unwrap_or_default()
+}
+
+/// publish times of the newest pages of a feed
+pub(crate) fn recent_published(url: &str, limit: usize) -> rusqlite::Result<Vec<i64>> {
+    let conn = connect()?;
+    let mut statement = conn.prepare(
+        "SELECT p.published_at FROM pages p JOIN feeds f ON f.id = p.feed_id
+         WHERE f.url = ? ORDER BY p.published_at DESC LIMIT ?",
+    )?;
+    let times = statement
+        .query_map(params![url, limit as i64], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(times)
+}
+
+static ENABLED_FEEDS: &str = "id IN (SELECT feed_id FROM subscriptions WHERE enabled)";
+// the feed ?1 is subscribed by the user ?2, who is an admin
+static SUBSCRIBED_BY_ADMIN: &str = "id IN (SELECT feed_id FROM subscriptions WHERE user_id = ?2)
+     AND ?2 IN (SELECT id FROM users WHERE admin)";
+
+/// urls of enabled feeds due for a fetch at `now`, the longest waiting first
+pub(crate) fn due_feeds(now: i64) -> rusqlite::Result<Vec<String>> {
+    let conn = connect()?;
+    let mut statement = conn.prepare(&format!(
+        "SELECT url FROM feeds WHERE {} AND next_fetch_at <= ? ORDER BY next_fetch_at, id",
+        ENABLED_FEEDS
+    ))?;
+    let urls = statement
+        .query_map([now], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(urls)
+}
+
+/// the first time after `now` an enabled feed is due
+pub(crate) fn next_due_at(now: i64) -> rusqlite::Result<Option<i64>> {
+    let conn = connect()?;
+    conn.query_row(
+        &format!(
+            "SELECT MIN(next_fetch_at) FROM feeds WHERE {} AND next_fetch_at > ?",
+            ENABLED_FEEDS
+        ),
+        [now],
+        |row| row.get(0),
+    )
+}
+
+/// set the minutes between two fetches of a feed an admin subscribes, `None` to follow
+/// the feed, it is fetched again right away. Nothing changes for other users.
+pub fn set_feed_interval(
+    user_id: i64,
+    feed_id: i64,
+    minutes: Option<i64>,
+) -> rusqlite::Result<usize> {
+    connect()?.execute(
+        &format!(
+            "UPDATE feeds SET fetch_interval = ?3, next_fetch_at = 0 WHERE id = ?1 AND {}",
+            SUBSCRIBED_BY_ADMIN
+        ),
+        params![feed_id, user_id, minutes],
+    )
+}
+
+/// extraction rules of a feed, the defaults for an unknown feed
+pub fn query_feed_rules(url: &str) -> ExtractRules {
+    let query = || -> rusqlite::Result<ExtractRules> {
+        connect()?.query_row(
+            "SELECT content_selector, strip_selectors, full_page FROM feeds WHERE url = ?",
+            [url],
+            |row| row_to_rules(row, 0),
+        )
+    };
+    query().unwrap_or_default()
+}
+
+/// set the extraction rules of a feed an admin subscribes, they apply to every subscriber.
+/// Nothing changes for other users.
+pub fn set_feed_rules(user_id: i64, feed_id: i64, rules: &ExtractRules) -> rusqlite::Result<usize> {
+    let strip = rules
+        .strip
+        .as_ref()
+        .map(|s| serde_json::to_string(s).unwrap());
+    connect()?.execute(
+        &format!(
+            "UPDATE feeds SET content_selector = ?3, strip_selectors = ?4, full_page = ?5
+             WHERE id = ?1 AND {}",
+            SUBSCRIBED_BY_ADMIN
+        ),
+        params![
+            feed_id,
+            user_id,
+            rules.content_selector,
+            strip,
+            rules.full_page
+        ],
+    )
+}
+
+/// delete the pages of feeds nobody subscribes, unless someone starred them,
+/// then the feeds left without subscribers nor pages and the tags left without pages
 pub(crate) fn cleanup_pages() -> rusqlite::Result<()> {
-    let feeds = all_feeds();
-    let conn = Connection::open(PAGES_DB)?;
-    let params = feeds
-        .iter()
-        .map(|f| format!("'{}'", f))
-        .collect::<Vec<String>>();
-    let sql = format!(
-        "DELETE FROM pages WHERE source NOT IN ({})",
-        params.join(", ")
-    );
-    conn.execute(&sql, [])?;
+    let conn = connect()?;
+    conn.execute_batch(
+        "DELETE FROM pages WHERE feed_id NOT IN (SELECT feed_id FROM subscriptions)
+         AND id NOT IN (SELECT page_id FROM page_states WHERE starred);
+         DELETE FROM feeds WHERE id NOT IN (SELECT feed_id FROM subscriptions)
+         AND id NOT IN (SELECT feed_id FROM pages);
+         DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM page_tags);",
+    )
+}
+
+pub(crate) fn query_feed_cache(feed: &str) -> Option<FeedCache> {
+    let conn = connect().ok()?;
+    conn.query_row(
+        "SELECT etag, last_modified FROM feed_cache WHERE feed = ?",
+        [feed],
+        |row| {
+            Ok(FeedCache {
+                etag: row.get(0)?,
+                last_modified: row.get(1)?,
+            })
+        },
+    )
+    .ok()
+}
+
+pub(crate) fn update_feed_cache(feed: &str, cache: &FeedCache) -> rusqlite::Result<()> {
+    let conn = connect()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO feed_cache (feed, etag, last_modified) values (?1, ?2, ?3)",
+        params![feed, cache.etag, cache.last_modified],
+    )?;
     Ok(())
 }
 
+/// whether a page is stored for any user, or was purged
+pub(crate) fn page_exists(link: &str) -> rusqlite::Result<bool> {
+    let conn = connect()?;
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pages WHERE link = ?1)
+             OR EXISTS (SELECT 1 FROM purged_links WHERE link = ?1)",
+        [link],
+        |row| row.get(0),
+    )
+}
+
+/// ids of the pages published before `cutoff` or past the `items` newest of their feed,
+/// except the pages a subscriber hasn't read, the ones someone starred and, unless
+/// `purge_tagged`, the ones someone tagged
+pub(crate) fn purgeable_pages(
+    cutoff: Option<i64>,
+    items: Option<usize>,
+    purge_tagged: bool,
+) -> rusqlite::Result<Vec<i64>> {
+    let conn = connect()?;
+    let mut statement = conn.prepare(
+        "SELECT r.id FROM (
+             SELECT p.id, p.feed_id, p.published_at, row_number() OVER (
+                 PARTITION BY p.feed_id ORDER BY p.published_at DESC, p.id DESC) AS position
+             FROM pages p) r
+         WHERE ((?1 IS NOT NULL AND r.published_at < ?1) OR (?2 IS NOT NULL AND r.position > ?2))
+         AND NOT EXISTS (SELECT 1 FROM page_states ps WHERE ps.page_id = r.id AND ps.starred)
+         AND (?3 OR NOT EXISTS (SELECT 1 FROM page_tags pt WHERE pt.page_id = r.id))
+         AND NOT EXISTS (SELECT 1 FROM subscriptions s WHERE s.feed_id = r.feed_id
+             AND NOT EXISTS (SELECT 1 FROM page_states ps
+                 WHERE ps.page_id = r.id AND ps.user_id = s.user_id AND ps.readed))
+         ORDER BY r.id",
+    )?;
+    let ids = statement
+        .query_map(
+            params![cutoff, items.map(|i| i as i64), purge_tagged],
+            |row| row.get(0),
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(ids)
+}
+
+/// delete pages with their body, states and tags, their links are remembered for
+/// `PURGED_LINKS_DAYS` so that they are not fetched again. `then` runs before the deletion is
+/// committed, no page can be stored in between.
+pub(crate) fn delete_pages<T, E: From<rusqlite::Error>>(
+    ids: &[i64],
+    now: i64,
+    then: impl FnOnce(&Connection) -> Result<T, E>,
+) -> Result<(usize, T), E> {
+    let mut conn = connect()?;
+    // holds the write lock from the start until `then` is done
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let mut deleted = 0;
+    // SQLite limits the number of bound parameters
+    for chunk in ids.chunks(500) {
+        let marks = vec!["?"; chunk.len()].join(", ");
+        let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(now)];
+        params.extend(chunk.iter().map(|&id| Box::new(id) as Box<dyn ToSql>));
+        tx.execute(
+            &format!(
+                "INSERT OR REPLACE INTO purged_links (link, purged_at)
+                 SELECT link, ?1 FROM pages WHERE id IN ({})",
+                marks
+            ),
+            params_from_iter(params.iter()),
+        )?;
+        deleted += tx.execute(
+            &format!("DELETE FROM pages WHERE id IN ({})", marks),
+            params_from_iter(params[1..].iter()),
+        )?;
+    }
+    tx.execute(
+        "DELETE FROM purged_links WHERE purged_at < ?",
+        [now - PURGED_LINKS_DAYS * 24 * 3600],
+    )?;
+    tx.execute(
+        "DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM page_tags)",
+        [],
+    )?;
+    let result = then(&tx)?;
+    tx.commit()?;
+    Ok((deleted, result))
+}
+
+/// call `f` with the id and body of every page stored in `conn`, one at a time
+pub(crate) fn scan_bodies(conn: &Connection, mut f: impl FnMut(i64, &str)) -> rusqlite::Result<()> {
+    let mut statement = conn.prepare("SELECT page_id, body FROM contents")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        f(row.get(0)?, &row.get::<_, String>(1)?);
+    }
+    Ok(())
+}
+
+/// give the space of deleted rows back to the file system
+pub(crate) fn vacuum() -> rusqlite::Result<()> {
+    connect()?.execute_batch("VACUUM;")
+}
+
+/// store a page once for all users, `readed` is ignored
 pub(crate) fn dump_new_page(page: &Page) -> rusqlite::Result<()> {
-    let conn = Connection::open(PAGES_DB)?;
-    if let Some(_) = query_page_link(&page.link) {
+    if page_exists(&page.link)? {
         return Ok(());
     }
+    let conn = connect()?;
     conn.execute(
-        "INSERT INTO pages (title, link, website, publish_datetime, readed, source) values (?1, ?2, ?3, ?4, ?5, ?6)",
-        params![page.title, page.link, page.website, page.publish_datetime, page.readed, page.source])?;
+        "INSERT INTO pages (title, link, website, published_at, fetched_at, feed_id)
+         SELECT ?1, ?2, ?3, ?4, ?5, id FROM feeds WHERE url = ?6",
+        params![
+            page.title,
+            page.link,
+            page.website,
+            page.published_at,
+            page.fetched_at,
+            page.source
+        ],
+    )?;
     Ok(())
 }
 
-pub fn update_page_read(link: &str) -> rusqlite::Result<usize> {
-    let conn = Connection::open(PAGES_DB)?;
-    conn.execute("UPDATE pages set readed = 1 where link = ?", [link])
+pub(crate) fn save_page_content(link: &str, body: &str) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO contents (page_id, body) SELECT id, ?1 FROM pages WHERE link = ?2",
+        params![body, link],
+    )
+}
+
+pub fn query_page_content(id: i64) -> rusqlite::Result<Option<String>> {
+    let conn = connect()?;
+    match conn.query_row("SELECT body FROM contents WHERE page_id = ?", [id], |row| {
+        row.get(0)
+    }) {
+        Ok(body) => Ok(Some(body)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// bodies of the pages, the pages without one are left out
+pub fn query_page_contents(ids: &[i64]) -> rusqlite::Result<HashMap<i64, String>> {
+    let conn = connect()?;
+    let mut bodies = HashMap::new();
+    // SQLite limits the number of bound parameters
+    for chunk in ids.chunks(500) {
+        let mut statement = conn.prepare(&format!(
+            "SELECT page_id, body FROM contents WHERE page_id IN ({})",
+            vec!["?"; chunk.len()].join(", ")
+        ))?;
+        let rows = statement.query_map(params_from_iter(chunk.iter()), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (id, body) = row?;
+            bodies.insert(id, body);
+        }
+    }
+    Ok(bodies)
+}
+
+/// set the read state of a page for the user, 0 when the user can't see the page
+pub fn set_page_read(user_id: i64, id: i64, readed: bool) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute(
+        &format!(
+            "INSERT INTO page_states (user_id, page_id, readed)
+             SELECT ?1, p.id, ?3 FROM {} WHERE p.id = ?2
+             ON CONFLICT (user_id, page_id) DO UPDATE SET readed = excluded.readed",
+            USER_PAGES
+        ),
+        params![user_id, id, readed],
+    )
+}
+
+/// star or unstar a page for the user, 0 when the user can't see the page.
+/// Starred pages are kept when their feed is unsubscribed.
+pub fn set_page_starred(user_id: i64, id: i64, starred: bool) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute(
+        &format!(
+            "INSERT INTO page_states (user_id, page_id, starred)
+             SELECT ?1, p.id, ?3 FROM {} WHERE p.id = ?2
+             ON CONFLICT (user_id, page_id) DO UPDATE SET starred = excluded.starred",
+            USER_PAGES
+        ),
+        params![user_id, id, starred],
+    )
+}
+
+/// A tag of the user with the number of pages it is on
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Tag {
+    pub name: String,
+    pub pages: usize,
+}
+
+static MAX_TAG_LEN: usize = 64;
+
+/// the tag name stored for what a user typed, None when it's empty or too long
+pub fn tag_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_TAG_LEN {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// tag a page for the user, 0 when the user can't see the page or it has the tag already
+pub fn add_page_tag(user_id: i64, id: i64, name: &str) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    let tx = conn.unchecked_transaction()?;
+    let visible = format!("EXISTS (SELECT 1 FROM {} WHERE p.id = ?2)", USER_PAGES);
+    tx.execute(
+        &format!(
+            "INSERT OR IGNORE INTO tags (user_id, name) SELECT ?1, ?3 WHERE {}",
+            visible
+        ),
+        params![user_id, id, name],
+    )?;
+    let added = tx.execute(
+        &format!(
+            "INSERT OR IGNORE INTO page_tags (tag_id, page_id, added_at)
+             SELECT t.id, ?2, ?4 FROM tags t WHERE t.user_id = ?1 AND t.name = ?3 AND {}",
+            visible
+        ),
+        params![user_id, id, name, Utc::now().timestamp()],
+    )?;
+    tx.commit()?;
+    Ok(added)
+}
+
+/// take a tag off a page of the user, the tag is gone with its last page
+pub fn remove_page_tag(user_id: i64, id: i64, name: &str) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    let removed = conn.execute(
+        "DELETE FROM page_tags WHERE page_id = ?2
+         AND tag_id = (SELECT id FROM tags WHERE user_id = ?1 AND name = ?3)",
+        params![user_id, id, name],
+    )?;
+    conn.execute(
+        "DELETE FROM tags WHERE user_id = ? AND id NOT IN (SELECT tag_id FROM page_tags)",
+        [user_id],
+    )?;
+    Ok(removed)
+}
+
+/// tags of the user sorted by name, counting the pages the user sees
+pub fn query_tags(user_id: i64) -> rusqlite::Result<Vec<Tag>> {
+    let conn = connect()?;
+    let mut statement = conn.prepare(&format!(
+        "SELECT t.name, count(*) FROM {}
+         JOIN page_tags pt ON pt.page_id = p.id
+         JOIN tags t ON t.id = pt.tag_id AND t.user_id = u.id
+         GROUP BY t.name ORDER BY t.name",
+        USER_PAGES
+    ))?;
+    let tags = statement
+        .query_map([user_id], |row| {
+            Ok(Tag {
+                name: row.get(0)?,
+                pages: row.get::<_, i64>(1)? as usize,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(tags)
+}
+
+/// names of the tags the user put on each of the pages, sorted
+pub fn query_page_tags(user_id: i64, ids: &[i64]) -> rusqlite::Result<HashMap<i64, Vec<String>>> {
+    let conn = connect()?;
+    let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(user_id)];
+    params.extend(ids.iter().map(|&id| Box::new(id) as Box<dyn ToSql>));
+    let mut statement = conn.prepare(&format!(
+        "SELECT pt.page_id, t.name FROM page_tags pt JOIN tags t ON t.id = pt.tag_id
+         WHERE t.user_id = ? AND pt.page_id IN ({}) ORDER BY t.name",
+        vec!["?"; ids.len()].join(", ")
+    ))?;
+    let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+    let rows = statement.query_map(params_from_iter(params.iter()), |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (id, name) = row?;
+        tags.entry(id).or_default().push(name);
+    }
+    Ok(tags)
 }
 
-pub fn mark_pages_read(limit: usize) -> rusqlite::Result<usize> {
-    let conn = Connection::open(PAGES_DB)?;
+/// set the read state of every page of the user matching the filter
+pub fn set_pages_read(user_id: i64, filter: &PageFilter, readed: bool) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    let (filter_sql, mut params) = filter.to_sql();
+    // the user is the first parameter of both the insert and `USER_PAGES`
+    params.insert(0, Box::new(readed));
+    params.insert(0, Box::new(user_id));
+    // the WHERE keeps the upsert clause from being parsed as a join constraint
     let sql = format!(
-        "UPDATE pages SET readed = 1 WHERE id IN (SELECT id FROM pages WHERE readed = 0 ORDER BY publish_datetime DESC LIMIT {})",
-        limit
+        "INSERT INTO page_states (user_id, page_id, readed)
+         SELECT ?1, id, ?2 FROM (SELECT p.id FROM {}{}) WHERE true
+         ON CONFLICT (user_id, page_id) DO UPDATE SET readed = excluded.readed",
+        USER_PAGES, filter_sql
     );
-    let res = conn.execute(&sql, []);
+    conn.execute(&sql, params_from_iter(params.iter()))
+}
+
+pub fn mark_pages_read(user_id: i64, limit: usize) -> rusqlite::Result<usize> {
+    let res = set_pages_read(user_id, &PageFilter::new().readed(false).limit(limit), true);
     println!("result: {:?}", res);
     res
 }
 
-pub fn remove_pages_from_link(link: &str) -> rusqlite::Result<usize> {
-    let conn = Connection::open(PAGES_DB)?;
-    let Some(page) = query_page_link(&link) else {
+pub fn remove_pages_from_link(user_id: i64, link: &str) -> rusqlite::Result<usize> {
+    let Some(page) = query_page_link(user_id, link)? else {
         return Ok(0);
     };
-    let res = conn.execute("DELETE FROM pages where source = ?", [&page.source]);
-    // remove feed from feeds.md
-    let feeds = all_feeds();
-    let feeds = feeds
-        .iter()
-        .filter(|&f| f != &page.source)
-        .map(|f| f.to_string())
-        .collect::<Vec<_>>();
-    fs::write(ALL_FEEDS, feeds.join("\n")).unwrap();
+    let res = remove_feed(user_id, &page.source);
     eprintln!("deleted {:#?}", res);
     res
 }
 
-pub fn query_pages(limits: &Vec<(&str, &str)>) -> Vec<Page> {
-    #[cfg(not(test))]
-    cleanup_pages().unwrap();
-    let conn = Connection::open(PAGES_DB).unwrap();
-    let limit_str = if limits.len() > 0 {
-        limits
-            .iter()
-            .map(|&(k, v)| format!("{} = '{}'", k, v))
-            .collect::<Vec<String>>()
-            .join(" AND ")
-    } else {
-        String::from(" 1 = 1 ")
-    };
-    let sql = format!(
-        "SELECT * FROM pages WHERE {} ORDER BY publish_datetime DESC",
-        limit_str
-    );
-    let mut statement = conn.prepare(&sql).unwrap();
+/// columns read by `row_to_page`, from `USER_PAGES`
+pub(crate) static PAGE_COLUMNS: &str =
+    "p.id, p.title, p.link, p.website, p.published_at, p.fetched_at, COALESCE(ps.readed, 0), f.url,
+     COALESCE(ps.starred, 0)";
+
+/// pages of the feeds subscribed by the user bound to the first parameter, with its states,
+/// and the pages the user starred in feeds no longer subscribed
+pub(crate) static USER_PAGES: &str = "pages p JOIN feeds f ON f.id = p.feed_id
+     LEFT JOIN subscriptions s ON s.feed_id = p.feed_id AND s.user_id = ?1
+     LEFT JOIN page_states ps ON ps.page_id = p.id AND ps.user_id = ?1
+     JOIN users u ON u.id = ?1 AND (s.user_id IS NOT NULL OR ps.starred)";
+
+pub(crate) fn row_to_page(row: &rusqlite::Row) -> rusqlite::Result<Page> {
+    Ok(Page {
+        id: row.get(0)?,
+        title: row.get(1).unwrap_or("no title".to_string()),
+        link: row.get(2)?,
+        website: row.get(3)?,
+        published_at: row.get(4)?,
+        fetched_at: row.get(5)?,
+        readed: row.get(6)?,
+        source: row.get(7)?,
+        starred: row.get(8)?,
+    })
+}
+
+pub fn query_pages(user_id: i64, filter: &PageFilter) -> rusqlite::Result<Vec<Page>> {
+    let conn = connect()?;
+    let (filter_sql, mut params) = filter.to_sql();
+    params.insert(0, Box::new(user_id));
+    let sql = format!("SELECT {} FROM {}{}", PAGE_COLUMNS, USER_PAGES, filter_sql);
+    let mut statement = conn.prepare(&sql)?;
     let pages = statement
-        .query_map([], |row| {
-            Ok(Page {
-                title: row.get(1).unwrap_or("no title".to_string()),
-                link: row.get(2).unwrap(),
-                website: row.get(3).unwrap(),
-                publish_datetime: row.get(4).unwrap(),
-                readed: row.get(5).unwrap(),
-                source: row.get(6).unwrap(),
-            })
-        })
-        .unwrap();
+        .query_map(params_from_iter(params.iter()), row_to_page)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(pages)
+}
 
-    let res: Vec<Page> = pages.map(|f| f.unwrap()).collect();
-    return res;
+/// number of pages of the user matching the filter, ignoring its limit and offset
+pub fn count_pages(user_id: i64, filter: &PageFilter) -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    let filter = PageFilter {
+        limit: None,
+        offset: 0,
+        ..filter.clone()
+    };
+    let (filter_sql, mut params) = filter.to_sql();
+    params.insert(0, Box::new(user_id));
+    let sql = format!("SELECT count(*) FROM {}{}", USER_PAGES, filter_sql);
+    conn.query_row(&sql, params_from_iter(params.iter()), |row| {
+        row.get::<_, i64>(0)
+    })
+    .map(|n| n as usize)
 }
 
-pub fn query_page_link(link: &str) -> Option<Page> {
-    let pages = query_pages(&vec![("link", link)]);
+pub fn query_page(user_id: i64, id: i64) -> rusqlite::Result<Option<Page>> {
+    Ok(query_pages(user_id, &PageFilter::new().id(id))?.pop())
+}
+
+pub fn query_page_link(user_id: i64, link: &str) -> rusqlite::Result<Option<Page>> {
+    let mut pages = query_pages(user_id, &PageFilter::new().link(link))?;
     assert!(pages.len() <= 1);
-    if pages.len() == 1 {
-        return Some(pages[0].clone());
-    } else {
-        None
+    Ok(pages.pop())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::default_user;
+    use crate::testing::{self, TestDir};
+
+    #[test]
+    fn test_page_filter_sql() {
+        let (sql, params) = PageFilter::new().to_sql();
+        assert_eq!(sql, " ORDER BY p.published_at DESC, p.id DESC");
+        assert!(params.is_empty());
+
+        let (sql, params) = PageFilter::new()
+            .readed(false)
+            .link("it's")
+            .source("http://a.com/rss")
+            .order(Order::OldestFirst)
+            .limit(10)
+            .offset(20)
+            .to_sql();
+        assert_eq!(
+            sql,
+            " WHERE COALESCE(ps.readed, 0) = ? AND p.link = ? AND f.url = ? \
+             ORDER BY p.published_at ASC, p.id ASC LIMIT ? OFFSET ?"
+        );
+        assert!(!sql.contains("it's"));
+        assert_eq!(params.len(), 5);
+
+        let (sql, params) = PageFilter::new().offset(5).to_sql();
+        assert!(sql.ends_with(" LIMIT ? OFFSET ?"));
+        assert_eq!(params.len(), 2);
+    }
+
+    #[test]
+    fn test_query_pages_error() {
+        // a db without tables is an error for the caller, not a panic
+        let _dir = TestDir::empty();
+        assert!(query_pages(1, &PageFilter::new()).is_err());
+        assert!(query_page(1, 1).is_err());
+    }
+
+    #[test]
+    fn test_query_pages_filter() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        add_feed(user, "http://a.com/rss")?;
+        add_feed(user, "http://b.com/it's")?;
+        let day = |d: u32| Utc.with_ymd_and_hms(2022, 1, d, 0, 0, 0).unwrap();
+        for (i, source) in ["http://a.com/rss", "http://b.com/it's", "http://a.com/rss"]
+            .iter()
+            .enumerate()
+        {
+            dump_new_page(&Page {
+                title: format!("title{}", i),
+                published_at: day(i as u32 + 1).timestamp(),
+                ..testing::page(&format!("http://a.com/'{}'", i), source)
+            })?;
+        }
+        let first = query_page_link(user, "http://a.com/'0'")?.unwrap();
+        set_page_read(user, first.id, true)?;
+
+        let titles = |filter: PageFilter| {
+            query_pages(user, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.title)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(titles(PageFilter::new()), ["title2", "title1", "title0"]);
+        assert_eq!(
+            titles(PageFilter::new().readed(false)),
+            ["title2", "title1"]
+        );
+        assert_eq!(
+            titles(PageFilter::new().source("http://b.com/it's")),
+            ["title1"]
+        );
+        assert_eq!(
+            titles(PageFilter::new().link("http://a.com/'0'")),
+            ["title0"]
+        );
+        assert_eq!(
+            titles(PageFilter::new().since(day(2)).until(day(3))),
+            ["title1"]
+        );
+        assert_eq!(
+            titles(PageFilter::new().order(Order::OldestFirst).limit(2)),
+            ["title0", "title1"]
+        );
+        assert_eq!(titles(PageFilter::new().limit(1).offset(1)), ["title1"]);
+        assert_eq!(
+            titles(
+                PageFilter::new()
+                    .since_id(first.id)
+                    .order(Order::IdAscending)
+            ),
+            ["title1", "title2"]
+        );
+        assert_eq!(
+            titles(
+                PageFilter::new()
+                    .max_id(first.id + 2)
+                    .order(Order::IdDescending)
+            ),
+            ["title1", "title0"]
+        );
+        assert_eq!(
+            titles(PageFilter::new().ids(&[first.id, first.id + 2])),
+            ["title2", "title0"]
+        );
+        assert!(titles(PageFilter::new().ids(&[])).is_empty());
+        assert_eq!(
+            count_pages(user, &PageFilter::new().readed(false).limit(1))?,
+            2
+        );
+
+        let b = PageFilter::new().source("http://b.com/it's");
+        assert_eq!(set_pages_read(user, &b, true)?, 1);
+        assert_eq!(titles(PageFilter::new().readed(false)), ["title2"]);
+        set_pages_read(user, &PageFilter::new(), false)?;
+        assert_eq!(titles(PageFilter::new().readed(true)).len(), 0);
+
+        set_page_read(user, first.id, true)?;
+        set_page_starred(user, first.id, true)?;
+        assert_eq!(titles(PageFilter::new().starred(true)), ["title0"]);
+        // starring keeps the read state
+        assert!(query_page(user, first.id)?.unwrap().readed);
+        set_page_starred(user, first.id, false)?;
+        assert!(titles(PageFilter::new().starred(true)).is_empty());
+        assert!(query_page_link(user, "' OR 1 = 1 --")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_content() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        add_feed(user, "source")?;
+        for link in ["link1", "link2"] {
+            dump_new_page(&Page {
+                title: "same title".to_string(),
+                ..testing::page(link, "source")
+            })?;
+            save_page_content(link, &format!("<p>{}</p>", link))?;
+        }
+        // pages with the same title keep their own body
+        let page1 = query_page_link(user, "link1")?.unwrap();
+        let page2 = query_page_link(user, "link2")?.unwrap();
+        assert_eq!(query_page(user, page1.id)?, Some(page1.clone()));
+        assert_eq!(query_page_content(page1.id)?.unwrap(), "<p>link1</p>");
+        assert_eq!(query_page_content(page2.id)?.unwrap(), "<p>link2</p>");
+        let contents = query_page_contents(&[page1.id, page2.id, 999])?;
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents[&page1.id], "<p>link1</p>");
+        assert_eq!(contents[&page2.id], "<p>link2</p>");
+
+        save_page_content("link1", "<p>updated</p>")?;
+        assert_eq!(query_page_content(page1.id)?.unwrap(), "<p>updated</p>");
+
+        remove_feed(user, "source")?;
+        assert!(query_page(user, page1.id)?.is_none());
+        assert!(query_page_content(page1.id)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_users() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        let conn = connect()?;
+        let alice = default_user(&conn)?;
+        conn.execute(
+            "INSERT INTO users (name, password_hash, created_at) values ('bob', '', 0)",
+            [],
+        )?;
+        let bob = conn.last_insert_rowid();
+        add_feed(alice, "shared")?;
+        add_feed(bob, "shared")?;
+        add_feed(alice, "alice")?;
+        for (link, source) in [("link1", "shared"), ("link2", "alice")] {
+            dump_new_page(&testing::page(link, source))?;
+        }
+        // pages are stored once, each user sees the feeds it subscribes
+        let count: i64 = conn.query_row("SELECT count(*) FROM pages", [], |row| row.get(0))?;
+        assert_eq!(count, 2);
+        assert_eq!(query_pages(alice, &PageFilter::new())?.len(), 2);
+        assert_eq!(query_pages(bob, &PageFilter::new())?.len(), 1);
+        let page2 = query_page_link(alice, "link2")?.unwrap();
+        assert!(query_page(bob, page2.id)?.is_none());
+        assert_eq!(set_page_read(bob, page2.id, true)?, 0);
+
+        // read state is kept per user
+        mark_pages_read(alice, 10)?;
+        assert!(query_page_link(alice, "link1")?.unwrap().readed);
+        assert!(!query_page_link(bob, "link1")?.unwrap().readed);
+
+        // folders and enabled are per subscription
+        import_feed(bob, "shared", "Shared", "", "news")?;
+        set_feed_enabled(alice, "shared", false)?;
+        assert_eq!(query_feeds(alice)?[0].folder, "");
+        assert_eq!(query_feeds(bob)?[0].folder, "news");
+        // unread pages are counted per user
+        assert_eq!(query_feeds(alice)?[0].unread, 0);
+        assert_eq!(query_feeds(bob)?[0].unread, 1);
+        let shared = query_feeds(alice)?[0].id;
+        set_feed_folder(alice, shared, " /Rust//Compiler/")?;
+        assert_eq!(query_feeds(alice)?[0].folder, "Rust/Compiler");
+        let filed = query_pages(alice, &PageFilter::new().folder("Rust/Compiler"))?;
+        assert_eq!(filed.len(), 1);
+        assert_eq!(filed[0].link, "link1");
+        let folder = |name: &str, feeds, unread| Folder {
+            name: name.to_string(),
+            feeds,
+            unread,
+        };
+        set_page_read(alice, page2.id, false)?;
+        assert_eq!(
+            query_folders(alice)?,
+            [folder("", 1, 1), folder("Rust/Compiler", 1, 0)]
+        );
+        assert_eq!(query_folders(bob)?, [folder("news", 1, 1)]);
+        assert_eq!(all_feeds(), vec!["shared", "alice"]);
+
+        // the feed stays while someone subscribes it
+        remove_feed(bob, "shared")?;
+        assert!(query_feeds(bob)?.is_empty());
+        assert_eq!(query_pages(alice, &PageFilter::new())?.len(), 2);
+        remove_feed(alice, "shared")?;
+        assert!(!page_exists("link1")?);
+
+        // a starred page outlives the feed, for the user who starred it only
+        add_feed(bob, "alice")?;
+        let page2 = query_page_link(bob, "link2")?.unwrap();
+        set_page_starred(bob, page2.id, true)?;
+        remove_feed(alice, "alice")?;
+        remove_feed(bob, "alice")?;
+        assert!(query_pages(alice, &PageFilter::new())?.is_empty());
+        assert!(query_page(bob, page2.id)?.unwrap().starred);
+        assert!(all_feeds().is_empty());
+        set_page_read(bob, page2.id, true)?;
+        assert!(query_page(bob, page2.id)?.unwrap().readed);
+        set_page_starred(bob, page2.id, false)?;
+        cleanup_pages()?;
+        assert!(!page_exists("link2")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tags() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        let conn = connect()?;
+        let alice = default_user(&conn)?;
+        conn.execute(
+            "INSERT INTO users (name, password_hash, created_at) values ('bob', '', 0)",
+            [],
+        )?;
+        let bob = conn.last_insert_rowid();
+        add_feed(alice, "source")?;
+        for link in ["link1", "link2"] {
+            dump_new_page(&testing::page(link, "source"))?;
+        }
+        let page1 = query_page_link(alice, "link1")?.unwrap().id;
+        let page2 = query_page_link(alice, "link2")?.unwrap().id;
+        assert_eq!(add_page_tag(alice, page1, "rfc")?, 1);
+        assert_eq!(add_page_tag(alice, page1, "rfc")?, 0);
+        add_page_tag(alice, page1, "incident")?;
+        add_page_tag(alice, page2, "rfc")?;
+        // bob can't tag what he doesn't see
+        assert_eq!(add_page_tag(bob, page1, "rfc")?, 0);
+        assert!(query_tags(bob)?.is_empty());
+
+        let tag = |name: &str, pages| Tag {
+            name: name.to_string(),
+            pages,
+        };
+        assert_eq!(query_tags(alice)?, [tag("incident", 1), tag("rfc", 2)]);
+        let tags = query_page_tags(alice, &[page1, page2])?;
+        assert_eq!(tags[&page1], ["incident", "rfc"]);
+        assert_eq!(tags[&page2], ["rfc"]);
+        let tagged = query_pages(alice, &PageFilter::new().tag("incident"))?;
+        assert_eq!(tagged.len(), 1);
+        assert_eq!(tagged[0].id, page1);
+
+        remove_page_tag(alice, page1, "incident")?;
+        assert_eq!(query_tags(alice)?, [tag("rfc", 2)]);
+        remove_feed(alice, "source")?;
+        assert!(query_tags(alice)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_schedule() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        add_feed(user, "a")?;
+        add_feed(user, "b")?;
+        add_feed(user, "off")?;
+        set_feed_enabled(user, "off", false)?;
+        // new feeds are due right away
+        assert_eq!(due_feeds(100)?, vec!["a", "b"]);
+        assert_eq!(next_due_at(100)?, None);
+
+        let failed = |error: &str| FetchLog {
+            fetched_at: 100,
+            error: Some(error.to_string()),
+            ..FetchLog::default()
+        };
+        let fetched = FetchLog {
+            fetched_at: 100,
+            http_status: Some(200),
+            duration_ms: 30,
+            items_found: 10,
+            items_new: 2,
+            error: None,
+        };
+        record_fetch("a", &fetched, 200)?;
+        record_fetch("b", &failed("timeout"), 300)?;
+        record_fetch("b", &failed("404 Not Found"), 150)?;
+        assert!(due_feeds(100)?.is_empty());
+        assert_eq!(next_due_at(100)?, Some(150));
+        assert_eq!(due_feeds(200)?, vec!["b", "a"]);
+
+        let a = query_fetch_state("a");
+        assert_eq!(a.failures, 0);
+        assert_eq!(a.last_success_at, Some(100));
+        let b = query_fetch_state("b");
+        assert_eq!(b.failures, 2);
+        assert!(!b.broken);
+        assert_eq!(b.last_error.as_deref(), Some("404 Not Found"));
+        assert!(b.last_success_at.is_none());
+        assert_eq!(query_feeds(user)?[1].last_status, "404 Not Found");
+
+        record_fetch("b", &fetched, 400)?;
+        assert_eq!(query_fetch_state("b").failures, 0);
+        // the last error stays for display
+        assert_eq!(
+            query_fetch_state("b").last_error.as_deref(),
+            Some("404 Not Found")
+        );
+        for _ in 0..BROKEN_FAILURES {
+            record_fetch("b", &failed("gone"), 400)?;
+        }
+        assert!(query_fetch_state("b").broken);
+
+        // the log keeps the newest fetches
+        let feeds = query_feeds(user)?;
+        assert_eq!(query_fetch_log(feeds[0].id, 10)?, vec![fetched.clone()]);
+        let log = query_fetch_log(feeds[1].id, 100)?;
+        assert_eq!(log.len(), 3 + BROKEN_FAILURES as usize);
+        assert_eq!(log[0].error.as_deref(), Some("gone"));
+        assert_eq!(log.last().unwrap().error.as_deref(), Some("timeout"));
+        for _ in 0..FETCH_LOG_SIZE {
+            record_fetch("b", &fetched, 400)?;
+        }
+        let log = query_fetch_log(feeds[1].id, 100)?;
+        assert_eq!(log.len(), FETCH_LOG_SIZE);
+        assert!(log.iter().all(|l| l.error.is_none()));
+
+        let a = &query_feeds(user)?[0];
+        update_feed_ttl("a", Some(60))?;
+        set_feed_interval(user, a.id, Some(30))?;
+        let state = query_fetch_state("a");
+        assert_eq!(
+            (state.interval, state.ttl, state.next_fetch_at),
+            (Some(30), Some(60), 0)
+        );
+        // the interval is shared, only admins set it
+        let bob = crate::auth::add_user("bob", "secret").unwrap();
+        add_feed(bob, "a")?;
+        assert_eq!(set_feed_interval(bob, a.id, Some(5))?, 0);
+        assert_eq!(query_fetch_state("a").interval, Some(30));
+        Ok(())
     }
 }
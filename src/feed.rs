@@ -1,45 +1,116 @@
 use crate::conf::*;
 use crate::db::*;
+use crate::readability;
+use crate::sanitize::sanitize;
+use crate::schedule;
+use crate::search::index_page;
 use chrono::prelude::*;
 use feed_rs::model::Link;
 use feed_rs::parser;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
 use scraper::{Html, Selector};
+use serde::Serialize;
 use sha2::Digest;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
-use url::Url;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use url::{Host, Url};
+
+type FetchError = Box<dyn Error + Send + Sync>;
+
+// the db, image files and html processing block, they run on the blocking pool so a
+// large refresh doesn't stall the http server sharing the async workers
+async fn blocking<T, E, F>(f: F) -> Result<T, FetchError>
+where
+    T: Send + 'static,
+    E: Into<FetchError>,
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+{
+    #[cfg(test)]
+    let dir = crate::testing::current();
+    tokio::task::spawn_blocking(move || {
+        #[cfg(test)]
+        crate::testing::enter(dir);
+        f().map_err(Into::into)
+    })
+    .await?
+}
+
+// `blocking` for work that can't fail
+async fn blocking_ok<T, F>(f: F) -> Result<T, FetchError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    blocking(move || Ok::<_, FetchError>(f())).await
+}
+
+/// Limits applied to one refresh cycle over all feeds
+#[derive(Clone, Copy, Debug)]
+pub struct FetchOptions {
+    /// max feeds fetched at the same time
+    pub jobs: usize,
+    /// max feeds fetched at the same time from one host
+    pub host_jobs: usize,
+    /// time budget for one feed, including its pages and images
+    pub timeout: Duration,
+    /// time between two fetches of a feed giving no hint about how often it changes
+    pub interval: Duration,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions {
+            jobs: FETCH_JOBS,
+            host_jobs: FETCH_HOST_JOBS,
+            timeout: Duration::from_secs(FETCH_TIMEOUT_SECS),
+            interval: Duration::from_secs(FETCH_INTERVAL_MINUTES * 60),
+        }
+    }
+}
+
+// elements stripped from fetched pages when the feed has no list of its own
+static DEFAULT_STRIP: &[&str] = &["footer", "header", "script", "style", "comments"];
 
 fn extract(html: &Html, keyword: &str) -> Option<String> {
-    let select = Selector::parse(keyword).unwrap();
+    let select = Selector::parse(keyword).ok()?;
     //If we have only one article class, use it
-    let mut elems = html
-        .select(&select)
-        .into_iter()
-        .map(|it| it.html())
-        .collect::<Vec<_>>();
-    elems.sort_by(|a, b| b.len().cmp(&a.len()));
-    if elems.len() > 0 {
+    let mut elems = html.select(&select).map(|it| it.html()).collect::<Vec<_>>();
+    elems.sort_by_key(|b| std::cmp::Reverse(b.len()));
+    if !elems.is_empty() {
         return Some(elems[0].clone());
     }
     None
 }
 
-fn remove_elements(content: &str, keywords: &Vec<&str>) -> String {
+fn remove_elements<S: AsRef<str>>(content: &str, keywords: &[S]) -> String {
     let html = Html::parse_document(content);
     let mut result = html.root_element().html().to_string();
     for keyword in keywords {
-        let select = Selector::parse(keyword).unwrap();
+        let Ok(select) = Selector::parse(keyword.as_ref()) else {
+            continue;
+        };
         html.select(&select).for_each(|it| {
             let unescaped = it.html();
             //assert!(result.contains(&unescaped));
             result = result.replace(&unescaped, "")
         });
     }
-    return result;
+    result
 }
 
-fn gen_image_name(uri: &str) -> Result<String, Box<dyn Error>> {
+// name of the local copy of the image at `uri`
+fn gen_image_name(uri: &str) -> Result<String, FetchError> {
     let digest = sha2::Sha256::digest(uri.as_bytes());
     let hex = digest
         .iter()
@@ -55,48 +126,60 @@ fn gen_image_name(uri: &str) -> Result<String, Box<dyn Error>> {
         .last()
         .unwrap()
         .to_string();
-    let segs = image_name.split(".").into_iter().collect::<Vec<_>>();
+    let segs = image_name.split(".").collect::<Vec<_>>();
     let extension = if segs.len() >= 2 {
         segs.last().unwrap()
     } else {
         "png"
     };
-    fs::create_dir_all(IMAGE_DIR)?;
-    Ok(format!("{}/{}.{}", IMAGE_DIR, hex_str, extension))
+    Ok(format!("{}.{}", hex_str, extension))
 }
 
 // try to download image from uri with a timeout of 3 seconds
 // return the relative path of the image
-fn convert_image(uri: &str) -> Result<String, Box<dyn Error>> {
+async fn convert_image(client: &reqwest::Client, uri: &str) -> Result<String, FetchError> {
     println!("preprocess_image: {:?}", uri);
-    let image_path = gen_image_name(uri)?;
-    let relative_path = image_path.replace("./", "/");
-    if !Path::new(&image_path).exists() {
-        let client = reqwest::blocking::Client::builder()
-            .timeout(std::time::Duration::from_secs(3))
-            .build()?;
-        let resp = client.get(uri).send()?;
+    let image_name = gen_image_name(uri)?;
+    let image_path = Path::new(&image_dir()).join(&image_name);
+    // an image used again counts as just downloaded, the purge leaves recent images alone
+    let exists = {
+        let image_path = image_path.clone();
+        blocking_ok(move || {
+            fs::File::options()
+                .write(true)
+                .open(&image_path)
+                .and_then(|f| f.set_modified(SystemTime::now()))
+                .is_ok()
+        })
+        .await?
+    };
+    if !exists {
+        let resp = client
+            .get(uri)
+            .timeout(Duration::from_secs(3))
+            .send()
+            .await?;
         if resp.status().is_success() {
-            let image = resp.bytes()?;
-            fs::write(&image_path, &image)?;
+            let image = resp.bytes().await?;
             println!("image saved: {:?}", image_path);
+            blocking(move || {
+                fs::create_dir_all(image_dir())?;
+                fs::write(&image_path, &image)
+            })
+            .await?;
         }
     } else {
         println!("image exists: {:?}", image_path);
     }
-    Ok(relative_path.clone())
+    Ok(format!("{}{}", IMAGE_URL_PREFIX, image_name))
 }
 
-// replace all images in content with local images
-fn preprocess_image(
-    content: &str,
-    website: &str,
-    cur_link: &str,
-) -> Result<String, Box<dyn Error>> {
+// collect the src of all images in content, with the absolute uri to download it from
+fn image_sources(content: &str, website: &str, cur_link: &str) -> Vec<(String, String)> {
     let html = Html::parse_document(content);
     let select = Selector::parse("img").unwrap();
     let imgs = html.select(&select);
-    let mut result = content.to_string();
+    let mut sources = vec![];
     for img in imgs {
         let node = img.value();
         let src = node.attr("src");
@@ -104,67 +187,470 @@ fn preprocess_image(
             let uri = Url::parse(url);
             let mut full_uri = url.to_string();
             if !(uri.is_ok() && uri.unwrap().scheme().to_string().starts_with("http")) {
-                if url.starts_with("/") {
+                if url.starts_with('/') {
                     full_uri = format!("{}{}", website, url);
                 } else {
-                    let last_pos = cur_link.rfind("/").unwrap();
+                    let last_pos = cur_link.rfind('/').unwrap();
                     let prefix = &cur_link[0..last_pos];
                     full_uri = format!("{}/{}", prefix, url);
                 }
             }
-            if let Ok(image) = convert_image(&full_uri) {
-                result = result.replace(url, &image);
-            }
+            sources.push((url.to_string(), full_uri));
         }
     }
-    Ok(result.clone())
+    sources
 }
 
-fn fetch_page(url: &str) -> Result<String, Box<dyn Error>> {
+// replace all images in content with local images
+async fn preprocess_image(
+    client: &reqwest::Client,
+    content: &str,
+    website: &str,
+    cur_link: &str,
+) -> Result<String, FetchError> {
+    let mut result = content.to_string();
+    let sources = {
+        let (content, website, cur_link) = (
+            content.to_string(),
+            website.to_string(),
+            cur_link.to_string(),
+        );
+        blocking_ok(move || image_sources(&content, &website, &cur_link)).await?
+    };
+    for (url, full_uri) in sources {
+        if let Ok(image) = convert_image(client, &full_uri).await {
+            result = result.replace(&url, &image);
+        }
+    }
+    Ok(result)
+}
+
+async fn fetch_page(
+    client: &reqwest::Client,
+    url: &str,
+    rules: &ExtractRules,
+) -> Result<String, FetchError> {
     println!("fetch_page: {:?}", url);
-    let resp = reqwest::blocking::get(url)?;
-    let res = resp.text()?;
-    let document = Html::parse_document(&res);
-    let article = extract(&document, "article");
-    if let Some(cont) = article {
-        Ok(cont)
+    let resp = client
+        .get(url)
+        .timeout(Duration::from_secs(FETCH_PAGE_TIMEOUT_SECS))
+        .send()
+        .await?;
+    let res = resp.error_for_status()?.text().await?;
+    let rules = rules.clone();
+    blocking_ok(move || extract_page(&res, &rules)).await
+}
+
+// the content selector of the feed wins, the guessed main content is the fallback
+fn extract_page(page: &str, rules: &ExtractRules) -> String {
+    let document = Html::parse_document(page);
+    let content = rules
+        .content_selector
+        .as_deref()
+        .and_then(|selector| extract(&document, selector))
+        .or_else(|| main_content(&document))
+        .unwrap_or_else(|| page.to_string());
+    match &rules.strip {
+        Some(strip) => remove_elements(&content, strip),
+        None => remove_elements(&content, DEFAULT_STRIP),
+    }
+}
+
+/// Check that every selector of the rules parses, the error names the first one that doesn't
+pub fn check_rules(rules: &ExtractRules) -> Result<(), String> {
+    let selectors = rules
+        .content_selector
+        .iter()
+        .chain(rules.strip.iter().flatten());
+    for selector in selectors {
+        if Selector::parse(selector).is_err() {
+            return Err(format!("invalid selector: {}", selector));
+        }
+    }
+    Ok(())
+}
+
+/// Fetch a page and extract its content the way entries of a feed with these rules are
+pub async fn preview_page(url: &str, rules: &ExtractRules) -> Result<String, FetchError> {
+    let client = reqwest::Client::new();
+    let page = fetch_page(&client, url, rules).await?;
+    blocking_ok(move || sanitize(&page)).await
+}
+
+// a page with a single <article> is taken as is, others are scored paragraph by paragraph
+fn main_content(document: &Html) -> Option<String> {
+    let articles = Selector::parse("article").unwrap();
+    if document.select(&articles).count() == 1 {
+        if let Some(article) = extract(document, "article") {
+            return Some(article);
+        }
+    }
+    readability::extract_content(document).or_else(|| extract(document, "body"))
+}
+
+// types of `<link rel="alternate">` pointing to a feed
+static FEED_TYPES: &[&str] = &[
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/feed+json",
+    "application/json",
+];
+// where feeds often are when a page doesn't link them
+static FEED_PATHS: &[&str] = &[
+    "/feed",
+    "/rss",
+    "/feed.xml",
+    "/rss.xml",
+    "/atom.xml",
+    "/index.xml",
+    "/feed.json",
+];
+
+/// A feed found for a website
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FeedCandidate {
+    pub url: String,
+    pub title: String,
+}
+
+// feeds a page links to, resolved against the page url or its `<base>`
+fn alternate_links(page: &str, base: &Url) -> Vec<String> {
+    let document = Html::parse_document(page);
+    let base = Selector::parse("base[href]")
+        .ok()
+        .and_then(|select| document.select(&select).next())
+        .and_then(|b| base.join(b.value().attr("href")?).ok())
+        .unwrap_or_else(|| base.clone());
+    let select = Selector::parse("link[rel][href][type]").unwrap();
+    let mut links: Vec<String> = vec![];
+    for link in document.select(&select) {
+        let value = link.value();
+        let alternate = value
+            .attr("rel")
+            .unwrap()
+            .split_whitespace()
+            .any(|rel| rel.eq_ignore_ascii_case("alternate"));
+        let kind = value.attr("type").unwrap().trim().to_lowercase();
+        if !alternate || !FEED_TYPES.contains(&kind.as_str()) {
+            continue;
+        }
+        if let Ok(url) = base.join(value.attr("href").unwrap().trim()) {
+            if !links.contains(&url.to_string()) {
+                links.push(url.to_string());
+            }
+        }
+    }
+    links
+}
+
+// the body at `url` as a candidate, when it parses as a feed
+fn parse_candidate(url: &str, body: &str) -> Option<FeedCandidate> {
+    let feed = parser::parse(body.as_bytes()).ok()?;
+    Some(FeedCandidate {
+        url: url.to_string(),
+        title: feed.title.map_or(String::new(), |t| t.content),
+    })
+}
+
+// discovery fetches urls given by users, it must not reach the server itself or its network
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local fc00::/7 and link local fe80::/10
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+// resolves like the system does, leaving out the addresses that aren't public. The connection
+// goes to the checked addresses, a second lookup can't point it elsewhere.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// ip addresses in urls don't go through the resolver
+fn check_url(url: &Url, public_only: bool) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("not a web url: {}", url).into());
+    }
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        _ => return Ok(()),
+    };
+    if public_only && !is_public(ip) {
+        return Err(format!("not a public address: {}", ip).into());
+    }
+    Ok(())
+}
+
+fn discover_client(public_only: bool) -> reqwest::Result<reqwest::Client> {
+    let redirect = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > DISCOVER_MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match check_url(attempt.url(), public_only) {
+            Ok(()) => attempt.follow(),
+            Err(e) => attempt.error(e),
+        }
+    });
+    let builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(DISCOVER_TIMEOUT_SECS))
+        .redirect(redirect);
+    if public_only {
+        // a proxy would resolve the names itself
+        builder
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+    } else {
+        builder.build()
+    }
+}
+
+// the body of a response up to `DISCOVER_MAX_BYTES`
+async fn limited_text(mut resp: reqwest::Response) -> Result<String, FetchError> {
+    let too_large = || format!("response larger than {} bytes", DISCOVER_MAX_BYTES).into();
+    if resp
+        .content_length()
+        .is_some_and(|len| len > DISCOVER_MAX_BYTES as u64)
+    {
+        return Err(too_large());
+    }
+    let mut body = vec![];
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > DISCOVER_MAX_BYTES {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+async fn fetch_text(
+    client: &reqwest::Client,
+    url: &str,
+    public_only: bool,
+) -> Result<(Url, String), FetchError> {
+    check_url(&Url::parse(url)?, public_only)?;
+    let resp = client.get(url).send().await?.error_for_status()?;
+    let final_url = resp.url().clone();
+    Ok((final_url, limited_text(resp).await?))
+}
+
+async fn check_candidate(
+    client: &reqwest::Client,
+    url: &str,
+    public_only: bool,
+) -> Option<FeedCandidate> {
+    let (final_url, body) = fetch_text(client, url, public_only).await.ok()?;
+    parse_candidate(final_url.as_str(), &body)
+}
+
+/// Find the feeds of a website: the url itself when it is a feed, else the feeds its page
+/// links to, else the ones at common paths like `/feed` or `/atom.xml`.
+/// Every candidate has been fetched and parsed as a feed, following a few redirects and up to
+/// a limited size. `public_only` keeps urls given by users of the server off its own network.
+pub async fn discover_feeds(
+    url: &str,
+    public_only: bool,
+) -> Result<Vec<FeedCandidate>, FetchError> {
+    let url = if url.contains("://") {
+        url.to_string()
     } else {
-        Ok(extract(&document, "body").unwrap_or(res.clone()))
+        format!("https://{}", url)
+    };
+    let client = discover_client(public_only)?;
+    let (base, body) = fetch_text(&client, &url, public_only).await?;
+    if let Some(feed) = parse_candidate(base.as_str(), &body) {
+        return Ok(vec![feed]);
+    }
+    let mut candidates: Vec<FeedCandidate> = vec![];
+    let mut links = alternate_links(&body, &base);
+    if links.is_empty() {
+        links = FEED_PATHS
+            .iter()
+            .filter_map(|path| base.join(path).ok())
+            .map(|url| url.to_string())
+            .collect();
+    }
+    for link in links {
+        if let Some(feed) = check_candidate(&client, &link, public_only).await {
+            // `/feed` and `/rss` often redirect to one of the others
+            if candidates.iter().all(|c| c.url != feed.url) {
+                candidates.push(feed);
+            }
+        }
     }
+    Ok(candidates)
 }
 
-fn first_link(links: &Vec<Link>) -> String {
+fn first_link(links: &[Link]) -> String {
     links
         .iter()
         .find(|l| Url::parse(&l.href).is_ok())
         .map_or("".to_owned(), |l| l.href.to_string())
 }
 
-pub(crate) fn fetch_feed(feed: &str, force: bool) -> Result<i32, Box<dyn Error>> {
+// build If-None-Match / If-Modified-Since from the validators of the last response
+fn conditional_headers(cache: &FeedCache) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(etag) = cache
+        .etag
+        .as_ref()
+        .and_then(|v| HeaderValue::from_str(v).ok())
+    {
+        headers.insert(IF_NONE_MATCH, etag);
+    }
+    if let Some(modified) = cache
+        .last_modified
+        .as_ref()
+        .and_then(|v| HeaderValue::from_str(v).ok())
+    {
+        headers.insert(IF_MODIFIED_SINCE, modified);
+    }
+    headers
+}
+
+fn response_cache(headers: &HeaderMap) -> FeedCache {
+    let value = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    FeedCache {
+        etag: value(ETAG),
+        last_modified: value(LAST_MODIFIED),
+    }
+}
+
+/// What a successful fetch of a feed found
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct FetchReport {
+    pub http_status: u16,
+    pub items_found: usize,
+    pub items_new: usize,
+}
+
+// the feed parsed, with its metadata stored and the rules for its entries
+fn parse_feed(feed: &str, body: &str) -> Result<(feed_rs::model::Feed, ExtractRules), FetchError> {
+    let feed_resp = parser::parse(body.as_bytes())?;
+    let website = first_link(&feed_resp.links);
+    let feed_title = feed_resp
+        .title
+        .as_ref()
+        .map_or(String::new(), |t| t.content.clone());
+    update_feed_info(feed, &feed_title, &website)?;
+    let ttl = feed_resp
+        .ttl
+        .map(i64::from)
+        .or_else(|| schedule::syndication_ttl(body));
+    update_feed_ttl(feed, ttl)?;
+    Ok((feed_resp, query_feed_rules(feed)))
+}
+
+// sanitize the content of a page and store it, false when nothing is left of it
+fn store_page(page: &Page, content: &str) -> rusqlite::Result<bool> {
+    let content = sanitize(content);
+    if content.is_empty() {
+        return Ok(false);
+    }
+    dump_new_page(page)?;
+    save_page_content(&page.link, &content)?;
+    index_page(&page.link, &page.title, &content)?;
+    Ok(true)
+}
+
+pub(crate) async fn fetch_feed(
+    client: &reqwest::Client,
+    feed: &str,
+    force: bool,
+) -> Result<FetchReport, FetchError> {
     println!("fetch_feed: {:?}", feed);
-    let resp = reqwest::blocking::get(feed);
-    let body = resp?.text();
-    let feed_resp = parser::parse(body?.as_bytes())?;
+    let mut req = client.get(feed);
+    if !force {
+        let url = feed.to_string();
+        if let Some(cache) = blocking_ok(move || query_feed_cache(&url)).await? {
+            req = req.headers(conditional_headers(&cache));
+        }
+    }
+    let resp = req.send().await?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        println!("not modified: {:?}", feed);
+        return Ok(FetchReport {
+            http_status: StatusCode::NOT_MODIFIED.as_u16(),
+            ..FetchReport::default()
+        });
+    }
+    let http_status = resp.status().as_u16();
+    let cache = response_cache(resp.headers());
+    let body = resp.error_for_status()?.text().await?;
+    let url = feed.to_string();
+    let (feed_resp, rules) = blocking(move || parse_feed(&url, &body)).await?;
     let website = first_link(&feed_resp.links);
+    let items_found = feed_resp.entries.len();
     let mut succ_count = 0;
     for entry in feed_resp.entries {
         if entry.title.is_none() {
             continue;
         }
-        let entry_title = entry.title.unwrap().content.replace("/", "|");
+        let entry_title = entry.title.unwrap().content;
         let published_time = entry
             .published
             .unwrap_or(entry.updated.unwrap_or(Utc::now()));
 
         let link = first_link(&entry.links);
         println!("link: {}", link);
-        let prev = query_page_link(&link);
-        let page_exist = prev.is_some();
-        if page_exist && !force {
+        let url = link.clone();
+        if !force && blocking(move || page_exists(&url)).await? {
             continue;
         }
-        let mut content = if let Some(ct) = entry.content {
-            ct.body.unwrap()
+        let body = entry.content.and_then(|ct| ct.body);
+        let mut content = if let Some(body) = body.filter(|_| !rules.full_page) {
+            // the built-in strip list is only meant for whole pages
+            match &rules.strip {
+                Some(strip) => {
+                    let strip = strip.clone();
+                    blocking_ok(move || remove_elements(&body, &strip)).await?
+                }
+                None => body,
+            }
         } else {
             let descrption = if let Some(desc) = entry.summary {
                 desc.content
@@ -172,15 +658,23 @@ pub(crate) fn fetch_feed(feed: &str, force: bool) -> Result<i32, Box<dyn Error>>
                 String::from("")
             };
 
-            let page = {
-                let page = fetch_page(&link)?;
-                let keywords = vec!["footer", "header", "script", "style", "comments"];
-                remove_elements(&page, &keywords)
+            // one page failing doesn't cost the other entries of the feed
+            let page = match fetch_page(client, &link, &rules).await {
+                Ok(page) => page,
+                Err(e) if !descrption.is_empty() => {
+                    eprintln!("failed to fetch {}, keeping the description: {}", link, e);
+                    descrption.clone()
+                }
+                Err(e) => {
+                    eprintln!("failed to fetch {}, skipped: {}", link, e);
+                    continue;
+                }
             };
 
             // We need to guess whether the descrption is only a summary
             // If page contains multimedia, return the page
-            if (page.len() > descrption.len() * 2)
+            if rules.full_page
+                || (page.len() > descrption.len() * 2)
                 || (page.contains(&descrption))
                 || (page.contains("<audio") || page.contains("<video"))
                 || page.contains("<code>")
@@ -191,37 +685,218 @@ pub(crate) fn fetch_feed(feed: &str, force: bool) -> Result<i32, Box<dyn Error>>
             }
         };
 
-        content = preprocess_image(&content, &website, &link)?;
+        content = preprocess_image(client, &content, &website, &link).await?;
         let page = Page {
+            id: 0,
             link: link.clone(),
             website: website.clone(),
-            publish_datetime: published_time.to_string(),
+            published_at: published_time.timestamp(),
+            fetched_at: Utc::now().timestamp(),
             title: entry_title.clone(),
             readed: false,
+            starred: false,
             source: feed.to_string(),
         };
 
-        if content.len() > 0 {
-            let path = format!("./pages/{}.html", entry_title);
-            fs::write(&path, &content)?;
-            dump_new_page(&page)?;
+        if blocking(move || store_page(&page, &content)).await? {
             succ_count += 1;
         } else {
             println!("error: {}", entry_title);
         }
     }
-    Ok(succ_count)
+    // only remember the validators once every entry is stored,
+    // otherwise a failed run would be skipped as not modified
+    let url = feed.to_string();
+    blocking(move || update_feed_cache(&url, &cache)).await?;
+    Ok(FetchReport {
+        http_status,
+        items_found,
+        items_new: succ_count,
+    })
+}
+
+fn feed_host(feed: &str) -> String {
+    Url::parse(feed)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .unwrap_or_default()
+}
+
+// fetch a feed within its time budget, then record how it went and when it's due again
+// returns the error of the fetch, it is also in the fetch log
+async fn fetch_and_record(
+    client: &reqwest::Client,
+    feed: &str,
+    force: bool,
+    opts: &FetchOptions,
+) -> Option<String> {
+    let started = Instant::now();
+    let fetched_at = Utc::now().timestamp();
+    let res = tokio::time::timeout(opts.timeout, fetch_feed(client, feed, force)).await;
+    let mut log = FetchLog {
+        fetched_at,
+        duration_ms: started.elapsed().as_millis() as i64,
+        ..FetchLog::default()
+    };
+    match res {
+        Ok(Ok(report)) => {
+            log.http_status = Some(report.http_status);
+            log.items_found = report.items_found as i64;
+            log.items_new = report.items_new as i64;
+        }
+        // failures past the response, like a feed not parsing, carry no status
+        Ok(Err(e)) => {
+            log.http_status = e
+                .downcast_ref::<reqwest::Error>()
+                .and_then(|e| e.status())
+                .map(|s| s.as_u16());
+            log.error = Some(e.to_string());
+        }
+        Err(_) => log.error = Some(format!("timeout after {:?}", opts.timeout)),
+    };
+    println!(
+        "feed: {:?} res: {:?}",
+        feed,
+        log.error.as_deref().unwrap_or("ok")
+    );
+    let url = feed.to_string();
+    let default_interval = opts.interval.as_secs() as i64;
+    let error = log.error.clone();
+    let recorded = blocking(move || {
+        let state = query_fetch_state(&url);
+        let recent = recent_published(&url, schedule::RECENT_PAGES).unwrap_or_default();
+        let interval = schedule::interval(state.interval, state.ttl, &recent, default_interval);
+        let wait = match log.error {
+            Some(_) => schedule::backoff(interval, state.failures + 1),
+            None => interval,
+        };
+        let next_fetch_at = Utc::now().timestamp() + wait;
+        record_fetch(&url, &log, next_fetch_at)
+    })
+    .await;
+    if let Err(e) = recorded {
+        eprintln!("failed to record fetch of {}: {}", feed, e);
+    }
+    error
 }
 
-pub fn update_rss(feed: Option<&str>, force: bool) -> Result<(), Box<dyn Error>> {
+// every feed runs in its own task, so a slow or failing feed only
+// holds its own permits and never blocks the others
+struct FetchLimits {
+    jobs: Arc<Semaphore>,
+    host_jobs: usize,
+    hosts: HashMap<String, Arc<Semaphore>>,
+}
+
+impl FetchLimits {
+    fn new(opts: &FetchOptions) -> Self {
+        FetchLimits {
+            jobs: Arc::new(Semaphore::new(opts.jobs.max(1))),
+            host_jobs: opts.host_jobs.max(1),
+            hosts: HashMap::new(),
+        }
+    }
+
+    fn spawn(
+        &mut self,
+        client: &reqwest::Client,
+        feed: String,
+        force: bool,
+        opts: &FetchOptions,
+    ) -> JoinHandle<String> {
+        let per_host = self.host_jobs;
+        let host_jobs = self
+            .hosts
+            .entry(feed_host(&feed))
+            .or_insert_with(|| Arc::new(Semaphore::new(per_host)))
+            .clone();
+        let jobs = self.jobs.clone();
+        let client = client.clone();
+        let opts = *opts;
+        tokio::spawn(async move {
+            let _host_permit = host_jobs.acquire_owned().await;
+            let _permit = jobs.acquire_owned().await;
+            fetch_and_record(&client, &feed, force, &opts).await;
+            feed
+        })
+    }
+}
+
+/// Fetch every enabled feed when it's due, for ever. A feed is due after an interval
+/// following how often it posts and its ttl, or the one set for it, failing feeds wait longer
+/// after every failure.
+pub async fn run_scheduler(opts: FetchOptions) {
+    let client = reqwest::Client::new();
+    let mut limits = FetchLimits::new(&opts);
+    let mut running = HashSet::new();
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let mut cleaned_at = 0;
+    loop {
+        while let Ok(feed) = done_rx.try_recv() {
+            running.remove(&feed);
+        }
+        let now = Utc::now().timestamp();
+        let due = blocking(move || due_feeds(now)).await.unwrap_or_else(|e| {
+            eprintln!("failed to query due feeds: {}", e);
+            vec![]
+        });
+        for feed in due {
+            if !running.insert(feed.clone()) {
+                continue;
+            }
+            let task = limits.spawn(&client, feed.clone(), false, &opts);
+            let done_tx = done_tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = task.await {
+                    eprintln!("fetch task panicked: {:?}", e);
+                }
+                let _ = done_tx.send(feed);
+            });
+        }
+        // the loop wakes up for every due feed, the cleanup doesn't need to run that often
+        if now - cleaned_at >= CLEANUP_INTERVAL_SECS {
+            if let Err(e) = blocking(cleanup_pages).await {
+                eprintln!("failed to clean up pages: {}", e);
+            }
+            cleaned_at = now;
+        }
+        // look again when the next feed is due, a finished fetch or a new feed may change that
+        let next = blocking(move || next_due_at(now))
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(now + SCHEDULER_TICK_SECS);
+        let wait = Duration::from_secs((next - now).clamp(1, SCHEDULER_TICK_SECS) as u64);
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            Some(feed) = done_rx.recv() => {
+                running.remove(&feed);
+            }
+        }
+    }
+}
+
+pub async fn update_rss(
+    feed: Option<&str>,
+    force: bool,
+    opts: &FetchOptions,
+) -> Result<(), FetchError> {
     init_db(None)?;
-    let feeds = all_feeds();
+    let client = reqwest::Client::new();
     if let Some(f) = feed {
-        let _ = fetch_feed(f, true)?;
+        if let Some(e) = fetch_and_record(&client, f, true, opts).await {
+            return Err(e.into());
+        }
     } else {
-        for feed in feeds.iter() {
-            let res = fetch_feed(&feed, force);
-            println!("feed: {:?} res: {:?}", feed, res);
+        let mut limits = FetchLimits::new(opts);
+        let tasks = all_feeds()
+            .into_iter()
+            .map(|feed| limits.spawn(&client, feed, force, opts))
+            .collect::<Vec<_>>();
+        for task in tasks {
+            if let Err(e) = task.await {
+                eprintln!("fetch task panicked: {:?}", e);
+            }
         }
     }
 
@@ -232,9 +907,11 @@ pub fn update_rss(feed: Option<&str>, force: bool) -> Result<(), Box<dyn Error>>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::default_user;
+    use crate::testing::{self, TestDir};
 
     #[test]
-    fn test_url_base() -> Result<(), Box<dyn Error>> {
+    fn test_url_base() -> Result<(), FetchError> {
         assert!(gen_image_name("http://abc/d/x/demo.png")?.ends_with(".png"));
         assert!(gen_image_name("http://abc/d/x/demo.png?ab=1&c=3")?.ends_with(".png"));
         assert!(gen_image_name("https://a/demo.png?ab=1&c=3")?.ends_with(".png"));
@@ -252,7 +929,7 @@ mod tests {
         <article>Hello, world!</article>
         <h1 class="foo">Hello, <i>world!</i></h1>
     "#;
-        let document = Html::parse_document(&html);
+        let document = Html::parse_document(html);
         let article = extract(&document, "article");
         assert!(article.is_some());
         assert_eq!(article.unwrap(), "<article>Hello, world!</article>");
@@ -268,12 +945,186 @@ mod tests {
         <h1 class="foo">Hello, <i>world!</i></h1>
         </body>
     "#;
-        let document = Html::parse_document(&html);
+        let document = Html::parse_document(html);
         let article = extract(&document, "article");
         assert!(article.is_some());
         assert_eq!(article.unwrap(), "<article>Hello, world!</article>");
     }
 
+    #[test]
+    fn test_main_content() {
+        let single = "<body><nav>menu</nav><article>Hello, world!</article></body>";
+        assert_eq!(
+            main_content(&Html::parse_document(single)).unwrap(),
+            "<article>Hello, world!</article>"
+        );
+        let body = "<body><p>short</p></body>";
+        assert_eq!(
+            main_content(&Html::parse_document(body)).unwrap(),
+            "<body><p>short</p></body>"
+        );
+        let paragraph = "Some text long enough to count as a paragraph, with a comma or two, ";
+        let listing = format!(
+            "<body><article>teaser</article><article>teaser</article>\
+             <div class=\"post\"><p>{0}</p><p>{0}</p><p>{0}</p></div>\
+             <div class=\"sidebar\"><p>{0}</p></div></body>",
+            paragraph
+        );
+        let content = main_content(&Html::parse_document(&listing)).unwrap();
+        assert!(content.starts_with("<div class=\"post\">"));
+        assert!(!content.contains("teaser"));
+    }
+
+    #[test]
+    fn test_extract_page() {
+        let page = "<body><header>site</header><div class=\"entry\"><p>text</p>\
+                    <div class=\"share\">share</div><script>x()</script></div></body>";
+        let mut rules = ExtractRules {
+            content_selector: Some("div.entry".into()),
+            ..ExtractRules::default()
+        };
+        let content = extract_page(page, &rules);
+        assert!(content.contains("<p>text</p>"));
+        assert!(content.contains("share"));
+        assert!(!content.contains("site"));
+        assert!(!content.contains("<script>"));
+
+        rules.strip = Some(vec![".share".into()]);
+        let content = extract_page(page, &rules);
+        assert!(!content.contains("share"));
+        assert!(content.contains("<script>"));
+
+        // a selector matching nothing falls back to the guessed content
+        rules.content_selector = Some("article".into());
+        assert!(extract_page(page, &rules).contains("<p>text</p>"));
+    }
+
+    #[test]
+    fn test_check_rules() {
+        assert!(check_rules(&ExtractRules::default()).is_ok());
+        let rules = ExtractRules {
+            content_selector: Some("main > .post".into()),
+            strip: Some(vec!["div[".into()]),
+            full_page: true,
+        };
+        assert_eq!(check_rules(&rules).unwrap_err(), "invalid selector: div[");
+    }
+
+    #[test]
+    fn test_alternate_links() {
+        let base = Url::parse("https://blog.example.com/posts/").unwrap();
+        let page = r#"<head>
+            <link rel="alternate" type="application/rss+xml" href="/feed.xml">
+            <link rel="Alternate" type="application/atom+xml" href="atom.xml">
+            <link rel="alternate" type="application/rss+xml" href="https://blog.example.com/feed.xml">
+            <link rel="alternate" type="text/html" hreflang="fr" href="/fr/">
+            <link rel="stylesheet" type="text/css" href="/style.css">
+            <link rel="alternate" type="application/feed+json" href="https://cdn.example.com/feed.json">
+            </head>"#;
+        assert_eq!(
+            alternate_links(page, &base),
+            vec![
+                "https://blog.example.com/feed.xml",
+                "https://blog.example.com/posts/atom.xml",
+                "https://cdn.example.com/feed.json",
+            ]
+        );
+        let page = r#"<head><base href="https://example.com/blog/">
+            <link rel="alternate" type="application/rss+xml" href="rss"></head>"#;
+        assert_eq!(
+            alternate_links(page, &base),
+            vec!["https://example.com/blog/rss"]
+        );
+    }
+
+    #[test]
+    fn test_public_addresses() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1::248",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(public(ip), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discover_feeds() -> Result<(), FetchError> {
+        use warp::Filter;
+
+        let rss = r#"<?xml version="1.0"?><rss version="2.0"><channel><title>Posts</title>
+            <link>http://example.com</link><description>d</description></channel></rss>"#;
+        let atom = r#"<?xml version="1.0"?><feed xmlns="http://www.w3.org/2005/Atom">
+            <title>Atom posts</title><id>x</id><updated>2022-01-01T00:00:00Z</updated></feed>"#;
+        let linked = r#"<html><head>
+            <link rel="alternate" type="application/rss+xml" href="/posts.rss">
+            <link rel="alternate" type="application/rss+xml" href="/missing.rss">
+            </head></html>"#;
+        let site = warp::path!("linked").map(move || warp::reply::html(linked));
+        let feed = warp::path!("posts.rss").map(move || rss);
+        let plain = warp::path!("plain").map(|| warp::reply::html("<p>no links</p>"));
+        let common = warp::path!("atom.xml").map(move || atom);
+        let redirect = warp::path!("redirect" / usize).map(|n: usize| {
+            let next = format!("/redirect/{}", n + 1);
+            warp::redirect::found(next.parse::<warp::http::Uri>().unwrap())
+        });
+        let large = warp::path!("large").map(|| "x".repeat(DISCOVER_MAX_BYTES + 1));
+        let routes = site.or(feed).or(plain).or(common).or(redirect).or(large);
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = |path: &str| format!("http://{}{}", addr, path);
+
+        let found = discover_feeds(&url("/linked"), false).await?;
+        assert_eq!(
+            found,
+            vec![FeedCandidate {
+                url: url("/posts.rss"),
+                title: "Posts".to_string()
+            }]
+        );
+        // a feed url is its own candidate
+        assert_eq!(discover_feeds(&url("/posts.rss"), false).await?, found);
+        // pages without links get their common paths tried
+        let found = discover_feeds(&url("/plain"), false).await?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].url, url("/atom.xml"));
+        assert_eq!(found[0].title, "Atom posts");
+        assert!(discover_feeds(&url("/nothing"), false).await.is_err());
+
+        // the server's own network is off limits for its users
+        let err = discover_feeds(&url("/posts.rss"), true).await.unwrap_err();
+        assert!(err.to_string().contains("not a public address"));
+        let local = format!("http://localhost:{}/posts.rss", addr.port());
+        let err = discover_feeds(&local, true).await.unwrap_err();
+        assert!(format!("{:?}", err).contains("no public address"));
+        assert!(discover_feeds("file:///etc/passwd", false).await.is_err());
+        // redirects and sizes are limited
+        let err = discover_feeds(&url("/redirect/0"), false)
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("too many redirects"));
+        let err = discover_feeds(&url("/large"), false).await.unwrap_err();
+        assert!(err.to_string().contains("larger than"));
+        Ok(())
+    }
+
     #[test]
     fn test_remove_element() {
         let html = r#"
@@ -285,7 +1136,7 @@ mod tests {
         <h1 class="foo">Hello, <i>world!</i></h1>
         </body>
     "#;
-        let res = remove_elements(html, &vec!["footer", "comments"]);
+        let res = remove_elements(html, &["footer", "comments"]);
         assert!(!res.contains("<footer>"));
         assert!(!res.contains("<comments>"));
     }
@@ -299,26 +1150,49 @@ mod tests {
         <article>Hello, world now!</article>
         <h1 class="foo">Hello, <i>world!</i></h1>
     "#;
-        let document = Html::parse_document(&html);
+        let document = Html::parse_document(html);
         let article = extract(&document, "article");
-        assert!(!article.is_none());
+        assert!(article.is_some());
         assert_eq!(article.unwrap(), "<article>Hello, world now!</article>");
     }
 
-    #[test]
-    fn test_process_image() -> Result<(), Box<dyn Error>> {
+    #[tokio::test]
+    async fn test_process_image() -> Result<(), FetchError> {
+        let _dir = TestDir::empty();
         let img = "https://coderscat.com/css/images/logo.png";
         let html = format!(
             "<img src=\"{}\" alt=\"moores-law\" style=\"width: 50%; height: 100%;\">",
             img
         );
-        let processed = preprocess_image(&html, "", "")?;
+        let client = reqwest::Client::new();
+        let processed = preprocess_image(&client, &html, "", "").await?;
         assert!(processed.find(".png").is_some());
         Ok(())
     }
 
-    #[test]
-    fn test_preprocess_image() -> Result<(), Box<dyn Error>> {
+    #[tokio::test]
+    async fn test_reuse_image() -> Result<(), FetchError> {
+        let _dir = TestDir::empty();
+        // an image downloaded long ago is not fetched again, it is recent for the purge
+        let uri = "http://unreachable.invalid/logo.png";
+        let path = Path::new(&image_dir()).join(gen_image_name(uri)?);
+        fs::create_dir_all(image_dir())?;
+        fs::write(&path, "image")?;
+        let old = SystemTime::now() - Duration::from_secs(RECENT_IMAGE_SECS + 10);
+        fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(old)?;
+        let local = convert_image(&reqwest::Client::new(), uri).await?;
+        assert!(local.starts_with(IMAGE_URL_PREFIX));
+        let modified = fs::metadata(&path)?.modified()?;
+        assert!(modified > old + Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_preprocess_image() -> Result<(), FetchError> {
+        let _dir = TestDir::empty();
         let content = r#"
         <!DOCTYPE html>
         <meta charset="utf-8">
@@ -328,40 +1202,283 @@ mod tests {
         <h1 class="foo">Hello, <i>world!</i></h1>
         "#;
 
-        let res = preprocess_image(content, "http://demo.com", "")?;
+        let client = reqwest::Client::new();
+        let res = preprocess_image(&client, content, "http://demo.com", "").await?;
         assert!(res.contains(".png"));
         Ok(())
     }
 
-    #[test]
-    fn test_fetch_page() {
+    #[tokio::test]
+    async fn test_fetch_page() {
         let url = "https://blog.janestreet.com/ocaml-4-03-everything-else/";
-        let content = fetch_page(url).unwrap();
+        let client = reqwest::Client::new();
+        let content = fetch_page(&client, url, &ExtractRules::default())
+            .await
+            .unwrap();
         assert!(!content.contains("<body>"));
     }
 
-    #[test]
-    fn test_fetch_page_with_image() {
+    #[tokio::test]
+    async fn test_fetch_page_with_image() {
+        let _dir = TestDir::empty();
         let url = "https://flaviocopes.com/macos-terminal-setup/";
-        let content = fetch_page(url).unwrap();
+        let client = reqwest::Client::new();
+        let content = fetch_page(&client, url, &ExtractRules::default())
+            .await
+            .unwrap();
         assert!(content.contains(".png"));
-        let res = preprocess_image(&content, "https://flaviocopes.com", url);
+        let res = preprocess_image(&client, &content, "https://flaviocopes.com", url).await;
         assert!(res.is_ok());
     }
 
-    #[test]
-    fn test_fetch_page_images() -> Result<(), Box<dyn Error>> {
+    #[tokio::test]
+    async fn test_fetch_page_images() -> Result<(), FetchError> {
+        let _dir = TestDir::empty();
         let uri = "https://yihui.org/cn/2020/07/wild-onion/";
-        let mut content = fetch_page(uri)?;
-        content = preprocess_image(&content, uri, "")?;
-        fs::write("/tmp/tmp.html", &content)?;
+        let client = reqwest::Client::new();
+        let mut content = fetch_page(&client, uri, &ExtractRules::default()).await?;
+        content = preprocess_image(&client, &content, uri, "").await?;
         assert!(content.contains("/images/"));
         Ok(())
     }
 
     #[test]
-    fn test_fetch_feed() {
-        let res = fetch_feed("http://chenyukang.github.io/atom.xml", true);
+    fn test_conditional_headers() {
+        assert!(conditional_headers(&FeedCache::default()).is_empty());
+
+        let cache = FeedCache {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+        let headers = conditional_headers(&cache);
+        assert_eq!(headers.get(IF_NONE_MATCH).unwrap(), "\"abc\"");
+        assert_eq!(
+            headers.get(IF_MODIFIED_SINCE).unwrap(),
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+
+        let mut resp = HeaderMap::new();
+        resp.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        resp.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(response_cache(&resp), cache);
+    }
+
+    #[test]
+    fn test_feed_cache() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        assert!(query_feed_cache("feed").is_none());
+
+        let cache = FeedCache {
+            etag: Some("W/\"123\"".to_string()),
+            last_modified: None,
+        };
+        update_feed_cache("feed", &cache)?;
+        assert_eq!(query_feed_cache("feed"), Some(cache));
+
+        let cache = FeedCache {
+            etag: None,
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+        update_feed_cache("feed", &cache)?;
+        assert_eq!(query_feed_cache("feed"), Some(cache));
+        Ok(())
+    }
+
+    #[test]
+    fn test_feed_host() {
+        assert_eq!(
+            feed_host("https://blog.rust-lang.org/feed.xml"),
+            "blog.rust-lang.org"
+        );
+        assert_eq!(feed_host("http://abc.com:8080/atom.xml"), "abc.com");
+        assert_eq!(feed_host("not a url"), "");
+    }
+
+    // a local server counting the feeds fetched at the same time, in all and per host
+    fn feed_server(delay: Duration) -> (std::net::SocketAddr, Arc<std::sync::Mutex<FetchCounts>>) {
+        use warp::Filter;
+
+        let counts = Arc::new(std::sync::Mutex::new(FetchCounts::default()));
+        let state = counts.clone();
+        let feed = warp::path!("feed" / usize)
+            .and(warp::header::<String>("host"))
+            .and_then(move |_: usize, host: String| {
+                let counts = state.clone();
+                async move {
+                    let host = host.split(':').next().unwrap_or_default().to_string();
+                    counts.lock().unwrap().start(&host);
+                    tokio::time::sleep(delay).await;
+                    counts.lock().unwrap().finish(&host);
+                    let rss = r#"<?xml version="1.0"?><rss version="2.0"><channel>
+                        <title>t</title><link>http://example.com</link>
+                        <description>d</description></channel></rss>"#;
+                    Ok::<_, warp::Rejection>(rss)
+                }
+            });
+        // never answers
+        let stalled = warp::path!("stalled")
+            .and_then(|| async { std::future::pending::<Result<&str, warp::Rejection>>().await });
+        let broken = warp::path!("broken").map(|| {
+            warp::reply::with_status("oops", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+        });
+        // entries without content, their pages are broken
+        let entries = warp::path!("entries")
+            .and(warp::header::<String>("host"))
+            .map(|host: String| {
+                format!(
+                    r#"<?xml version="1.0"?><rss version="2.0"><channel>
+                    <title>t</title><link>http://example.com</link><description>d</description>
+                    <item><title>no summary</title><link>http://{0}/broken?b</link></item>
+                    <item><title>summary</title><link>http://{0}/broken?a</link>
+                        <description>&lt;p&gt;summary a&lt;/p&gt;</description></item>
+                    </channel></rss>"#,
+                    host
+                )
+            });
+        let (addr, server) = warp::serve(feed.or(stalled).or(broken).or(entries))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, counts)
+    }
+
+    #[derive(Default)]
+    struct FetchCounts {
+        running: usize,
+        max_running: usize,
+        hosts: HashMap<String, usize>,
+        max_hosts: HashMap<String, usize>,
+        done: usize,
+    }
+
+    impl FetchCounts {
+        fn start(&mut self, host: &str) {
+            self.running += 1;
+            self.max_running = self.max_running.max(self.running);
+            let running = self.hosts.entry(host.to_string()).or_default();
+            *running += 1;
+            let max = self.max_hosts.entry(host.to_string()).or_default();
+            *max = (*max).max(*running);
+        }
+
+        fn finish(&mut self, host: &str) {
+            self.running -= 1;
+            *self.hosts.get_mut(host).unwrap() -= 1;
+            self.done += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_limits() -> Result<(), FetchError> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        let (addr, counts) = feed_server(Duration::from_millis(200));
+        // the same server under two host names
+        for i in 0..4 {
+            add_feed(
+                user,
+                &format!("http://127.0.0.1:{}/feed/{}", addr.port(), i),
+            )?;
+            add_feed(
+                user,
+                &format!("http://localhost:{}/feed/{}", addr.port(), i),
+            )?;
+        }
+        let opts = FetchOptions {
+            jobs: 3,
+            host_jobs: 2,
+            ..FetchOptions::default()
+        };
+        update_rss(None, true, &opts).await?;
+
+        let counts = counts.lock().unwrap();
+        assert_eq!(counts.done, 8);
+        assert_eq!(counts.max_running, 3);
+        assert_eq!(counts.max_hosts["127.0.0.1"], 2);
+        assert_eq!(counts.max_hosts["localhost"], 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_stalled_feed() -> Result<(), FetchError> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        let (addr, counts) = feed_server(Duration::from_millis(10));
+        let url = |path: &str| format!("http://127.0.0.1:{}{}", addr.port(), path);
+        add_feed(user, &url("/stalled"))?;
+        add_feed(user, &url("/broken"))?;
+        for i in 0..4 {
+            add_feed(user, &url(&format!("/feed/{}", i)))?;
+        }
+        let opts = FetchOptions {
+            jobs: 2,
+            host_jobs: 2,
+            timeout: Duration::from_secs(3),
+            ..FetchOptions::default()
+        };
+        let started = Instant::now();
+        let refresh = tokio::spawn(async move { update_rss(None, true, &opts).await.is_ok() });
+        // the other feeds go through while the stalled one holds its permit
+        while counts.lock().unwrap().done < 4 {
+            assert!(started.elapsed() < Duration::from_secs(2));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(refresh.await?);
+
+        let feeds = query_feeds(user)?;
+        let status = |path: &str| {
+            let feed = feeds.iter().find(|f| f.url == url(path)).unwrap();
+            query_fetch_log(feed.id, 1).unwrap().remove(0)
+        };
+        assert!(status("/stalled").error.unwrap().starts_with("timeout"));
+        assert_eq!(status("/broken").http_status, Some(500));
+        assert!(status("/broken").error.is_some());
+        for i in 0..4 {
+            let log = status(&format!("/feed/{}", i));
+            assert_eq!(log.http_status, Some(200));
+            assert!(log.error.is_none());
+        }
+
+        // a single feed is recorded too
+        update_rss(Some(&url("/feed/0")), true, &opts).await?;
+        assert!(update_rss(Some(&url("/broken")), true, &opts)
+            .await
+            .is_err());
+        let feeds = query_feeds(user)?;
+        let log = |path: &str| {
+            let feed = feeds.iter().find(|f| f.url == url(path)).unwrap();
+            query_fetch_log(feed.id, 10).unwrap()
+        };
+        assert_eq!(log("/feed/0").len(), 2);
+        assert_eq!(log("/broken").len(), 2);
+        assert_eq!(log("/broken")[0].http_status, Some(500));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_broken_pages() -> Result<(), FetchError> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        let (addr, _) = feed_server(Duration::from_millis(10));
+        let url = |path: &str| format!("http://127.0.0.1:{}{}", addr.port(), path);
+        add_feed(user, &url("/entries"))?;
+        let report = fetch_feed(&reqwest::Client::new(), &url("/entries"), true).await?;
+        assert_eq!(report.items_found, 2);
+        assert_eq!(report.items_new, 1);
+        // without a description there is nothing to store
+        assert!(query_page_link(user, &url("/broken?b"))?.is_none());
+        let page = query_page_link(user, &url("/broken?a"))?.unwrap();
+        assert_eq!(query_page_content(page.id)?.unwrap(), "<p>summary a</p>");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_feed() {
+        let _dir = TestDir::new().unwrap();
+        let client = reqwest::Client::new();
+        let res = fetch_feed(&client, "http://chenyukang.github.io/atom.xml", true).await;
         println!("res: {:?}", res);
         assert!(res.is_ok());
     }
@@ -370,20 +1487,22 @@ mod tests {
     fn test_db() -> rusqlite::Result<()> {
         use rusqlite::Connection;
 
-        let _ = fs::remove_file(PAGES_DB);
-        init_db(None)?;
-        assert!(Path::new(PAGES_DB).exists());
+        let _dir = TestDir::new()?;
+        assert!(Path::new(&pages_db()).exists());
 
-        let conn = Connection::open(&PAGES_DB)?;
+        let conn = Connection::open(pages_db())?;
+        let user = default_user(&conn)?;
+        add_feed(user, "source")?;
+        add_feed(user, "source3")?;
         conn.execute_batch(
             r#"
-        INSERT INTO pages (title, link, website, publish_datetime, readed, source)
+        INSERT INTO pages (title, link, website, published_at, fetched_at, feed_id)
         VALUES ('title',
                 'link',
                 'website',
-                'publish_time',
-                true,
-                'source');
+                0,
+                0,
+                (SELECT id FROM feeds WHERE url = 'source'));
         "#,
         )?;
 
@@ -393,18 +1512,15 @@ mod tests {
 
         let page = Page {
             title: "title_new".to_string(),
-            link: "link_new".to_string(),
-            website: "website".to_string(),
-            publish_datetime: "publish_time".to_string(),
             readed: true,
-            source: "source".to_string(),
+            ..testing::page("link_new", "source")
         };
         dump_new_page(&page)?;
 
-        let pages = query_pages(&vec![]);
+        let pages = query_pages(user, &PageFilter::new())?;
         assert_eq!(pages.len(), 2);
 
-        let page_res = query_page_link("link_new");
+        let page_res = query_page_link(user, "link_new")?;
         assert_eq!(page_res.unwrap().link, "link_new");
 
         let mut new_page = page.clone();
@@ -412,34 +1528,74 @@ mod tests {
         new_page.link = "link_3".to_string();
         dump_new_page(&new_page)?;
 
-        remove_pages_from_link("link_new")?;
-        let pages = query_pages(&vec![]);
+        remove_pages_from_link(user, "link_new")?;
+        let pages = query_pages(user, &PageFilter::new())?;
         // only the last page is lefted
         assert_eq!(pages.len(), 1);
+        assert_eq!(all_feeds(), vec!["source3".to_string()]);
         Ok(())
     }
 
     #[test]
-    fn test_update_read() -> rusqlite::Result<()> {
-        let _ = fs::remove_file(PAGES_DB);
+    fn test_feeds() -> rusqlite::Result<()> {
+        let _dir = TestDir::empty();
+        fs::write(
+            feeds_file(),
+            "http://a.com/rss\n\n  http://b.com/atom.xml \n",
+        )
+        .unwrap();
+        init_db(None)?;
+        assert!(!Path::new(&feeds_file()).exists());
+        assert_eq!(
+            all_feeds(),
+            vec!["http://a.com/rss", "http://b.com/atom.xml"]
+        );
+
+        // import only happens once for the first user, the file is kept as a backup
         init_db(None)?;
-        assert!(Path::new(PAGES_DB).exists());
+        let user = default_user(&connect()?)?;
+        assert_eq!(query_feeds(user)?.len(), 2);
+
+        set_feed_enabled(user, "http://a.com/rss", false)?;
+        assert_eq!(all_feeds(), vec!["http://b.com/atom.xml"]);
+
+        update_feed_info("http://a.com/rss", "A", "http://a.com")?;
+        record_fetch("http://a.com/rss", &FetchLog::default(), 0)?;
+        let feed = &query_feeds(user)?[0];
+        assert_eq!(feed.title, "A");
+        assert_eq!(feed.site_link, "http://a.com");
+        assert_eq!(feed.last_status, "ok");
+        assert!(!feed.enabled);
+
+        // pages of a feed unknown to the feeds table are not stored
+        let page = Page {
+            title: "title".to_string(),
+            ..testing::page("link", "http://c.com/rss")
+        };
+        dump_new_page(&page)?;
+        assert!(!page_exists("link")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_read() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        assert!(Path::new(&pages_db()).exists());
+        let user = default_user(&connect()?)?;
+        add_feed(user, "source1")?;
         let page = Page {
             title: "title1".to_string(),
-            link: "link1".to_string(),
-            website: "website".to_string(),
-            publish_datetime: "publish_time".to_string(),
-            readed: false,
-            source: "source1".to_string(),
+            ..testing::page("link1", "source1")
         };
         dump_new_page(&page)?;
 
-        let page = query_page_link("link1");
-        assert_eq!(page.unwrap().readed, false);
+        let page = query_page_link(user, "link1")?.unwrap();
+        assert!(!page.readed);
 
-        update_page_read("link1")?;
-        let page = query_page_link("link1");
-        assert_eq!(page.unwrap().readed, true);
+        set_page_read(user, page.id, true)?;
+        assert!(query_page_link(user, "link1")?.unwrap().readed);
+        set_page_read(user, page.id, false)?;
+        assert!(!query_page_link(user, "link1")?.unwrap().readed);
         Ok(())
     }
 }
@@ -1,5 +1,15 @@
 pub mod api;
+pub mod auth;
 mod conf;
-mod db;
+pub mod db;
 pub mod feed;
+mod migrations;
+pub mod opml;
+pub mod purge;
+mod readability;
+mod sanitize;
+mod schedule;
+pub mod search;
+#[cfg(test)]
+mod testing;
 pub mod utils;
@@ -1,34 +1,549 @@
-use clap::{value_parser, App};
+use chrono::prelude::*;
+use clap::{value_parser, App, ArgMatches};
 use colored::Colorize;
 use daemonize::Daemonize;
 use rss_rs::api::*;
+use rss_rs::auth;
+use rss_rs::db;
+use rss_rs::db::ExtractRules;
 use rss_rs::feed;
+use rss_rs::feed::FetchOptions;
+use rss_rs::opml;
+use rss_rs::purge::{self, Retention};
+use rss_rs::search;
 use rss_rs::utils::*;
 use std::fs::File;
 use std::path::PathBuf;
 
-fn start_auto_update_job(minutes: u64) {
-    // start a new thread to update rss priodically
-    if minutes <= 0 {
+fn start_auto_update_job(minutes: u64, opts: FetchOptions) {
+    // every feed is fetched on its own schedule, `minutes` is for feeds giving no hint
+    if minutes == 0 {
         return;
     };
 
-    tokio::spawn(async move {
-        loop {
-            let res = tokio::task::spawn_blocking(|| feed::update_rss(None, false).unwrap()).await;
-            match res {
-                Ok(_) => eprintln!("RSS updated successfully"),
-                Err(e) => eprintln!("Background task panicked: {:?}", e),
+    let opts = FetchOptions {
+        interval: std::time::Duration::from_secs(60 * minutes),
+        ..opts
+    };
+    tokio::spawn(feed::run_scheduler(opts));
+}
+
+fn start_purge_job(retention: Retention) {
+    // pages are kept forever without a retention
+    if !retention.is_set() {
+        return;
+    }
+    tokio::spawn(purge::run_purge_job(retention));
+}
+
+// subcommands act for `--user`, or for the first user when it's not given
+fn cli_user(matches: &ArgMatches) -> Result<i64, Box<dyn std::error::Error>> {
+    match matches.get_one::<String>("user") {
+        Some(name) => match auth::find_user(name)? {
+            Some(user) => Ok(user.id),
+            None => Err(format!("no user named {}", name).into()),
+        },
+        None => Ok(auth::first_user()?.id),
+    }
+}
+
+// --selector, --strip and --full-page override the given rules
+fn rules_from_args(mut rules: ExtractRules, matches: &ArgMatches) -> ExtractRules {
+    if let Some(selector) = matches.get_one::<String>("selector") {
+        rules.content_selector = Some(selector.clone());
+    }
+    if let Some(strip) = matches.get_many::<String>("strip") {
+        rules.strip = Some(strip.cloned().collect());
+    }
+    if let Some(full_page) = matches.get_one::<bool>("full-page") {
+        rules.full_page = *full_page;
+    }
+    rules
+}
+
+fn print_rules(rules: &ExtractRules) {
+    let content = rules.content_selector.as_deref().unwrap_or("(guessed)");
+    let strip = rules
+        .strip
+        .as_ref()
+        .map_or("(default)".to_string(), |s| s.join(", "));
+    println!("content:   {}", content);
+    println!("strip:     {}", strip);
+    println!("full page: {}", rules.full_page);
+}
+
+// rules and intervals are shared by all users subscribing a feed
+static ONLY_ADMINS: &str = "only admins can change the rules and interval of a feed";
+
+fn subscribed_feed(user_id: i64, url: &str) -> Result<db::Feed, Box<dyn std::error::Error>> {
+    match db::query_feeds(user_id)?.into_iter().find(|f| f.url == url) {
+        Some(feed) => Ok(feed),
+        None => Err(format!("not subscribed: {}", url).into()),
+    }
+}
+
+fn run_rules_command(user_id: i64, matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let url = matches.get_one::<String>("url").unwrap();
+    let feed = subscribed_feed(user_id, url)?;
+    let rules = if matches.is_present("reset") {
+        ExtractRules::default()
+    } else {
+        rules_from_args(feed.rules.clone(), matches)
+    };
+    if rules != feed.rules {
+        feed::check_rules(&rules)?;
+        if db::set_feed_rules(user_id, feed.id, &rules)? == 0 {
+            return Err(ONLY_ADMINS.into());
+        }
+    }
+    print_rules(&rules);
+    Ok(())
+}
+
+fn run_preview_command(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let url = matches.get_one::<String>("url").unwrap();
+    let rules = match matches.get_one::<String>("feed") {
+        Some(feed) => db::query_feed_rules(feed),
+        None => ExtractRules::default(),
+    };
+    let rules = rules_from_args(rules, matches);
+    feed::check_rules(&rules)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let content = runtime
+        .block_on(feed::preview_page(url, &rules))
+        .map_err(|e| e.to_string())?;
+    println!("{}", content);
+    Ok(())
+}
+
+fn local_time(secs: i64) -> String {
+    Utc.timestamp_opt(secs, 0)
+        .single()
+        .map_or(String::new(), |t| {
+            t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string()
+        })
+}
+
+// last success and, while a feed keeps failing, its last error
+fn fetch_status(feed: &db::Feed) -> String {
+    let fetch = &feed.fetch;
+    let next = format!("next {}", local_time(fetch.next_fetch_at)).dimmed();
+    if fetch.failures > 0 {
+        let error = format!(
+            "failed {} times, last at {}: {}",
+            fetch.failures,
+            local_time(fetch.last_error_at.unwrap_or_default()),
+            fetch.last_error.as_deref().unwrap_or_default()
+        );
+        format!("{} {}", error.red(), next)
+    } else if let Some(success) = fetch.last_success_at {
+        format!("ok {} {}", local_time(success), next)
+    } else {
+        "not fetched yet".dimmed().to_string()
+    }
+}
+
+fn feed_state(fetch: &db::FetchState) -> colored::ColoredString {
+    if fetch.broken {
+        "broken".red().bold()
+    } else if fetch.failures > 0 {
+        format!("failing {}", fetch.failures).yellow()
+    } else if fetch.last_success_at.is_some() {
+        "ok".green()
+    } else {
+        "new".dimmed()
+    }
+}
+
+fn print_feeds_status(feeds: &[db::Feed]) {
+    println!(
+        "{:>4} {:<10} {:<16} {:<16} URL / LAST ERROR",
+        "ID", "STATE", "LAST OK", "NEXT"
+    );
+    for feed in feeds {
+        let fetch = &feed.fetch;
+        println!(
+            "{:>4} {:<10} {:<16} {:<16} {}",
+            feed.id,
+            feed_state(fetch),
+            fetch.last_success_at.map(local_time).unwrap_or_default(),
+            local_time(fetch.next_fetch_at),
+            feed.url
+        );
+        if fetch.failures > 0 {
+            if let Some(error) = &fetch.last_error {
+                println!("{:50}{}", "", error.red());
+            }
+        }
+    }
+}
+
+fn print_fetch_log(feed: &db::Feed) -> rusqlite::Result<()> {
+    println!("{} {}", feed.url, feed_state(&feed.fetch));
+    println!(
+        "{:<16} {:>4} {:>7} {:>5} {:>3} ERROR",
+        "TIME", "HTTP", "MS", "FOUND", "NEW"
+    );
+    for log in db::query_fetch_log(feed.id, 20)? {
+        println!(
+            "{:<16} {:>4} {:>7} {:>5} {:>3} {}",
+            local_time(log.fetched_at),
+            log.http_status.map_or("-".to_string(), |s| s.to_string()),
+            log.duration_ms,
+            log.items_found,
+            log.items_new,
+            log.error.unwrap_or_default().red()
+        );
+    }
+    Ok(())
+}
+
+// subscribe the feed of a website, asking which one unless the url is the feed
+fn run_subscribe_command(
+    user_id: i64,
+    matches: &ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = matches.get_one::<String>("url").unwrap();
+    let runtime = tokio::runtime::Runtime::new()?;
+    // unlike the server, the cli may subscribe feeds of the local network
+    let candidates = runtime
+        .block_on(feed::discover_feeds(url, false))
+        .map_err(|e| e.to_string())?;
+    if candidates.is_empty() {
+        return Err(format!("no feed found at {}", url).into());
+    }
+    let chosen = match candidates.iter().find(|c| c.url == *url) {
+        Some(candidate) => candidate,
+        None => {
+            let items = candidates
+                .iter()
+                .map(|c| format!("{} {}", c.title, c.url.dimmed()))
+                .collect::<Vec<_>>();
+            let index = dialoguer::Select::new()
+                .with_prompt("Which feed?")
+                .items(&items)
+                .default(0)
+                .interact()?;
+            &candidates[index]
+        }
+    };
+    match matches.get_one::<String>("folder") {
+        Some(folder) => db::import_feed(user_id, &chosen.url, &chosen.title, "", folder)?,
+        None => {
+            db::add_feed(user_id, &chosen.url)?;
+        }
+    }
+    println!("added: {} {}", chosen.title, chosen.url);
+    Ok(())
+}
+
+fn run_feeds_command(user_id: i64, matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    match matches.subcommand() {
+        Some(("add", sub)) => {
+            let url = sub.get_one::<String>("url").unwrap();
+            db::add_feed(user_id, url)?;
+            println!("added: {}", url);
+        }
+        Some(("subscribe", sub)) => run_subscribe_command(user_id, sub)?,
+        Some(("remove", sub)) => {
+            let url = sub.get_one::<String>("url").unwrap();
+            db::remove_feed(user_id, url)?;
+            println!("removed: {}", url);
+        }
+        Some(("enable", sub)) => {
+            db::set_feed_enabled(user_id, sub.get_one::<String>("url").unwrap(), true)?;
+        }
+        Some(("disable", sub)) => {
+            db::set_feed_enabled(user_id, sub.get_one::<String>("url").unwrap(), false)?;
+        }
+        Some(("rules", sub)) => run_rules_command(user_id, sub)?,
+        Some(("status", sub)) => match sub.get_one::<String>("url") {
+            Some(url) => print_fetch_log(&subscribed_feed(user_id, url)?)?,
+            None => print_feeds_status(&db::query_feeds(user_id)?),
+        },
+        Some(("interval", sub)) => {
+            let url = sub.get_one::<String>("url").unwrap();
+            let feed = subscribed_feed(user_id, url)?;
+            // 0 goes back to following the feed
+            let minutes = sub.get_one::<i64>("minutes").copied().filter(|m| *m > 0);
+            if db::set_feed_interval(user_id, feed.id, minutes)? == 0 {
+                return Err(ONLY_ADMINS.into());
+            }
+        }
+        Some(("folder", sub)) => {
+            let feed = subscribed_feed(user_id, sub.get_one::<String>("url").unwrap())?;
+            let folder = sub.get_one::<String>("folder").map_or("", |f| f.as_str());
+            db::set_feed_folder(user_id, feed.id, folder)?;
+        }
+        _ => {
+            let feeds = db::query_feeds(user_id)?;
+            // feeds outside any folder come first
+            for folder in db::query_folders(user_id)? {
+                if !folder.name.is_empty() {
+                    println!(
+                        "{} {}",
+                        folder.name.bold(),
+                        folder.unread.to_string().cyan()
+                    );
+                }
+                for feed in feeds.iter().filter(|f| f.folder == folder.name) {
+                    let url = if feed.enabled {
+                        feed.url.normal()
+                    } else {
+                        feed.url.dimmed()
+                    };
+                    println!(
+                        "{:>4} {} {} {} {}",
+                        feed.id,
+                        url,
+                        feed.title,
+                        feed.unread.to_string().cyan(),
+                        fetch_status(feed)
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_opml_command(user_id: i64, matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    match matches.subcommand() {
+        Some(("import", sub)) => {
+            let xml = std::fs::read_to_string(sub.get_one::<String>("file").unwrap())?;
+            let count = opml::import(user_id, &xml)?;
+            println!("imported {} feeds", count);
+        }
+        Some(("export", sub)) => {
+            let xml = opml::export(user_id)?;
+            match sub.get_one::<String>("file") {
+                Some(file) => std::fs::write(file, xml)?,
+                None => print!("{}", xml),
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn run_search_command(user_id: i64, matches: &ArgMatches) -> rusqlite::Result<()> {
+    let query = matches
+        .get_many::<String>("query")
+        .unwrap()
+        .map(|q| q.as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let limit = *matches.get_one::<usize>("limit").unwrap();
+    for hit in search::search(user_id, &query, limit, 0)? {
+        let mark = |s: &str| {
+            s.replace("<mark>", "\x1b[1;33m")
+                .replace("</mark>", "\x1b[0m")
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&amp;", "&")
+        };
+        println!("{}  {}", mark(&hit.title).bold(), hit.page.link.dimmed());
+        println!("    {}", mark(&hit.snippet));
+    }
+    Ok(())
+}
+
+fn retention_from_args(matches: &ArgMatches) -> Retention {
+    Retention {
+        days: matches.get_one::<i64>("keep-days").copied(),
+        items: matches.get_one::<usize>("keep-items").copied(),
+        purge_tagged: matches.is_present("purge-tagged"),
+    }
+}
+
+fn run_purge_command(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let retention = retention_from_args(matches);
+    if !retention.is_set() {
+        return Err("set --keep-days or --keep-items".into());
+    }
+    let dry_run = matches.is_present("dry-run");
+    let report = purge::purge(&retention, dry_run).map_err(|e| e.to_string())?;
+    if dry_run {
+        for image in &report.images {
+            println!("{}", image.display().to_string().dimmed());
+        }
+    }
+    println!(
+        "{} {} pages and {} images ({} KB)",
+        if dry_run { "would purge" } else { "purged" },
+        report.pages,
+        report.images.len(),
+        report.image_bytes / 1024
+    );
+    Ok(())
+}
+
+fn read_password() -> std::io::Result<String> {
+    dialoguer::Password::new()
+        .with_prompt("Password")
+        .with_confirmation("Repeat password", "passwords don't match")
+        .interact()
+}
+
+fn run_user_command(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    match matches.subcommand() {
+        Some(("add", sub)) => {
+            let name = sub.get_one::<String>("name").unwrap();
+            auth::add_user(name, &read_password()?)?;
+            println!("added user: {}", name);
+        }
+        Some(("passwd", sub)) => {
+            let name = sub.get_one::<String>("name").unwrap();
+            auth::set_password(name, &read_password()?)?;
+            println!("password changed: {}", name);
+        }
+        Some(("remove", sub)) => {
+            let name = sub.get_one::<String>("name").unwrap();
+            if auth::remove_user(name)? == 0 {
+                return Err(format!("no user named {}", name).into());
+            }
+            println!("removed user: {}", name);
+        }
+        Some(("token", sub)) => {
+            let name = sub.get_one::<String>("name").unwrap();
+            let label = sub.get_one::<String>("label").unwrap();
+            println!("{}", auth::create_api_token(name, label)?);
+        }
+        Some(("fever", sub)) => {
+            let name = sub.get_one::<String>("name").unwrap();
+            if sub.is_present("disable") {
+                auth::disable_fever(name)?;
+                println!("Fever API turned off: {}", name);
+            } else {
+                let password = auth::enable_fever(name)?;
+                println!("Fever API user: {}", name);
+                println!("Fever API password: {}", password);
+            }
+        }
+        Some(("admin", sub)) => {
+            let name = sub.get_one::<String>("name").unwrap();
+            if sub.is_present("revoke") {
+                auth::set_admin(name, false)?;
+                println!("no longer admin: {}", name);
+            } else {
+                auth::set_admin(name, true)?;
+                println!("admin: {}", name);
+            }
+        }
+        _ => {
+            for user in auth::query_users()? {
+                let admin = if user.admin { " (admin)" } else { "" };
+                println!("{:>4} {}{}", user.id, user.name, admin);
             }
-            tokio::time::sleep(std::time::Duration::from_secs(60 * minutes)).await;
         }
-    });
+    }
+    Ok(())
 }
 
 #[tokio::main]
-async fn run_app(port: u16, minutes: u64) {
-    start_auto_update_job(minutes);
-    run_server(port).await;
+async fn run_app(
+    port: u16,
+    public_url: Option<String>,
+    minutes: u64,
+    opts: FetchOptions,
+    retention: Retention,
+) {
+    start_auto_update_job(minutes, opts);
+    start_purge_job(retention);
+    run_server(port, public_url).await;
+}
+
+fn feed_url_arg() -> clap::Arg<'static> {
+    clap::Arg::new("url").help("Feed url").required(true)
+}
+
+// the options of `feeds rules` and `preview`
+fn rules_args() -> Vec<clap::Arg<'static>> {
+    vec![
+        clap::Arg::new("selector")
+            .long("selector")
+            .help("CSS selector of the content in a page")
+            .takes_value(true),
+        clap::Arg::new("strip")
+            .long("strip")
+            .help("CSS selector of elements removed from the content, can be repeated")
+            .takes_value(true)
+            .multiple_occurrences(true),
+        clap::Arg::new("full-page")
+            .long("full-page")
+            .help("Always fetch the page instead of taking the content of the feed")
+            .takes_value(true)
+            .value_parser(value_parser!(bool)),
+    ]
+}
+
+// clap keeps default values for as long as the program runs
+fn default_value(value: impl ToString) -> &'static str {
+    Box::leak(value.to_string().into_boxed_str())
+}
+
+// the options of the server, defaulting to `FetchOptions::default()`
+fn fetch_args() -> Vec<clap::Arg<'static>> {
+    let defaults = FetchOptions::default();
+    vec![
+        clap::Arg::new("update")
+            .short('u')
+            .help("Minutes between two fetches of feeds not telling how often they change, 0 to disable fetching")
+            .takes_value(true)
+            .default_value(default_value(defaults.interval.as_secs() / 60))
+            .value_parser(value_parser!(u64)),
+        clap::Arg::new("jobs")
+            .short('j')
+            .help("Max number of feeds fetched concurrently")
+            .takes_value(true)
+            .default_value(default_value(defaults.jobs))
+            .value_parser(value_parser!(usize)),
+        clap::Arg::new("host-jobs")
+            .long("host-jobs")
+            .help("Max number of feeds fetched concurrently from one host")
+            .takes_value(true)
+            .default_value(default_value(defaults.host_jobs))
+            .value_parser(value_parser!(usize)),
+        clap::Arg::new("timeout")
+            .long("timeout")
+            .help("Seconds allowed for fetching one feed")
+            .takes_value(true)
+            .default_value(default_value(defaults.timeout.as_secs()))
+            .value_parser(value_parser!(u64)),
+    ]
+}
+
+// the options of the server and `purge`
+fn retention_args() -> Vec<clap::Arg<'static>> {
+    vec![
+        clap::Arg::new("keep-days")
+            .long("keep-days")
+            .help("Purge pages published more than this many days ago")
+            .takes_value(true)
+            .value_parser(value_parser!(i64)),
+        clap::Arg::new("keep-items")
+            .long("keep-items")
+            .help("Purge pages past this many newest ones of their feed")
+            .takes_value(true)
+            .value_parser(value_parser!(usize)),
+        clap::Arg::new("purge-tagged")
+            .long("purge-tagged")
+            .help("Purge the pages someone tagged too, they are kept by default"),
+    ]
+}
+
+// an http(s) url without a trailing slash
+fn public_url(value: &str) -> Result<String, String> {
+    match url::Url::parse(value) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {
+            Ok(value.trim_end_matches('/').to_string())
+        }
+        _ => Err(format!("{} is not an http(s) url", value)),
+    }
+}
+
+fn user_name_arg() -> clap::Arg<'static> {
+    clap::Arg::new("name").help("User name").required(true)
 }
 
 fn main() {
@@ -44,21 +559,264 @@ fn main() {
                 .default_value("8005")
                 .value_parser(value_parser!(u16)),
         )
-        .arg(clap::Arg::new("daemon").short('d').help("Run as daemon"))
         .arg(
-            clap::Arg::new("update")
-                .short('u')
-                .help("Start a background job to fetch articles from feed")
+            clap::Arg::new("public-url")
+                .long("public-url")
+                .help("URL clients reach the server at, for the links in published feeds, http://localhost:<port> by default")
                 .takes_value(true)
-                .default_value("20")
-                .value_parser(value_parser!(u64)),
+                .value_parser(public_url),
         )
+        .arg(clap::Arg::new("daemon").short('d').help("Run as daemon"))
+        .args(fetch_args())
+        .args(retention_args())
         .arg(clap::Arg::new("stop").short('s').help("Stop daemon"))
+        .arg(
+            clap::Arg::new("user")
+                .long("user")
+                .help("User the feeds, opml and search commands act for, the first user by default")
+                .takes_value(true)
+                .global(true),
+        )
+        .subcommand(
+            App::new("feeds")
+                .about("Manage subscribed feeds, list them by default")
+                .subcommand(App::new("list").about("List feeds"))
+                .subcommand(
+                    App::new("add")
+                        .about("Subscribe a feed")
+                        .arg(feed_url_arg()),
+                )
+                .subcommand(
+                    App::new("subscribe")
+                        .about("Find the feeds of a website and subscribe one")
+                        .arg(
+                            clap::Arg::new("url")
+                                .help("Website or feed url")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::new("folder")
+                                .long("folder")
+                                .help("Folder to put the feed in")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    App::new("remove")
+                        .about("Unsubscribe a feed")
+                        .arg(feed_url_arg()),
+                )
+                .subcommand(
+                    App::new("enable")
+                        .about("Enable fetching a feed")
+                        .arg(feed_url_arg()),
+                )
+                .subcommand(
+                    App::new("disable")
+                        .about("Disable fetching a feed")
+                        .arg(feed_url_arg()),
+                )
+                .subcommand(
+                    App::new("status")
+                        .about("Show how fetching the feeds goes, or the last fetches of one feed")
+                        .arg(clap::Arg::new("url").help("Feed url")),
+                )
+                .subcommand(
+                    App::new("folder")
+                        .about("Put a feed in a folder, nested folders are separated by /")
+                        .arg(feed_url_arg())
+                        .arg(
+                            clap::Arg::new("folder")
+                                .help("Folder path, none to take the feed out of its folder"),
+                        ),
+                )
+                .subcommand(
+                    App::new("interval")
+                        .about("Set the minutes between two fetches of a feed, 0 to follow the feed")
+                        .arg(feed_url_arg())
+                        .arg(
+                            clap::Arg::new("minutes")
+                                .help("Minutes between two fetches")
+                                .required(true)
+                                .value_parser(value_parser!(i64)),
+                        ),
+                )
+                .subcommand(
+                    App::new("rules")
+                        .about("Show or change how the content of a feed is extracted")
+                        .arg(feed_url_arg())
+                        .args(rules_args())
+                        .arg(
+                            clap::Arg::new("reset")
+                                .long("reset")
+                                .help("Go back to the default rules"),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("preview")
+                .about("Print the content extracted from a page")
+                .arg(clap::Arg::new("url").help("Page url").required(true))
+                .arg(
+                    clap::Arg::new("feed")
+                        .long("feed")
+                        .help("Start from the rules of this feed")
+                        .takes_value(true),
+                )
+                .args(rules_args()),
+        )
+        .subcommand(
+            App::new("opml")
+                .about("Import or export subscriptions as OPML")
+                .subcommand_required(true)
+                .subcommand(
+                    App::new("import")
+                        .about("Subscribe all feeds in an OPML file")
+                        .arg(clap::Arg::new("file").help("OPML file").required(true)),
+                )
+                .subcommand(
+                    App::new("export")
+                        .about("Write subscriptions as OPML, to stdout by default")
+                        .arg(clap::Arg::new("file").help("Output file")),
+                ),
+        )
+        .subcommand(
+            App::new("search")
+                .about("Full-text search over stored pages")
+                .arg(
+                    clap::Arg::new("query")
+                        .help("Words to search for")
+                        .required(true)
+                        .multiple_values(true),
+                )
+                .arg(
+                    clap::Arg::new("limit")
+                        .short('n')
+                        .help("Max number of results")
+                        .takes_value(true)
+                        .default_value("20")
+                        .value_parser(value_parser!(usize)),
+                ),
+        )
+        .subcommand(App::new("reindex").about("Rebuild the search index from stored pages"))
+        .subcommand(
+            App::new("purge")
+                .about("Delete old pages and the images they leave unused, never unread, starred or tagged ones")
+                .args(retention_args())
+                .arg(
+                    clap::Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Only tell what would be deleted"),
+                ),
+        )
+        .subcommand(
+            App::new("user")
+                .about("Manage users of the web server, list them by default")
+                .subcommand(App::new("list").about("List users"))
+                .subcommand(
+                    App::new("add")
+                        .about("Create a user, asking for the password")
+                        .arg(user_name_arg()),
+                )
+                .subcommand(
+                    App::new("passwd")
+                        .about("Reset the password of a user and end its sessions")
+                        .arg(user_name_arg()),
+                )
+                .subcommand(
+                    App::new("remove")
+                        .about("Remove a user")
+                        .arg(user_name_arg()),
+                )
+                .subcommand(
+                    App::new("token")
+                        .about("Create an API token for scripts")
+                        .arg(user_name_arg())
+                        .arg(
+                            clap::Arg::new("label")
+                                .help("What the token is for")
+                                .default_value("script"),
+                        ),
+                )
+                .subcommand(
+                    App::new("fever")
+                        .about("Give a user a new random password for Fever API clients")
+                        .arg(user_name_arg())
+                        .arg(
+                            clap::Arg::new("disable")
+                                .long("disable")
+                                .help("Turn the Fever API off for the user"),
+                        ),
+                )
+                .subcommand(
+                    App::new("admin")
+                        .about("Let a user change the rules and interval of the feeds it subscribes")
+                        .arg(user_name_arg())
+                        .arg(
+                            clap::Arg::new("revoke")
+                                .long("revoke")
+                                .help("Take the right back"),
+                        ),
+                ),
+        )
         .get_matches();
 
+    if let Err(e) = db::init_db(None) {
+        eprintln!("{}", format!("failed to init db: {}", e).red());
+        return;
+    }
+    if let Some(("feeds" | "opml" | "search", sub)) = matches.subcommand() {
+        let res = cli_user(sub).and_then(|user_id| match matches.subcommand() {
+            Some(("feeds", sub)) => Ok(run_feeds_command(user_id, sub)?),
+            Some(("opml", sub)) => run_opml_command(user_id, sub),
+            _ => Ok(run_search_command(user_id, sub)?),
+        });
+        if let Err(e) = res {
+            eprintln!("{}", e.to_string().red());
+        }
+        return;
+    }
+
+    match matches.subcommand() {
+        Some(("user", sub)) => {
+            if let Err(e) = run_user_command(sub) {
+                eprintln!("{}", e.to_string().red());
+            }
+            return;
+        }
+        Some(("preview", sub)) => {
+            if let Err(e) = run_preview_command(sub) {
+                eprintln!("{}", e.to_string().red());
+            }
+            return;
+        }
+        Some(("reindex", _)) => {
+            match search::reindex() {
+                Ok(count) => println!("indexed {} pages", count),
+                Err(e) => eprintln!("{}", e.to_string().red()),
+            }
+            return;
+        }
+        Some(("purge", sub)) => {
+            if let Err(e) = run_purge_command(sub) {
+                eprintln!("{}", e.to_string().red());
+            }
+            return;
+        }
+        _ => {}
+    }
+
     let port = *matches.get_one::<u16>("port").unwrap();
+    let public_url = matches.get_one::<String>("public-url").cloned();
     let minutes = *matches.get_one::<u64>("update").unwrap();
     let daemon = matches.is_present("daemon");
+    let opts = FetchOptions {
+        jobs: *matches.get_one::<usize>("jobs").unwrap(),
+        host_jobs: *matches.get_one::<usize>("host-jobs").unwrap(),
+        timeout: std::time::Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap()),
+        ..FetchOptions::default()
+    };
+    let retention = retention_from_args(&matches);
 
     let pid_file: PathBuf = format!("/tmp/rss-rs-{}.pid", port).into();
     if daemon {
@@ -77,13 +835,13 @@ fn main() {
         match daemonize.start() {
             Ok(_) => {
                 println!("Success, daemonized");
-                run_app(port, minutes);
+                run_app(port, public_url, minutes, opts, retention);
             }
             Err(e) => eprintln!("Error, {}", e),
         }
     } else if matches.is_present("stop") {
         kill_process(&pid_file, "rss-rs").unwrap();
     } else {
-        run_app(port, minutes);
+        run_app(port, public_url, minutes, opts, retention);
     }
 }
@@ -0,0 +1,524 @@
+use crate::auth::default_user;
+use crate::conf::*;
+use crate::db::feeds_file_urls;
+use crate::sanitize::sanitize;
+use chrono::prelude::*;
+use rusqlite::{ffi, params, Connection, Transaction};
+use std::fs;
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Schema migrations in order, `PRAGMA user_version` holds how many of them are applied.
+/// Append new migrations at the end and never change one that has been released.
+static MIGRATIONS: &[Migration] = &[
+    baseline,
+    epoch_timestamps,
+    users,
+    user_subscriptions,
+    fever_keys,
+    starred_pages,
+    sanitize_contents,
+    extract_rules,
+    fetch_schedule,
+    fetch_log,
+    page_tags,
+    purged_links,
+    admins,
+];
+
+pub(crate) fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+/// Bring the schema up to date, each migration runs in its own transaction
+pub(crate) fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version = schema_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_ERROR),
+            Some(format!(
+                "db schema version {} is newer than {} supported by this rss-rs",
+                version,
+                MIGRATIONS.len()
+            )),
+        ));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+        tx.commit()?;
+        eprintln!("db migrated to version {}", i + 1);
+    }
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut statement = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = statement
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(names.iter().any(|n| n == column))
+}
+
+// Databases from before versioning may be at any earlier layout,
+// so every step here checks what is already there.
+fn baseline(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS feeds (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url String NOT NULL UNIQUE,
+            title String,
+            site_link String,
+            added_datetime String,
+            last_status String,
+            enabled Boolean NOT NULL DEFAULT 1,
+            folder String);
+        CREATE TABLE IF NOT EXISTS feed_cache (
+            feed String PRIMARY KEY,
+            etag String,
+            last_modified String);
+        "#,
+    )?;
+    if !has_column(tx, "feeds", "folder")? {
+        tx.execute_batch("ALTER TABLE feeds ADD COLUMN folder String;")?;
+    }
+    let legacy =
+        tx.prepare("SELECT 1 FROM pages LIMIT 1").is_ok() && !has_column(tx, "pages", "feed_id")?;
+    if legacy {
+        upgrade_pages_source(tx)?;
+    }
+    tx.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS pages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title String NOT NULL,
+            link String NOT NULL,
+            website String,
+            publish_datetime String,
+            readed Boolean,
+            feed_id INTEGER NOT NULL REFERENCES feeds(id) ON DELETE CASCADE);
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_pages_link ON pages (link);
+        CREATE INDEX IF NOT EXISTS idx_pages_feed ON pages (feed_id);
+        CREATE TABLE IF NOT EXISTS contents (
+            page_id INTEGER PRIMARY KEY REFERENCES pages(id) ON DELETE CASCADE,
+            body String NOT NULL);
+        CREATE VIRTUAL TABLE IF NOT EXISTS pages_fts USING fts5(title, body);
+        CREATE TRIGGER IF NOT EXISTS pages_fts_delete AFTER DELETE ON pages BEGIN
+            DELETE FROM pages_fts WHERE rowid = old.id;
+        END;
+        "#,
+    )?;
+    import_page_files(tx)
+}
+
+// times were stored as the string of a UTC datetime, like "2022-01-02 03:04:05.678 UTC"
+fn epoch_timestamps(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        ALTER TABLE pages ADD COLUMN published_at INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE pages ADD COLUMN fetched_at INTEGER NOT NULL DEFAULT 0;
+        UPDATE pages SET published_at =
+            COALESCE(CAST(strftime('%s', substr(publish_datetime, 1, 19)) AS INTEGER), 0);
+        UPDATE pages SET fetched_at = published_at;
+        ALTER TABLE pages DROP COLUMN publish_datetime;
+        CREATE INDEX idx_pages_published ON pages (published_at);
+
+        ALTER TABLE feeds ADD COLUMN added_at INTEGER NOT NULL DEFAULT 0;
+        UPDATE feeds SET added_at =
+            COALESCE(CAST(strftime('%s', substr(added_datetime, 1, 19)) AS INTEGER), 0);
+        ALTER TABLE feeds DROP COLUMN added_datetime;
+        "#,
+    )
+}
+
+fn users(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE users (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name String NOT NULL UNIQUE,
+            password_hash String NOT NULL,
+            created_at INTEGER NOT NULL);
+        CREATE TABLE sessions (
+            token_hash String PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            expires_at INTEGER NOT NULL);
+        CREATE TABLE api_tokens (
+            token_hash String PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            name String NOT NULL,
+            created_at INTEGER NOT NULL);
+        "#,
+    )
+}
+
+// subscriptions, folders and read state were global, they move to the first user
+fn user_subscriptions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE subscriptions (
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            feed_id INTEGER NOT NULL REFERENCES feeds(id) ON DELETE CASCADE,
+            folder String,
+            enabled Boolean NOT NULL DEFAULT 1,
+            added_at INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (user_id, feed_id));
+        CREATE INDEX idx_subscriptions_feed ON subscriptions (feed_id);
+        CREATE TABLE page_states (
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            page_id INTEGER NOT NULL REFERENCES pages(id) ON DELETE CASCADE,
+            readed Boolean NOT NULL DEFAULT 0,
+            PRIMARY KEY (user_id, page_id));
+        "#,
+    )?;
+    let has_feeds = tx.query_row("SELECT EXISTS (SELECT 1 FROM feeds)", [], |row| {
+        row.get::<_, bool>(0)
+    })?;
+    if has_feeds {
+        let owner = default_user(tx)?;
+        tx.execute(
+            "INSERT INTO subscriptions (user_id, feed_id, folder, enabled, added_at)
+             SELECT ?, id, folder, enabled, added_at FROM feeds",
+            [owner],
+        )?;
+        tx.execute(
+            "INSERT INTO page_states (user_id, page_id, readed)
+             SELECT ?, id, 1 FROM pages WHERE readed",
+            [owner],
+        )?;
+    }
+    tx.execute_batch(
+        r#"
+        ALTER TABLE feeds DROP COLUMN folder;
+        ALTER TABLE feeds DROP COLUMN enabled;
+        ALTER TABLE feeds DROP COLUMN added_at;
+        ALTER TABLE pages DROP COLUMN readed;
+        "#,
+    )
+}
+
+// key of the Fever API, md5("name:password") of a random Fever password. NULL while the user
+// hasn't turned Fever access on.
+fn fever_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE users ADD COLUMN fever_key String;")
+}
+
+fn starred_pages(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE page_states ADD COLUMN starred Boolean NOT NULL DEFAULT 0;")
+}
+
+// bodies stored before they were sanitized on fetch
+fn sanitize_contents(tx: &Transaction) -> rusqlite::Result<()> {
+    let bodies = {
+        let mut statement = tx.prepare("SELECT page_id, body FROM contents")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    for (id, body) in bodies {
+        tx.execute(
+            "UPDATE contents SET body = ? WHERE page_id = ?",
+            params![sanitize(&body), id],
+        )?;
+    }
+    Ok(())
+}
+
+fn extract_rules(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        ALTER TABLE feeds ADD COLUMN content_selector String;
+        ALTER TABLE feeds ADD COLUMN strip_selectors String;
+        ALTER TABLE feeds ADD COLUMN full_page Boolean NOT NULL DEFAULT 0;
+        "#,
+    )
+}
+
+fn fetch_schedule(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        ALTER TABLE feeds ADD COLUMN fetch_interval INTEGER;
+        ALTER TABLE feeds ADD COLUMN ttl INTEGER;
+        ALTER TABLE feeds ADD COLUMN next_fetch_at INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE feeds ADD COLUMN failures INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE feeds ADD COLUMN last_success_at INTEGER;
+        ALTER TABLE feeds ADD COLUMN last_error TEXT;
+        ALTER TABLE feeds ADD COLUMN last_error_at INTEGER;
+        "#,
+    )
+}
+
+fn fetch_log(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE fetch_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            feed_id INTEGER NOT NULL REFERENCES feeds(id) ON DELETE CASCADE,
+            fetched_at INTEGER NOT NULL,
+            http_status INTEGER,
+            duration_ms INTEGER NOT NULL,
+            items_found INTEGER NOT NULL,
+            items_new INTEGER NOT NULL,
+            error TEXT);
+        CREATE INDEX idx_fetch_log_feed ON fetch_log (feed_id);
+        "#,
+    )
+}
+
+// tags are per user, a tag without pages is deleted
+fn page_tags(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE tags (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            UNIQUE (user_id, name));
+        CREATE TABLE page_tags (
+            tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+            page_id INTEGER NOT NULL REFERENCES pages(id) ON DELETE CASCADE,
+            added_at INTEGER NOT NULL,
+            PRIMARY KEY (tag_id, page_id));
+        CREATE INDEX idx_page_tags_page ON page_tags (page_id);
+        "#,
+    )
+}
+
+// links of purged pages, so that fetching their feed again doesn't bring them back
+fn purged_links(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        CREATE TABLE purged_links (
+            link TEXT PRIMARY KEY,
+            purged_at INTEGER NOT NULL);
+        "#,
+    )
+}
+
+// admins change what is shared by all users, like the extraction rules and fetch interval of
+// a feed. The first user is one, and so is the first user of an empty db.
+fn admins(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+        ALTER TABLE users ADD COLUMN admin Boolean NOT NULL DEFAULT 0;
+        UPDATE users SET admin = 1 WHERE id = (SELECT MIN(id) FROM users);
+        CREATE TRIGGER first_admin AFTER INSERT ON users
+        WHEN NOT EXISTS (SELECT 1 FROM users WHERE admin)
+        BEGIN
+            UPDATE users SET admin = 1 WHERE id = NEW.id;
+        END;
+        "#,
+    )
+}
+
+// pages used to store the feed url in `source`, move them onto feed ids. The feeds listed in
+// the feeds file are the subscriptions, pages of other sources are kept as the history of a
+// disabled feed, which is not fetched again.
+fn upgrade_pages_source(conn: &Connection) -> rusqlite::Result<()> {
+    let now = Utc::now().to_string();
+    for url in feeds_file_urls().unwrap_or_default() {
+        conn.execute(
+            "INSERT OR IGNORE INTO feeds (url, added_datetime) VALUES (?1, ?2)",
+            params![url, now],
+        )?;
+    }
+    conn.execute(
+        "INSERT OR IGNORE INTO feeds (url, added_datetime, enabled)
+         SELECT DISTINCT source, ?1, 0 FROM pages",
+        [&now],
+    )?;
+    conn.execute_batch(
+        r#"
+        CREATE TABLE pages_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title String NOT NULL,
+            link String NOT NULL,
+            website String,
+            publish_datetime String,
+            readed Boolean,
+            feed_id INTEGER NOT NULL REFERENCES feeds(id) ON DELETE CASCADE);
+        INSERT INTO pages_new (id, title, link, website, publish_datetime, readed, feed_id)
+            SELECT p.id, p.title, p.link, p.website, p.publish_datetime, p.readed, f.id
+            FROM pages p JOIN feeds f ON f.url = p.source;
+        DROP TABLE pages;
+        ALTER TABLE pages_new RENAME TO pages;
+        "#,
+    )?;
+    eprintln!("pages moved onto feeds table");
+    Ok(())
+}
+
+// page bodies used to be files named after the title, move them into the contents table
+fn import_page_files(conn: &Connection) -> rusqlite::Result<()> {
+    let mut statement = conn.prepare(
+        "SELECT p.id, p.title FROM pages p LEFT JOIN contents c ON c.page_id = p.id
+         WHERE c.page_id IS NULL",
+    )?;
+    let pages = statement
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut moved = vec![];
+    for (id, title) in pages {
+        let path = format!("{}/{}.html", pages_dir(), title);
+        if let Ok(body) = fs::read_to_string(&path) {
+            conn.execute(
+                "INSERT INTO contents (page_id, body) values (?1, ?2)",
+                params![id, body],
+            )?;
+            moved.push(path);
+        }
+    }
+    // pages with the same title shared one file, remove them only after all are imported
+    for path in moved.iter() {
+        let _ = fs::remove_file(path);
+    }
+    if !moved.is_empty() {
+        eprintln!("moved {} page files into db", moved.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::*;
+    use crate::testing::TestDir;
+    use chrono::TimeZone;
+    use std::path::Path;
+
+    #[test]
+    fn test_migrate() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        let conn = Connection::open(pages_db())?;
+        assert_eq!(schema_version(&conn)?, MIGRATIONS.len());
+        // running again is a no-op
+        init_db(None)?;
+        assert_eq!(schema_version(&conn)?, MIGRATIONS.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuse_newer_schema() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        let conn = Connection::open(pages_db())?;
+        conn.pragma_update(None, "user_version", (MIGRATIONS.len() + 1) as i64)?;
+        let err = init_db(None).unwrap_err();
+        assert!(err.to_string().contains("newer"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_pages_source() -> rusqlite::Result<()> {
+        let _dir = TestDir::empty();
+        let conn = Connection::open(pages_db())?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE pages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                title String NOT NULL,
+                link String NOT NULL,
+                website String,
+                publish_datetime String,
+                readed Boolean,
+                source String NOT NULL);
+            INSERT INTO pages (title, link, website, publish_datetime, readed, source)
+            VALUES ('title', 'link', 'website', '2020-01-02 03:04:05.678 UTC', true, 'source'),
+                   ('old', 'old_link', 'website', '2020-01-02 03:04:05.678 UTC', false, 'removed');
+            "#,
+        )?;
+        fs::write(feeds_file(), "source\nnew\n").unwrap();
+        let started = Utc::now().timestamp();
+        init_db(None)?;
+        // data from before users belongs to the first user
+        let owner = default_user(&conn)?;
+        let page = query_page_link(owner, "link")?.unwrap();
+        assert_eq!(page.source, "source");
+        assert!(page.readed);
+        // only the listed feeds are fetched, the pages of the others are kept
+        assert_eq!(all_feeds(), vec!["source", "new"]);
+        assert_eq!(
+            query_page_link(owner, "old_link")?.unwrap().source,
+            "removed"
+        );
+        let feeds = query_feeds(owner)?;
+        let removed = feeds.iter().find(|f| f.url == "removed").unwrap();
+        assert!(!removed.enabled);
+        // feeds were added by the migration, not when their pages were published
+        assert!(feeds.iter().all(|f| f.added_at >= started));
+        Ok(())
+    }
+
+    #[test]
+    fn test_epoch_timestamps() -> rusqlite::Result<()> {
+        let _dir = TestDir::empty();
+        let conn = Connection::open(pages_db())?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE pages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                title String NOT NULL,
+                link String NOT NULL,
+                website String,
+                publish_datetime String,
+                readed Boolean,
+                source String NOT NULL);
+            INSERT INTO pages (title, link, website, publish_datetime, readed, source)
+            VALUES ('title', 'link1', 'website', '2022-01-02 03:04:05.678 UTC', true, 'source'),
+                   ('title', 'link2', 'website', '2022-01-02 03:04:05 UTC', true, 'source'),
+                   ('title', 'link3', 'website', 'broken', true, 'source');
+            "#,
+        )?;
+        init_db(None)?;
+        let expected = chrono::Utc
+            .with_ymd_and_hms(2022, 1, 2, 3, 4, 5)
+            .unwrap()
+            .timestamp();
+        let owner = default_user(&conn)?;
+        let page = query_page_link(owner, "link1")?.unwrap();
+        assert_eq!(page.published_at, expected);
+        assert_eq!(page.fetched_at, expected);
+        assert_eq!(
+            query_page_link(owner, "link2")?.unwrap().published_at,
+            expected
+        );
+        assert_eq!(query_page_link(owner, "link3")?.unwrap().published_at, 0);
+        assert!(query_feeds(owner)?[0].added_at > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_page_files() -> rusqlite::Result<()> {
+        let _dir = TestDir::empty();
+        let conn = Connection::open(pages_db())?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE pages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                title String NOT NULL,
+                link String NOT NULL,
+                website String,
+                publish_datetime String,
+                readed Boolean,
+                source String NOT NULL);
+            INSERT INTO pages (title, link, website, publish_datetime, readed, source)
+            VALUES ('a | b', 'link', 'website', 'publish_time', true, 'source');
+            "#,
+        )?;
+        let path = format!("{}/a | b.html", pages_dir());
+        fs::create_dir_all(pages_dir()).unwrap();
+        fs::write(&path, "<p onclick=\"x()\">body</p><script>x()</script>").unwrap();
+
+        init_db(None)?;
+        let page = query_page_link(default_user(&conn)?, "link")?.unwrap();
+        // bodies from before sanitizing are cleaned too
+        assert_eq!(query_page_content(page.id)?.unwrap(), "<p>body</p>");
+        assert!(!Path::new(&path).exists());
+        Ok(())
+    }
+}
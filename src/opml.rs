@@ -0,0 +1,297 @@
+use crate::db::*;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// A feed outline in an OPML document
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Outline {
+    pub title: String,
+    pub xml_url: String,
+    pub html_url: String,
+    /// folder path from the enclosing outlines, separated by `/`
+    pub folder: String,
+}
+
+fn outline_attrs(reader: &Reader<&[u8]>, elem: &BytesStart) -> Result<Outline, Box<dyn Error>> {
+    let mut outline = Outline::default();
+    let mut text = String::new();
+    for attr in elem.attributes() {
+        let attr = attr?;
+        let value = attr.decode_and_unescape_value(reader)?.trim().to_string();
+        match attr.key.as_ref() {
+            b"title" => outline.title = value,
+            b"text" => text = value,
+            b"xmlUrl" => outline.xml_url = value,
+            b"htmlUrl" => outline.html_url = value,
+            _ => {}
+        }
+    }
+    if outline.title.is_empty() {
+        outline.title = text;
+    }
+    Ok(outline)
+}
+
+/// Parse all feeds in an OPML document, outlines without `xmlUrl` are folders
+pub fn parse(xml: &str) -> Result<Vec<Outline>, Box<dyn Error>> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut outlines = vec![];
+    // one entry for every open outline, folders carry their name
+    let mut stack: Vec<Option<String>> = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.name().as_ref() == b"outline" => {
+                let outline = outline_attrs(&reader, &e)?;
+                if outline.xml_url.is_empty() {
+                    stack.push(Some(outline.title));
+                } else {
+                    stack.push(None);
+                    outlines.push(Outline {
+                        folder: outline_folder(&stack),
+                        ..outline
+                    });
+                }
+            }
+            Event::Empty(e) if e.name().as_ref() == b"outline" => {
+                let outline = outline_attrs(&reader, &e)?;
+                if !outline.xml_url.is_empty() {
+                    outlines.push(Outline {
+                        folder: outline_folder(&stack),
+                        ..outline
+                    });
+                }
+            }
+            Event::End(e) if e.name().as_ref() == b"outline" => {
+                stack.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(outlines)
+}
+
+fn outline_folder(stack: &[Option<String>]) -> String {
+    stack
+        .iter()
+        .flatten()
+        .filter(|f| !f.is_empty())
+        .map(|f| f.replace('/', "|"))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[derive(Default)]
+struct Folder<'a> {
+    feeds: Vec<&'a Feed>,
+    children: BTreeMap<&'a str, Folder<'a>>,
+}
+
+fn render_folder(folder: &Folder, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth + 2);
+    for (name, child) in &folder.children {
+        out.push_str(&format!(
+            "{}<outline text=\"{}\" title=\"{}\">\n",
+            indent,
+            escape(name),
+            escape(name)
+        ));
+        render_folder(child, depth + 1, out);
+        out.push_str(&format!("{}</outline>\n", indent));
+    }
+    for feed in &folder.feeds {
+        let title = if feed.title.is_empty() {
+            &feed.url
+        } else {
+            &feed.title
+        };
+        out.push_str(&format!(
+            "{}<outline type=\"rss\" text=\"{}\" title=\"{}\" xmlUrl=\"{}\" htmlUrl=\"{}\"/>\n",
+            indent,
+            escape(title),
+            escape(title),
+            escape(&feed.url),
+            escape(&feed.site_link)
+        ));
+    }
+}
+
+// after the feeds, an outline of type "tag" for every tag with a link outline for each page.
+// Readers skip outline types they don't know.
+fn render_tags(tags: &[(String, Vec<Page>)], out: &mut String) {
+    for (name, pages) in tags {
+        out.push_str(&format!(
+            "    <outline type=\"tag\" text=\"{}\" title=\"{}\">\n",
+            escape(name),
+            escape(name)
+        ));
+        for page in pages {
+            out.push_str(&format!(
+                "      <outline type=\"link\" text=\"{}\" url=\"{}\"/>\n",
+                escape(&page.title),
+                escape(&page.link)
+            ));
+        }
+        out.push_str("    </outline>\n");
+    }
+}
+
+/// Render feeds as an OPML 2.0 document, folders become nested outlines. Tags follow with the
+/// pages they are on.
+pub fn render(feeds: &[Feed], tags: &[(String, Vec<Page>)]) -> String {
+    let mut root = Folder::default();
+    for feed in feeds {
+        let mut folder = &mut root;
+        for name in feed.folder.split('/').filter(|f| !f.is_empty()) {
+            folder = folder.children.entry(name).or_default();
+        }
+        folder.feeds.push(feed);
+    }
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n  <head>\n    <title>rss-rs subscriptions</title>\n  </head>\n  <body>\n",
+    );
+    render_folder(&root, 0, &mut out);
+    render_tags(tags, &mut out);
+    out.push_str("  </body>\n</opml>\n");
+    out
+}
+
+/// Subscribe the user to all feeds in an OPML document, return the number of feeds found.
+/// Nothing is imported when one of them fails, db errors are returned as `rusqlite::Error`.
+pub fn import(user_id: i64, xml: &str) -> Result<usize, Box<dyn Error>> {
+    let outlines = parse(xml)?;
+    let conn = connect()?;
+    let tx = conn.unchecked_transaction()?;
+    for outline in outlines.iter() {
+        import_feed_in(
+            &tx,
+            user_id,
+            &outline.xml_url,
+            &outline.title,
+            &outline.html_url,
+            &outline.folder,
+        )?;
+    }
+    tx.commit()?;
+    Ok(outlines.len())
+}
+
+/// The subscriptions and tags of a user as OPML
+pub fn export(user_id: i64) -> Result<String, Box<dyn Error>> {
+    let mut tags = vec![];
+    for tag in query_tags(user_id)? {
+        let filter = PageFilter::new().tag(&tag.name).order(Order::OldestFirst);
+        tags.push((tag.name, query_pages(user_id, &filter)?));
+    }
+    Ok(render(&query_feeds(user_id)?, &tags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::default_user;
+    use crate::testing::{self, TestDir};
+
+    static OPML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="2.0">
+  <head><title>subscriptions</title></head>
+  <body>
+    <outline text="Rust" title="Rust">
+      <outline type="rss" text="Rust Blog" xmlUrl="https://blog.rust-lang.org/feed.xml" htmlUrl="https://blog.rust-lang.org/"/>
+      <outline text="Compiler">
+        <outline type="rss" text="Inside Rust" title="Inside Rust &amp; co" xmlUrl="https://blog.rust-lang.org/inside-rust/feed.xml"/>
+      </outline>
+    </outline>
+    <outline type="rss" text="LWN" xmlUrl="https://lwn.net/headlines/rss"></outline>
+    <outline text="Empty"/>
+  </body>
+</opml>
+"#;
+
+    #[test]
+    fn test_parse() -> Result<(), Box<dyn Error>> {
+        let outlines = parse(OPML)?;
+        assert_eq!(outlines.len(), 3);
+        assert_eq!(
+            outlines[0],
+            Outline {
+                title: "Rust Blog".to_string(),
+                xml_url: "https://blog.rust-lang.org/feed.xml".to_string(),
+                html_url: "https://blog.rust-lang.org/".to_string(),
+                folder: "Rust".to_string(),
+            }
+        );
+        assert_eq!(outlines[1].title, "Inside Rust & co");
+        assert_eq!(outlines[1].folder, "Rust/Compiler");
+        assert_eq!(outlines[2].title, "LWN");
+        assert_eq!(outlines[2].folder, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_export() -> Result<(), Box<dyn Error>> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        assert_eq!(import(user, OPML)?, 3);
+
+        let exported = export(user)?;
+        assert!(exported.contains("title=\"Inside Rust &amp; co\""));
+        let mut outlines = parse(&exported)?;
+        let mut expected = parse(OPML)?;
+        outlines.sort_by(|a, b| a.xml_url.cmp(&b.xml_url));
+        expected.sort_by(|a, b| a.xml_url.cmp(&b.xml_url));
+        assert_eq!(outlines, expected);
+
+        // importing again doesn't duplicate feeds
+        import(user, OPML)?;
+        assert_eq!(query_feeds(user)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_fails() -> Result<(), Box<dyn Error>> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        assert!(import(user, "<opml><body><outline").is_err());
+        // the last feed can't be stored, the ones before it are not imported either
+        connect()?.execute_batch(
+            "CREATE TRIGGER no_lwn BEFORE INSERT ON feeds WHEN NEW.url LIKE '%lwn.net%'
+             BEGIN SELECT RAISE(ABORT, 'no lwn'); END;",
+        )?;
+        let err = import(user, OPML).unwrap_err();
+        assert!(err.is::<rusqlite::Error>());
+        assert!(query_feeds(user)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_tags() -> Result<(), Box<dyn Error>> {
+        let _dir = TestDir::new()?;
+        let user = default_user(&connect()?)?;
+        import(user, OPML)?;
+        dump_new_page(&Page {
+            title: "Rust & co".to_string(),
+            website: "https://blog.rust-lang.org/".to_string(),
+            ..testing::page(
+                "https://blog.rust-lang.org/1",
+                "https://blog.rust-lang.org/feed.xml",
+            )
+        })?;
+        let page = query_page_link(user, "https://blog.rust-lang.org/1")?.unwrap();
+        add_page_tag(user, page.id, "to-review")?;
+
+        let exported = export(user)?;
+        assert!(exported.contains(
+            "    <outline type=\"tag\" text=\"to-review\" title=\"to-review\">\n      \
+             <outline type=\"link\" text=\"Rust &amp; co\" url=\"https://blog.rust-lang.org/1\"/>\n    \
+             </outline>\n"
+        ));
+        // tags are not feeds nor folders when the export is imported again
+        assert_eq!(parse(&exported)?.len(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use crate::conf::*;
+use crate::db;
+use chrono::prelude::*;
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+type PurgeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How long pages are kept. A page outside any of the limits set is purged, unless a
+/// subscriber hasn't read it or someone starred or tagged it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// days since the page was published
+    pub days: Option<i64>,
+    /// newest pages kept in every feed
+    pub items: Option<usize>,
+    /// purge the pages someone tagged too, they go with their tags
+    pub purge_tagged: bool,
+}
+
+impl Retention {
+    pub fn is_set(&self) -> bool {
+        self.days.is_some() || self.items.is_some()
+    }
+}
+
+/// What a purge deleted, or would delete on a dry run
+#[derive(Debug, Default, PartialEq)]
+pub struct PurgeReport {
+    pub pages: usize,
+    pub images: Vec<PathBuf>,
+    /// size of the images
+    pub image_bytes: u64,
+}
+
+// names of the images a body links to
+fn image_refs(body: &str, refs: &mut HashSet<String>) {
+    for part in body.split(IMAGE_URL_PREFIX).skip(1) {
+        let name: String = part
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+            .collect();
+        if !name.is_empty() {
+            refs.insert(name);
+        }
+    }
+}
+
+fn is_recent(path: &Path) -> bool {
+    let age = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| SystemTime::now().duration_since(t).ok());
+    age.is_some_and(|age| age < Duration::from_secs(RECENT_IMAGE_SECS))
+}
+
+// images in `dir` that no page of `conn` but the purged ones links to
+fn unreferenced_images(
+    conn: &Connection,
+    dir: &Path,
+    purged: &HashSet<i64>,
+) -> Result<Vec<PathBuf>, PurgeError> {
+    let mut refs = HashSet::new();
+    db::scan_bodies(conn, |id, body| {
+        if !purged.contains(&id) {
+            image_refs(body, &mut refs);
+        }
+    })?;
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(vec![]);
+    };
+    let mut images = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if path.is_file() && !refs.contains(name) && !is_recent(&path) {
+            images.push(path);
+        }
+    }
+    images.sort();
+    Ok(images)
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |m| m.len())
+}
+
+fn purge_in(
+    retention: &Retention,
+    image_dir: &Path,
+    now: i64,
+    dry_run: bool,
+) -> Result<PurgeReport, PurgeError> {
+    let cutoff = retention.days.map(|days| now - days * 24 * 3600);
+    let ids = db::purgeable_pages(cutoff, retention.items, retention.purge_tagged)?;
+    if dry_run {
+        let purged: HashSet<i64> = ids.iter().copied().collect();
+        let images = unreferenced_images(&db::connect()?, image_dir, &purged)?;
+        return Ok(PurgeReport {
+            pages: ids.len(),
+            image_bytes: images.iter().map(|image| file_size(image)).sum(),
+            images,
+        });
+    }
+    // a page stored since the pages to purge were picked may use an old image again, the
+    // references are checked after the deletion and before another page can be stored
+    let (pages, report) = db::delete_pages(&ids, now, |conn| -> Result<_, PurgeError> {
+        let mut report = PurgeReport::default();
+        for image in unreferenced_images(conn, image_dir, &HashSet::new())? {
+            let bytes = file_size(&image);
+            match fs::remove_file(&image) {
+                Ok(()) => {
+                    report.images.push(image);
+                    report.image_bytes += bytes;
+                }
+                Err(e) => eprintln!("failed to remove {:?}: {}", image, e),
+            }
+        }
+        Ok(report)
+    })?;
+    db::vacuum()?;
+    Ok(PurgeReport { pages, ..report })
+}
+
+/// Delete the pages past the retention with their bodies, then the downloaded images no
+/// page links to anymore, and compact the db. Nothing is deleted on a dry run.
+pub fn purge(retention: &Retention, dry_run: bool) -> Result<PurgeReport, PurgeError> {
+    purge_in(
+        retention,
+        Path::new(&image_dir()),
+        Utc::now().timestamp(),
+        dry_run,
+    )
+}
+
+/// Purge every `PURGE_INTERVAL_HOURS` while the server runs
+pub async fn run_purge_job(retention: Retention) {
+    loop {
+        match tokio::task::spawn_blocking(move || purge(&retention, false)).await {
+            Ok(Ok(report)) => println!(
+                "purged {} pages and {} images",
+                report.pages,
+                report.images.len()
+            ),
+            Ok(Err(e)) => eprintln!("purge failed: {}", e),
+            Err(e) => eprintln!("purge failed: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(PURGE_INTERVAL_HOURS * 3600)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::*;
+    use crate::testing::{self, TestDir};
+
+    #[test]
+    fn test_image_refs() {
+        let mut refs = HashSet::new();
+        image_refs(
+            r#"<img src="/pages/images/0a1b2c.png"><img srcset="/pages/images/ff.jpg 2x">
+               <a href="/pages/images/">x</a>"#,
+            &mut refs,
+        );
+        assert_eq!(
+            refs,
+            HashSet::from(["0a1b2c.png".to_string(), "ff.jpg".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_purge() -> Result<(), PurgeError> {
+        let _dir = TestDir::new()?;
+        let user = auth_user()?;
+        add_feed(user, "source")?;
+        let day = 24 * 3600;
+        let now = 100 * day;
+        for i in 0..5 {
+            let link = format!("link{}", i);
+            dump_new_page(&Page {
+                published_at: now - (10 - i) * day,
+                ..testing::page(&link, "source")
+            })?;
+            save_page_content(
+                &link,
+                &format!(r#"<img src="/pages/images/{}.png">"#, i % 2),
+            )?;
+        }
+        let id = |link: &str| query_page_link(user, link).unwrap().unwrap().id;
+        // link0 is unread, link1 starred and link2 tagged
+        set_pages_read(user, &PageFilter::new(), true)?;
+        set_page_read(user, id("link0"), false)?;
+        set_page_starred(user, id("link1"), true)?;
+        add_page_tag(user, id("link2"), "rfc")?;
+
+        let dir = PathBuf::from(image_dir());
+        fs::create_dir_all(&dir)?;
+        for name in ["0.png", "1.png", "other.png"] {
+            fs::write(dir.join(name), "image")?;
+        }
+        // images just downloaded are left alone
+        assert!(purge_in(&Retention::default(), &dir, now, true)?
+            .images
+            .is_empty());
+        let old = SystemTime::now() - Duration::from_secs(RECENT_IMAGE_SECS + 10);
+        for name in ["0.png", "1.png", "other.png"] {
+            fs::File::options()
+                .write(true)
+                .open(dir.join(name))?
+                .set_modified(old)?;
+        }
+
+        let retention = Retention {
+            days: Some(7),
+            ..Retention::default()
+        };
+        let report = purge_in(&retention, &dir, now, true)?;
+        assert_eq!(report.pages, 0);
+        assert_eq!(report.images, [dir.join("other.png")]);
+        assert_eq!(report.image_bytes, 5);
+
+        // all but the newest go, except link0 to link2
+        let retention = Retention {
+            items: Some(1),
+            ..Retention::default()
+        };
+        let report = purge_in(&retention, &dir, now, false)?;
+        assert_eq!(report.pages, 1);
+        assert_eq!(report.images, [dir.join("other.png")]);
+        assert!(!dir.join("other.png").exists());
+        assert!(dir.join("1.png").exists());
+        let links: Vec<String> = query_pages(user, &PageFilter::new().order(Order::OldestFirst))?
+            .into_iter()
+            .map(|p| p.link)
+            .collect();
+        assert_eq!(links, ["link0", "link1", "link2", "link4"]);
+        // purged pages are not stored again
+        assert!(page_exists("link3")?);
+        dump_new_page(&Page {
+            title: "again".to_string(),
+            published_at: now,
+            ..testing::page("link3", "source")
+        })?;
+        assert!(query_page_link(user, "link3")?.is_none());
+
+        set_page_starred(user, id("link1"), false)?;
+        purge_in(&retention, &dir, now, false)?;
+        assert!(query_page_link(user, "link1")?.is_none());
+        assert!(!dir.join("1.png").exists());
+        assert!(dir.join("0.png").exists());
+
+        // tagged pages are only purged when asked to
+        let retention = Retention {
+            purge_tagged: true,
+            ..retention
+        };
+        assert_eq!(purge_in(&retention, &dir, now, false)?.pages, 1);
+        assert!(query_page_link(user, "link2")?.is_none());
+        assert!(query_tags(user)?.is_empty());
+        Ok(())
+    }
+
+    fn auth_user() -> rusqlite::Result<i64> {
+        crate::auth::default_user(&connect()?)
+    }
+}
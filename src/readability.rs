@@ -0,0 +1,416 @@
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::HashMap;
+
+// class and id hints, matched as substrings of the lowercased `class` and `id`
+static UNLIKELY: &[&str] = &[
+    "ad-break",
+    "agegate",
+    "banner",
+    "breadcrumb",
+    "combx",
+    "comment",
+    "community",
+    "cookie",
+    "disqus",
+    "extra",
+    "footer",
+    "gdpr",
+    "header",
+    "menu",
+    "nav",
+    "newsletter",
+    "pager",
+    "pagination",
+    "popup",
+    "related",
+    "remark",
+    "replies",
+    "share",
+    "shoutbox",
+    "sidebar",
+    "skyscraper",
+    "social",
+    "sponsor",
+    "subscribe",
+    "supplemental",
+];
+static MAYBE: &[&str] = &["article", "body", "column", "content", "main"];
+static NEGATIVE: &[&str] = &[
+    "banner",
+    "combx",
+    "comment",
+    "contact",
+    "footer",
+    "masthead",
+    "meta",
+    "outbrain",
+    "promo",
+    "related",
+    "scroll",
+    "share",
+    "shoutbox",
+    "sidebar",
+    "skyscraper",
+    "sponsor",
+    "shopping",
+    "tags",
+    "tool",
+    "widget",
+];
+static POSITIVE: &[&str] = &[
+    "article", "blog", "body", "content", "entry", "h-entry", "hentry", "main", "page", "post",
+    "story", "text",
+];
+// elements never holding the main content
+static SKIPPED_TAGS: &[&str] = &[
+    "aside", "footer", "form", "header", "nav", "noscript", "script", "style",
+];
+// paragraphs shorter than this don't vote for their ancestors
+static MIN_PARAGRAPH_LEN: usize = 25;
+// a candidate with less text is not an article, the caller falls back to the whole body
+static MIN_CONTENT_LEN: usize = 140;
+
+fn hints(element: &ElementRef) -> String {
+    let value = element.value();
+    format!(
+        "{} {}",
+        value.attr("class").unwrap_or_default(),
+        value.attr("id").unwrap_or_default()
+    )
+    .to_lowercase()
+}
+
+// generators like Sphinx give sections the slug of their heading as id, `editing-and-navigation`
+// says nothing about the role of the section
+fn is_titled_section(element: &ElementRef) -> bool {
+    element.value().name() == "section"
+        && element
+            .children()
+            .filter_map(ElementRef::wrap)
+            .find(|e| text_len(e) > 0)
+            .is_some_and(|e| matches!(e.value().name(), "h1" | "h2" | "h3" | "h4" | "h5" | "h6"))
+}
+
+fn matches_any(hints: &str, words: &[&str]) -> bool {
+    words.iter().any(|w| hints.contains(w))
+}
+
+fn is_unlikely(element: &ElementRef) -> bool {
+    // classes on the root often describe the layout, like `has-sidebar`
+    if matches!(element.value().name(), "html" | "body") {
+        return false;
+    }
+    if SKIPPED_TAGS.contains(&element.value().name()) {
+        return true;
+    }
+    let hints = if is_titled_section(element) {
+        element
+            .value()
+            .attr("class")
+            .unwrap_or_default()
+            .to_lowercase()
+    } else {
+        hints(element)
+    };
+    matches_any(&hints, UNLIKELY) && !matches_any(&hints, MAYBE)
+}
+
+fn class_weight(element: &ElementRef) -> f64 {
+    let hints = hints(element);
+    let mut weight = 0.0;
+    if matches_any(&hints, NEGATIVE) {
+        weight -= 25.0;
+    }
+    if matches_any(&hints, POSITIVE) {
+        weight += 25.0;
+    }
+    weight
+}
+
+fn initial_score(element: &ElementRef) -> f64 {
+    let tag = match element.value().name() {
+        "div" | "article" | "section" | "main" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    };
+    tag + class_weight(element)
+}
+
+fn text_len(element: &ElementRef) -> usize {
+    element
+        .text()
+        .flat_map(|t| t.split_whitespace())
+        .map(|w| w.chars().count() + 1)
+        .sum()
+}
+
+fn link_density(element: &ElementRef) -> f64 {
+    let len = text_len(element);
+    if len == 0 {
+        return 0.0;
+    }
+    let links = Selector::parse("a").unwrap();
+    let link_len: usize = element.select(&links).map(|a| text_len(&a)).sum();
+    link_len as f64 / len as f64
+}
+
+fn parent<'a>(element: &ElementRef<'a>) -> Option<ElementRef<'a>> {
+    element.parent().and_then(ElementRef::wrap)
+}
+
+fn ancestors<'a>(element: &ElementRef<'a>) -> Vec<ElementRef<'a>> {
+    element.ancestors().filter_map(ElementRef::wrap).collect()
+}
+
+struct Scores<'a> {
+    html: &'a Html,
+    scores: HashMap<NodeId, f64>,
+}
+
+impl<'a> Scores<'a> {
+    // scores are weighted by how much of the text is not links
+    fn get(&self, element: &ElementRef) -> Option<f64> {
+        let score = self.scores.get(&element.id())?;
+        Some(score * (1.0 - link_density(element)))
+    }
+
+    fn add(&mut self, element: &ElementRef, score: f64) {
+        *self
+            .scores
+            .entry(element.id())
+            .or_insert_with(|| initial_score(element)) += score;
+    }
+
+    fn element(&self, id: NodeId) -> ElementRef<'a> {
+        ElementRef::wrap(self.html.tree.get(id).unwrap()).unwrap()
+    }
+
+    fn ranked(&self) -> Vec<(ElementRef<'a>, f64)> {
+        let mut ranked: Vec<_> = self
+            .scores
+            .keys()
+            .map(|&id| {
+                let element = self.element(id);
+                (element, self.get(&element).unwrap())
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+    }
+}
+
+// every paragraph gives points to its parent, half to the grandparent and less further up
+fn score_paragraphs(html: &Html) -> Scores<'_> {
+    let mut scores = Scores {
+        html,
+        scores: HashMap::new(),
+    };
+    let paragraphs = Selector::parse("p, pre, td").unwrap();
+    for paragraph in html.select(&paragraphs) {
+        let ancestors = ancestors(&paragraph);
+        if is_unlikely(&paragraph) || ancestors.iter().any(is_unlikely) {
+            continue;
+        }
+        let len = text_len(&paragraph);
+        if len < MIN_PARAGRAPH_LEN {
+            continue;
+        }
+        let text: String = paragraph.text().collect();
+        let score = 1.0 + text.matches(',').count() as f64 + (len as f64 / 100.0).min(3.0);
+        for (level, ancestor) in ancestors.iter().take(3).enumerate() {
+            if matches!(ancestor.value().name(), "html" | "body") {
+                break;
+            }
+            let divider = match level {
+                0 => 1.0,
+                1 => 2.0,
+                _ => level as f64 * 3.0,
+            };
+            scores.add(ancestor, score / divider);
+        }
+    }
+    scores
+}
+
+// the best candidate often holds only part of the article, move up to the element holding it all
+fn promote<'a>(
+    scores: &Scores<'a>,
+    ranked: &[(ElementRef<'a>, f64)],
+    top: ElementRef<'a>,
+    top_score: f64,
+) -> ElementRef<'a> {
+    let is_root = |e: &ElementRef| matches!(e.value().name(), "html" | "body");
+    // several good candidates under one ancestor are parts of the same article
+    let alternatives: Vec<Vec<NodeId>> = ranked
+        .iter()
+        .skip(1)
+        .take(5)
+        .filter(|(_, score)| *score >= top_score * 0.75)
+        .map(|(e, _)| ancestors(e).iter().map(|a| a.id()).collect())
+        .collect();
+    if alternatives.len() >= 3 {
+        let mut ancestor = parent(&top);
+        while let Some(a) = ancestor.filter(|a| !is_root(a)) {
+            if alternatives
+                .iter()
+                .filter(|list| list.contains(&a.id()))
+                .count()
+                >= 3
+            {
+                return a;
+            }
+            ancestor = parent(&a);
+        }
+    }
+    // a parent scoring better than the candidate holds more of the article
+    let mut top = top;
+    let mut last_score = top_score;
+    let mut ancestor = parent(&top);
+    while let Some(a) = ancestor.filter(|a| !is_root(a)) {
+        if let Some(score) = scores.get(&a) {
+            if score < last_score / 3.0 {
+                break;
+            }
+            if score > last_score {
+                top = a;
+                break;
+            }
+            last_score = score;
+        }
+        ancestor = parent(&a);
+    }
+    // wrappers holding only the candidate add nothing
+    while let Some(a) = parent(&top).filter(|a| !is_root(a)) {
+        if a.children().filter_map(ElementRef::wrap).count() != 1 {
+            break;
+        }
+        top = a;
+    }
+    top
+}
+
+// long paragraphs with few links, or short ones without links ending a sentence
+fn is_prose(paragraph: &ElementRef) -> bool {
+    let len = text_len(paragraph);
+    let density = link_density(paragraph);
+    if len > 80 {
+        return density < 0.25;
+    }
+    let text: String = paragraph.text().collect();
+    let text = text.trim_end();
+    density == 0.0 && (text.ends_with('.') || text.contains(". "))
+}
+
+// siblings scoring close to the candidate, or paragraphs of prose, belong to the article
+fn with_siblings(scores: &Scores, top: ElementRef, top_score: f64) -> String {
+    let threshold = (top_score * 0.2).max(10.0);
+    let Some(parent) = parent(&top) else {
+        return top.html();
+    };
+    let mut parts = vec![];
+    for sibling in parent.children().filter_map(ElementRef::wrap) {
+        let keep = sibling.id() == top.id()
+            || scores.get(&sibling).is_some_and(|s| s >= threshold)
+            || (sibling.value().name() == "p" && is_prose(&sibling));
+        if keep {
+            parts.push(sibling.html());
+        }
+    }
+    if parts.len() == 1 {
+        top.html()
+    } else {
+        format!("<div>{}</div>", parts.join(""))
+    }
+}
+
+/// The main content of a page, found by scoring its paragraphs like Mozilla Readability does.
+/// None when nothing looks like an article.
+pub(crate) fn extract_content(html: &Html) -> Option<String> {
+    let scores = score_paragraphs(html);
+    let ranked = scores.ranked();
+    let &(top, top_score) = ranked.first()?;
+    let top = promote(&scores, &ranked, top, top_score);
+    let top_score = scores.get(&top).unwrap_or(top_score);
+    if text_len(&top) < MIN_CONTENT_LEN {
+        return None;
+    }
+    Some(with_siblings(&scores, top, top_score))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::plain_text;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_hints() {
+        let html = Html::parse_fragment(
+            r#"<div class="sidebar" id="x"></div><div class="main-sidebar"></div>
+               <div class="post-content"></div><nav></nav>"#,
+        );
+        let divs: Vec<_> = html.select(&Selector::parse("div, nav").unwrap()).collect();
+        assert!(is_unlikely(&divs[0]));
+        assert!(!is_unlikely(&divs[1]));
+        assert!(!is_unlikely(&divs[2]));
+        assert!(is_unlikely(&divs[3]));
+        assert_eq!(class_weight(&divs[0]), -25.0);
+        assert_eq!(class_weight(&divs[2]), 25.0);
+
+        let html = Html::parse_fragment(
+            r#"<section id="menus"><span id="x"></span><h2>Menus</h2></section>
+               <section id="related-posts"><ul><li>Other post</li></ul></section>
+               <section id="a" class="menus"><h2>Menus</h2></section>"#,
+        );
+        let sections: Vec<_> = html.select(&Selector::parse("section").unwrap()).collect();
+        assert!(!is_unlikely(&sections[0]));
+        assert!(is_unlikely(&sections[1]));
+        assert!(is_unlikely(&sections[2]));
+    }
+
+    #[test]
+    fn test_link_density() {
+        let html = Html::parse_fragment(r#"<p>read <a href="/">this link</a></p>"#);
+        let p = html.select(&Selector::parse("p").unwrap()).next().unwrap();
+        assert!((link_density(&p) - 10.0 / 15.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_no_article() {
+        let html =
+            Html::parse_document("<body><nav><p>Home, About, Contact us here</p></nav></body>");
+        assert!(extract_content(&html).is_none());
+    }
+
+    // every page under tests/fixtures/readability comes with the sentences the extracted
+    // text must contain (`+ `) and must not contain (`- `)
+    #[test]
+    fn test_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/readability");
+        let mut checked = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|e| e != "html") {
+                continue;
+            }
+            let page = fs::read_to_string(&path).unwrap();
+            let expected = fs::read_to_string(path.with_extension("txt")).unwrap();
+            let content = extract_content(&Html::parse_document(&page))
+                .unwrap_or_else(|| panic!("no content in {:?}", path));
+            let text = plain_text(&content);
+            for line in expected.lines() {
+                if let Some(wanted) = line.strip_prefix("+ ") {
+                    assert!(text.contains(wanted), "{:?} misses {:?}", path, wanted);
+                } else if let Some(unwanted) = line.strip_prefix("- ") {
+                    assert!(!text.contains(unwanted), "{:?} keeps {:?}", path, unwanted);
+                }
+            }
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+}
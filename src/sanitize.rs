@@ -0,0 +1,132 @@
+use scraper::{Html, Selector};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use url::Url;
+
+/// Hosts of the video and audio players kept as iframes, other iframes are dropped
+pub(crate) static EMBED_HOSTS: &[&str] = &[
+    "www.youtube.com",
+    "www.youtube-nocookie.com",
+    "player.vimeo.com",
+    "w.soundcloud.com",
+    "open.spotify.com",
+];
+
+fn is_embed(src: &str) -> bool {
+    Url::parse(src).is_ok_and(|url| {
+        url.scheme() == "https" && url.host_str().is_some_and(|h| EMBED_HOSTS.contains(&h))
+    })
+}
+
+// iframes can't be filtered by their source in ammonia, take them out of the tree first,
+// the tree doesn't keep the order of attributes
+fn remove_foreign_iframes(content: &str) -> String {
+    let mut html = Html::parse_fragment(content);
+    let select = Selector::parse("iframe").unwrap();
+    let foreign = html
+        .select(&select)
+        .filter(|it| !it.value().attr("src").is_some_and(is_embed))
+        .map(|it| it.id())
+        .collect::<Vec<_>>();
+    for id in foreign {
+        if let Some(mut node) = html.tree.get_mut(id) {
+            node.detach();
+        }
+    }
+    html.root_element().inner_html()
+}
+
+/// Clean the html of an article before it is stored: only whitelisted tags and attributes
+/// are kept, so scripts, event handlers, `javascript:` urls and forms are gone
+pub fn sanitize(content: &str) -> String {
+    builder()
+        .clean(&remove_foreign_iframes(content))
+        .to_string()
+}
+
+fn builder() -> ammonia::Builder<'static> {
+    let mut builder = ammonia::Builder::default();
+    builder
+        .add_tags(&["audio", "video", "source", "picture", "iframe"])
+        .add_tag_attributes("audio", &["src", "controls"])
+        .add_tag_attributes("video", &["src", "controls", "poster", "width", "height"])
+        .add_tag_attributes("source", &["src", "srcset", "type", "media"])
+        .add_tag_attributes("img", &["srcset", "loading"])
+        .add_tag_attributes("iframe", &["src", "width", "height", "allowfullscreen"])
+        .add_generic_attributes(&["class"])
+        .clean_content_tags(HashSet::from([
+            "script", "style", "title", "form", "textarea", "select",
+        ]));
+    builder
+}
+
+/// Change attributes of a sanitized article, `rewrite` gets the name and value of every
+/// attribute and returns the new value, `None` keeps it. The attributes are changed in the
+/// parsed article, which is serialized like `sanitize` does.
+pub(crate) fn rewrite_attributes<F>(content: &str, rewrite: F) -> String
+where
+    F: Fn(&str, &str) -> Option<String> + Send + Sync + 'static,
+{
+    builder()
+        .attribute_filter(move |_, name, value| {
+            Some(rewrite(name, value).map_or(Cow::Borrowed(value), Cow::Owned))
+        })
+        .clean(content)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let html = r#"<p onclick="steal()">hello <b>world</b></p>
+            <script>alert(1)</script><style>p { display: none }</style>
+            <a href="javascript:alert(1)">x</a> <a href="https://a.com/">a</a>
+            <img src="/pages/images/1.png" onerror="steal()">
+            <form action="https://evil.com"><input name="password"><button>go</button></form>
+            <pre><code class="language-rust">fn main() {}</code></pre>"#;
+        let clean = sanitize(html);
+        assert!(clean.contains("<p>hello <b>world</b></p>"));
+        assert!(!clean.contains("alert"));
+        assert!(!clean.contains("display"));
+        assert!(!clean.contains("steal"));
+        assert!(!clean.contains("javascript"));
+        assert!(clean.contains(r#"<a rel="noopener noreferrer">x</a>"#));
+        assert!(clean.contains(r#"<a href="https://a.com/" rel="noopener noreferrer">a</a>"#));
+        assert!(clean.contains(r#"<img src="/pages/images/1.png">"#));
+        assert!(!clean.contains("form"));
+        assert!(!clean.contains("input"));
+        assert!(!clean.contains("go"));
+        assert!(clean.contains(r#"<code class="language-rust">"#));
+    }
+
+    #[test]
+    fn test_sanitize_iframes() {
+        let html = r#"<iframe src="https://www.youtube.com/embed/x" width="560" onload="steal()"></iframe>
+            <iframe src="https://evil.com/embed"><p>inside</p></iframe>
+            <iframe src="http://www.youtube.com/embed/y"></iframe>
+            <iframe srcdoc="<script>alert(1)</script>"></iframe>
+            <video src="/v.mp4" controls autoplay></video>"#;
+        // attributes come out in any order
+        let clean = sanitize(html);
+        assert_eq!(clean.matches("<iframe").count(), 1);
+        assert!(clean.contains(r#"src="https://www.youtube.com/embed/x""#));
+        assert!(clean.contains(r#"width="560""#));
+        assert!(!clean.contains("onload"));
+        assert!(!clean.contains("evil"));
+        assert!(!clean.contains("embed/y"));
+        assert!(!clean.contains("alert"));
+        assert!(clean.contains(r#"src="/v.mp4""#));
+        assert!(clean.contains(r#"controls="""#));
+        assert!(!clean.contains("autoplay"));
+    }
+
+    #[test]
+    fn test_sanitize_document() {
+        let html = "<html><head><title>t</title><script>x()</script></head>\
+                    <body><article><h1>Title</h1></article></body></html>";
+        assert_eq!(sanitize(html), "<article><h1>Title</h1></article>");
+    }
+}
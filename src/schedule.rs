@@ -0,0 +1,122 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+// bounds of the interval guessed for a feed, in seconds
+static MIN_INTERVAL: i64 = 10 * 60;
+static MAX_INTERVAL: i64 = 24 * 3600;
+// a failing feed is still tried at least this often
+static MAX_BACKOFF: i64 = 2 * 24 * 3600;
+// how many of the newest pages tell how often a feed posts
+pub(crate) static RECENT_PAGES: usize = 10;
+
+/// Minutes between two updates announced by a feed with the RSS syndication module,
+/// like `<sy:updatePeriod>daily</sy:updatePeriod><sy:updateFrequency>2</sy:updateFrequency>`
+pub(crate) fn syndication_ttl(xml: &str) -> Option<i64> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut period = None;
+    let mut frequency = 1;
+    let mut current = vec![];
+    loop {
+        match reader.read_event().ok()? {
+            // the channel comes before its items
+            Event::Start(e) if matches!(e.local_name().as_ref(), b"item" | b"entry") => break,
+            Event::Start(e) => current = e.local_name().as_ref().to_vec(),
+            Event::Text(t) => {
+                let text = t.unescape().ok()?;
+                match current.as_slice() {
+                    b"updatePeriod" => period = Some(text.trim().to_lowercase()),
+                    b"updateFrequency" => frequency = text.trim().parse().unwrap_or(1).max(1),
+                    _ => {}
+                }
+            }
+            Event::End(_) => current.clear(),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    let minutes = match period?.as_str() {
+        "hourly" => 60,
+        "daily" => 24 * 60,
+        "weekly" => 7 * 24 * 60,
+        "monthly" => 30 * 24 * 60,
+        "yearly" => 365 * 24 * 60,
+        _ => return None,
+    };
+    Some(minutes / frequency)
+}
+
+/// Seconds until the next fetch of a feed that was fetched fine.
+/// An interval set by the user wins, otherwise it's half the average time between
+/// the `recent` publish times, never shorter than the feed's ttl in minutes.
+pub(crate) fn interval(
+    user_interval: Option<i64>,
+    ttl: Option<i64>,
+    recent: &[i64],
+    default: i64,
+) -> i64 {
+    if let Some(minutes) = user_interval.filter(|m| *m > 0) {
+        return minutes * 60;
+    }
+    let guessed = match (recent.iter().max(), recent.iter().min()) {
+        (Some(newest), Some(oldest)) if recent.len() >= 2 => {
+            ((newest - oldest) / (recent.len() as i64 - 1) / 2).clamp(MIN_INTERVAL, MAX_INTERVAL)
+        }
+        _ => default,
+    };
+    let ttl = ttl.unwrap_or(0).min(MAX_INTERVAL / 60) * 60;
+    guessed.max(ttl)
+}
+
+/// Seconds until the next try of a feed failing for `failures` times in a row,
+/// doubling with every failure
+pub(crate) fn backoff(interval: i64, failures: i64) -> i64 {
+    let doublings = (failures - 1).clamp(0, 16) as u32;
+    interval.saturating_mul(1 << doublings).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syndication_ttl() {
+        let rss = r#"<rss xmlns:sy="http://purl.org/rss/1.0/modules/syndication/"><channel>
+            <title>t</title><sy:updatePeriod>daily</sy:updatePeriod>
+            <sy:updateFrequency>4</sy:updateFrequency>
+            <item><title>i</title></item></channel></rss>"#;
+        assert_eq!(syndication_ttl(rss), Some(6 * 60));
+        let rss = "<rss><channel><sy:updatePeriod> hourly </sy:updatePeriod></channel></rss>";
+        assert_eq!(syndication_ttl(rss), Some(60));
+        assert_eq!(syndication_ttl("<rss><channel></channel></rss>"), None);
+        assert_eq!(syndication_ttl("not xml <"), None);
+    }
+
+    #[test]
+    fn test_interval() {
+        let day = 24 * 3600;
+        assert_eq!(interval(Some(30), Some(600), &[], 1200), 1800);
+        assert_eq!(interval(None, None, &[], 60), 60);
+        // a post every day is checked twice a day
+        assert_eq!(
+            interval(None, None, &[3 * day, 2 * day, day], 1200),
+            day / 2
+        );
+        // but not more often than the ttl asks
+        assert_eq!(
+            interval(None, Some(24 * 60), &[3 * day, 2 * day], 1200),
+            day
+        );
+        // within bounds
+        assert_eq!(interval(None, None, &[100, 50, 0], 1200), MIN_INTERVAL);
+        assert_eq!(interval(None, None, &[100 * day, 0], 1200), MAX_INTERVAL);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(600, 1), 600);
+        assert_eq!(backoff(600, 2), 1200);
+        assert_eq!(backoff(600, 4), 4800);
+        assert_eq!(backoff(600, 100), MAX_BACKOFF);
+    }
+}
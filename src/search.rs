@@ -0,0 +1,218 @@
+use crate::db::*;
+use rusqlite::params;
+use scraper::{Html, Node};
+use serde::Serialize;
+
+// markers around matched terms, replaced by <mark> after escaping the text
+static MARK_START: &str = "\u{2}";
+static MARK_END: &str = "\u{3}";
+
+/// A page matching a search, with highlighted title and a snippet of the body
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub page: Page,
+    pub title: String,
+    pub snippet: String,
+    pub rank: f64,
+}
+
+/// Text content of an html document, without scripts and styles
+pub fn plain_text(content: &str) -> String {
+    let html = Html::parse_document(content);
+    let mut words = vec![];
+    for node in html.tree.nodes() {
+        if let Node::Text(text) = node.value() {
+            let hidden = node
+                .parent()
+                .and_then(|p| p.value().as_element().map(|e| e.name()))
+                .is_some_and(|name| matches!(name, "script" | "style"));
+            if !hidden {
+                words.extend(text.split_whitespace());
+            }
+        }
+    }
+    words.join(" ")
+}
+
+// every word of the query is quoted, so user input never reaches the FTS5 query syntax
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn highlight(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace(MARK_START, "<mark>")
+        .replace(MARK_END, "</mark>")
+}
+
+/// add the body of the page with `link` into the full-text index
+pub(crate) fn index_page(link: &str, title: &str, content: &str) -> rusqlite::Result<()> {
+    let conn = connect()?;
+    conn.execute(
+        "DELETE FROM pages_fts WHERE rowid IN (SELECT id FROM pages WHERE link = ?)",
+        [link],
+    )?;
+    conn.execute(
+        "INSERT INTO pages_fts (rowid, title, body) SELECT id, ?1, ?2 FROM pages WHERE link = ?3",
+        params![title, plain_text(content), link],
+    )?;
+    Ok(())
+}
+
+/// Search titles and bodies of the pages the user subscribes, best matches first
+pub fn search(
+    user_id: i64,
+    query: &str,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<SearchHit>> {
+    let query = fts_query(query);
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+    let conn = connect()?;
+    // the user is the first parameter of `USER_PAGES`
+    let mut statement = conn.prepare(&format!(
+        "SELECT {},
+                highlight(pages_fts, 0, ?2, ?3),
+                snippet(pages_fts, 1, ?2, ?3, '...', 24),
+                bm25(pages_fts, 10.0, 1.0) AS rank
+         FROM {} JOIN pages_fts ON pages_fts.rowid = p.id
+         WHERE pages_fts MATCH ?4
+         ORDER BY rank LIMIT ?5 OFFSET ?6",
+        PAGE_COLUMNS, USER_PAGES
+    ))?;
+    let hits = statement
+        .query_map(
+            params![
+                user_id,
+                MARK_START,
+                MARK_END,
+                query,
+                limit as i64,
+                offset as i64
+            ],
+            |row| {
+                Ok(SearchHit {
+                    page: row_to_page(row)?,
+                    title: highlight(&row.get::<_, String>(9)?),
+                    snippet: highlight(&row.get::<_, String>(10)?),
+                    rank: row.get(11)?,
+                })
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(hits)
+}
+
+/// Rebuild the index from the stored page bodies, return the number of pages indexed
+pub fn reindex() -> rusqlite::Result<usize> {
+    let conn = connect()?;
+    conn.execute("DELETE FROM pages_fts", [])?;
+    let mut statement = conn.prepare(
+        "SELECT p.link, p.title, c.body FROM pages p JOIN contents c ON c.page_id = p.id",
+    )?;
+    let pages = statement
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (link, title, content) in pages.iter() {
+        index_page(link, title, content)?;
+    }
+    Ok(pages.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::default_user;
+    use crate::testing::{self, TestDir};
+
+    #[test]
+    fn test_plain_text() {
+        let html = r#"
+        <html><head><style>p { color: red; }</style></head>
+        <body><h1>Hello,  <i>world!</i></h1>
+        <script>alert("x")</script>
+        <p>rust &amp; sqlite</p></body></html>
+        "#;
+        assert_eq!(plain_text(html), "Hello, world! rust & sqlite");
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(fts_query("  rust   async "), "\"rust\" \"async\"");
+        assert_eq!(fts_query("a\"b OR"), "\"a\"\"b\" \"OR\"");
+        assert_eq!(fts_query(""), "");
+    }
+
+    #[test]
+    fn test_search() -> rusqlite::Result<()> {
+        let _dir = TestDir::new()?;
+        let conn = connect()?;
+        let user = default_user(&conn)?;
+        conn.execute(
+            "INSERT INTO users (name, password_hash, created_at) values ('bob', '', 0)",
+            [],
+        )?;
+        let bob = conn.last_insert_rowid();
+        add_feed(user, "source")?;
+        for (i, (title, body)) in [
+            ("Async Rust", "<p>futures and <b>tokio</b> tasks</p>"),
+            (
+                "SQLite notes",
+                "<p>rust bindings for sqlite, full text &lt;search&gt;</p>",
+            ),
+            ("Cooking", "<p>nothing to see</p>"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let link = format!("link{}", i);
+            dump_new_page(&Page {
+                title: title.to_string(),
+                ..testing::page(&link, "source")
+            })?;
+            index_page(&link, title, body)?;
+        }
+
+        let hits = search(user, "rust", 10, 0)?;
+        assert_eq!(hits.len(), 2);
+        // matches in the title rank first
+        assert_eq!(hits[0].page.link, "link0");
+        assert_eq!(hits[0].title, "Async <mark>Rust</mark>");
+        assert!(hits[1].snippet.contains("<mark>rust</mark> bindings"));
+        assert!(hits[1].snippet.contains("&lt;search&gt;"));
+
+        assert_eq!(search(user, "tokio futures", 10, 0)?.len(), 1);
+        assert_eq!(search(user, "rust", 1, 1)?.len(), 1);
+        assert!(search(user, "\"", 10, 0)?.is_empty());
+        assert!(search(user, "", 10, 0)?.is_empty());
+
+        // other users only find pages of their own feeds
+        assert!(search(bob, "rust", 10, 0)?.is_empty());
+
+        // starred pages are found after unsubscribing, index entries go away with the others
+        set_page_starred(user, hits[0].page.id, true)?;
+        remove_feed(user, "source")?;
+        let hits = search(user, "rust", 10, 0)?;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].page.link, "link0");
+        assert!(hits[0].page.starred);
+        set_page_starred(user, hits[0].page.id, false)?;
+        cleanup_pages()?;
+        assert!(search(user, "rust", 10, 0)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::db::{init_db, Page};
+use std::cell::RefCell;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+thread_local! {
+    static DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// The directory of one test, with its own db, feeds file and pages. Tests running in
+/// parallel don't share anything, the directory is removed when this is dropped.
+pub(crate) struct TestDir {
+    dir: PathBuf,
+}
+
+impl TestDir {
+    /// A directory for the current test, without a db yet
+    pub(crate) fn empty() -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "rss-rs-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        DIR.with(|d| *d.borrow_mut() = Some(dir.clone()));
+        TestDir { dir }
+    }
+
+    /// A directory for the current test with an up to date db
+    pub(crate) fn new() -> rusqlite::Result<Self> {
+        let dir = Self::empty();
+        init_db(None)?;
+        Ok(dir)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        DIR.with(|d| *d.borrow_mut() = None);
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The directory of the current test, to hand over to another thread with `enter`
+pub(crate) fn current() -> Option<PathBuf> {
+    DIR.with(|d| d.borrow().clone())
+}
+
+/// Run the rest of this thread in `dir`, for work the test moved to another thread
+pub(crate) fn enter(dir: Option<PathBuf>) {
+    DIR.with(|d| *d.borrow_mut() = dir);
+}
+
+/// `name` in the directory of the current test
+pub(crate) fn path(name: &str) -> String {
+    DIR.with(|d| {
+        let dir = d.borrow();
+        let dir = dir
+            .as_ref()
+            .expect("tests using the db start with TestDir::new()");
+        dir.join(name).to_string_lossy().into_owned()
+    })
+}
+
+/// An unread page of the feed `source` to store with `dump_new_page`, titled after its link
+pub(crate) fn page(link: &str, source: &str) -> Page {
+    Page {
+        id: 0,
+        title: link.to_string(),
+        link: link.to_string(),
+        website: "website".to_string(),
+        published_at: 0,
+        fetched_at: 0,
+        readed: false,
+        starred: false,
+        source: source.to_string(),
+    }
+}
@@ -0,0 +1,107 @@
+use md5::Digest;
+use rss_rs::{api, auth, db};
+use serde_json::Value;
+use std::path::PathBuf;
+use warp::http::StatusCode;
+
+// the server keeps its db and pages under the working directory
+fn enter_new_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rss-rs-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    std::env::set_current_dir(&dir).unwrap();
+    dir
+}
+
+fn fever_key(name: &str, password: &str) -> String {
+    md5::Md5::digest(format!("{}:{}", name, password).as_bytes())
+        .iter()
+        .map(|v| format!("{:02x}", v))
+        .collect()
+}
+
+// alice files feed A under "news" and B nowhere, she has read the first page.
+// Returns the script with the Fever keys of alice and bob filled in.
+fn setup(script: &str) -> Result<String, Box<dyn std::error::Error>> {
+    db::init_db(None)?;
+    let alice = auth::add_user("alice", "login")?;
+    auth::add_user("bob", "login")?;
+    let alice_key = fever_key("alice", &auth::enable_fever("alice")?);
+    let bob_key = fever_key("bob", &auth::enable_fever("bob")?);
+    db::import_feed(alice, "http://a.com/feed", "A", "http://a.com", "news")?;
+    db::import_feed(alice, "http://b.com/feed", "B", "http://b.com", "")?;
+    let conn = rusqlite::Connection::open("db/pages.db")?;
+    for (i, title) in ["a0", "a1", "a2", "b0"].iter().enumerate() {
+        let source = &title[..1];
+        conn.execute(
+            "INSERT INTO pages (title, link, website, published_at, fetched_at, feed_id)
+             SELECT ?1, ?2, ?3, ?4, 0, id FROM feeds WHERE url = ?5",
+            rusqlite::params![
+                title,
+                format!("http://{}.com/{}", source, &title[1..]),
+                format!("http://{}.com", source),
+                1600000000 + 100 * i as i64,
+                format!("http://{}.com/feed", source),
+            ],
+        )?;
+        conn.execute(
+            "INSERT INTO contents (page_id, body) VALUES (?1, ?2)",
+            rusqlite::params![conn.last_insert_rowid(), format!("<p>{}</p>", title)],
+        )?;
+    }
+    db::set_page_read(alice, 1, true)?;
+    Ok(script
+        .replace("{alice}", &alice_key)
+        .replace("{ALICE}", &alice_key.to_uppercase())
+        .replace("{bob}", &bob_key))
+}
+
+// every field of `expected` must be in `actual` with the same value
+fn assert_fields(request: &str, expected: &Value, actual: &Value) {
+    for (key, value) in expected.as_object().unwrap() {
+        assert_eq!(&actual[key], value, "{} in the reply to {}", key, request);
+    }
+}
+
+// send the requests of a script in order to all routes of the server and check the replies
+async fn replay(name: &str, script: &str) {
+    let dir = enter_new_dir(name);
+    let script = setup(script).unwrap();
+    let routes = api::routes("http://localhost:8005");
+    let requests = script
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    for request in requests
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+    {
+        let (line, rest) = request.split_once('\n').unwrap();
+        let (method, path) = line.split_once(' ').unwrap();
+        let (body, expected) = rest.split_once("=> ").unwrap();
+        let res = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(body.trim())
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{}", request);
+        let actual: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_fields(request, &serde_json::from_str(expected).unwrap(), &actual);
+        if actual["auth"] == 0 {
+            assert_eq!(actual.as_object().unwrap().len(), 2, "{}", request);
+        }
+    }
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+// the working directory is shared by the whole process, the scripts run one after the other
+#[tokio::test]
+async fn test_fever_scripts() {
+    replay("sync", include_str!("fixtures/fever/sync.txt")).await;
+    replay("mark-read", include_str!("fixtures/fever/mark-read.txt")).await;
+    replay("auth", include_str!("fixtures/fever/auth.txt")).await;
+}